@@ -0,0 +1,249 @@
+use candid::{CandidType, Deserialize, Principal};
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::alkanes::alkanes_storage::{get_owner, set_owner};
+use crate::error::{FomowellError, OwnerReceipt};
+use crate::events::{self, EventKind};
+
+/// 权限角色。owner 隐含所有角色，Admin 隐含其余所有角色
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// Grants and revokes roles, wipes data, changes the indexer.
+    Admin,
+    /// Runs the canister day to day: scheduler config, whitelist, ledger sync.
+    Operator,
+    /// Uploads and removes alkane records and ledger UTXOs.
+    Uploader,
+    /// May queue withdraws through `withdraw_alkanes`.
+    WithdrawCaller,
+    /// Read-only access to privileged diagnostics.
+    Auditor,
+}
+
+thread_local! {
+    /// Heap only, like the rest of the canister state: roles persist across
+    /// upgrades through `RuntimeState.roles` in the upgrade snapshot
+    /// (`list_roles` in `pre_upgrade`, `restore` in `post_upgrade`), and
+    /// `post_upgrade` traps rather than start with an empty table when that
+    /// snapshot does not decode.
+    static ROLES: RefCell<HashMap<Principal, BTreeSet<Role>>> = RefCell::new(HashMap::new());
+    static PENDING_OWNER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+// ic_cdk::api::caller 在 canister 外会 panic，单元测试里由测试指定调用者
+#[cfg(test)]
+thread_local! {
+    static TEST_CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+}
+#[cfg(test)]
+fn caller() -> Principal {
+    TEST_CALLER.with(|c| *c.borrow())
+}
+#[cfg(test)]
+pub(crate) fn set_test_caller(principal: Principal) {
+    TEST_CALLER.with(|c| *c.borrow_mut() = principal);
+}
+
+pub fn has_role(principal: Principal, role: Role) -> bool {
+    if principal == get_owner() {
+        return true;
+    }
+    ROLES.with(|r| {
+        r.borrow()
+            .get(&principal)
+            .is_some_and(|roles| roles.contains(&Role::Admin) || roles.contains(&role))
+    })
+}
+
+/// Rejects the caller unless it holds at least one of `roles`.
+pub fn require_any(roles: &[Role]) -> Result<(), String> {
+    let caller = caller();
+    if roles.iter().any(|role| has_role(caller, *role)) {
+        return Ok(());
+    }
+    let names: Vec<String> = roles.iter().map(|r| format!("{:?}", r)).collect();
+    Err(format!("Unauthorized: requires role {}", names.join(" or ")))
+}
+
+pub fn require(role: Role) -> Result<(), String> {
+    require_any(&[role])
+}
+
+// Guards for `#[update(guard = "...")]` / `#[query(guard = "...")]`. They run
+// before the method body, so rejected callers never reach outcalls or
+// inter-canister calls.
+
+pub fn guard_admin() -> Result<(), String> {
+    require(Role::Admin)
+}
+
+pub fn guard_operator() -> Result<(), String> {
+    require(Role::Operator)
+}
+
+pub fn guard_uploader() -> Result<(), String> {
+    require(Role::Uploader)
+}
+
+pub fn guard_withdraw_caller() -> Result<(), String> {
+    require(Role::WithdrawCaller)
+}
+
+pub fn guard_operator_or_auditor() -> Result<(), String> {
+    require_any(&[Role::Operator, Role::Auditor])
+}
+
+pub fn guard_admin_or_auditor() -> Result<(), String> {
+    require_any(&[Role::Admin, Role::Auditor])
+}
+
+pub fn guard_owner() -> Result<(), String> {
+    if caller() != get_owner() {
+        return Err("Unauthorized: only the owner can call this method".into());
+    }
+    Ok(())
+}
+
+pub fn guard_pending_owner() -> Result<(), String> {
+    if pending_owner() != Some(caller()) {
+        return Err("Unauthorized: caller is not the proposed owner".into());
+    }
+    Ok(())
+}
+
+/// 不做权限检查，由 init、升级迁移和通过审批的提案调用
+pub fn insert_role(principal: Principal, role: Role) {
+    ROLES.with(|r| {
+        r.borrow_mut().entry(principal).or_default().insert(role);
+    });
+}
+
+/// Returns false if `principal` did not hold `role`.
+pub fn remove_role(principal: Principal, role: Role) -> bool {
+    ROLES.with(|r| {
+        let mut map = r.borrow_mut();
+        let removed = map.get_mut(&principal).is_some_and(|roles| roles.remove(&role));
+        if map.get(&principal).is_some_and(|roles| roles.is_empty()) {
+            map.remove(&principal);
+        }
+        removed
+    })
+}
+
+pub fn list_roles() -> Vec<(Principal, Vec<Role>)> {
+    let mut roles: Vec<(Principal, Vec<Role>)> = ROLES.with(|r| {
+        r.borrow()
+            .iter()
+            .map(|(p, roles)| (*p, roles.iter().copied().collect()))
+            .collect()
+    });
+    roles.sort_by_key(|(p, _)| *p);
+    roles
+}
+
+/// 第一步：owner 提名新 owner，新 owner 调用 `accept_owner` 后才生效。
+/// Runs from an approved `ProposeOwner` proposal; proposing again replaces
+/// the previous nominee.
+pub fn propose_owner(new_owner: Principal) -> Result<String, String> {
+    PENDING_OWNER.with(|p| *p.borrow_mut() = Some(new_owner));
+    crate::append_log(format!("[access] owner proposed new_owner={}", new_owner));
+    Ok(format!("Ownership transfer to {} proposed", new_owner))
+}
+
+pub fn accept_owner() -> Result<OwnerReceipt, FomowellError> {
+    let caller = caller();
+    if pending_owner() != Some(caller) {
+        return Err(FomowellError::Unauthorized("caller is not the proposed owner".into()));
+    }
+    let previous = get_owner();
+    set_owner(caller);
+    PENDING_OWNER.with(|p| *p.borrow_mut() = None);
+    crate::append_log(format!("[access] owner changed from={} to={}", previous, caller));
+    events::event(EventKind::AdminAction)
+        .principal(caller)
+        .detail(format!("owner changed from {}", previous))
+        .record();
+    Ok(OwnerReceipt { owner: caller, previous })
+}
+
+pub fn pending_owner() -> Option<Principal> {
+    PENDING_OWNER.with(|p| *p.borrow())
+}
+
+pub fn restore(roles: Vec<(Principal, Vec<Role>)>, pending_owner: Option<Principal>) {
+    ROLES.with(|r| {
+        *r.borrow_mut() = roles
+            .into_iter()
+            .map(|(p, roles)| (p, roles.into_iter().collect()))
+            .collect();
+    });
+    PENDING_OWNER.with(|p| *p.borrow_mut() = pending_owner);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_implies_every_role() {
+        let admin = Principal::from_slice(&[1]);
+        let uploader = Principal::from_slice(&[2]);
+        insert_role(admin, Role::Admin);
+        insert_role(uploader, Role::Uploader);
+
+        assert!(has_role(admin, Role::WithdrawCaller));
+        assert!(has_role(uploader, Role::Uploader));
+        assert!(!has_role(uploader, Role::Operator));
+        assert!(!has_role(Principal::from_slice(&[3]), Role::Auditor));
+    }
+
+    #[test]
+    fn test_guards_against_seeded_roles() {
+        let p = |n: u8| Principal::from_slice(&[n]);
+        let (owner, admin, operator, uploader, withdrawer, auditor, nominee, stranger) =
+            (p(10), p(11), p(12), p(13), p(14), p(15), p(16), p(17));
+        set_owner(owner);
+        restore(
+            vec![
+                (admin, vec![Role::Admin]),
+                (operator, vec![Role::Operator]),
+                (uploader, vec![Role::Uploader]),
+                (withdrawer, vec![Role::WithdrawCaller]),
+                (auditor, vec![Role::Auditor]),
+            ],
+            Some(nominee),
+        );
+
+        type Guard = fn() -> Result<(), String>;
+        let guards: [(&str, Guard, &[Principal]); 8] = [
+            ("guard_admin", guard_admin, &[owner, admin]),
+            ("guard_operator", guard_operator, &[owner, admin, operator]),
+            ("guard_uploader", guard_uploader, &[owner, admin, uploader]),
+            ("guard_withdraw_caller", guard_withdraw_caller, &[owner, admin, withdrawer]),
+            ("guard_operator_or_auditor", guard_operator_or_auditor, &[owner, admin, operator, auditor]),
+            ("guard_admin_or_auditor", guard_admin_or_auditor, &[owner, admin, auditor]),
+            ("guard_owner", guard_owner, &[owner]),
+            ("guard_pending_owner", guard_pending_owner, &[nominee]),
+        ];
+        for (name, guard, allowed) in guards {
+            for caller in [owner, admin, operator, uploader, withdrawer, auditor, nominee, stranger] {
+                set_test_caller(caller);
+                assert_eq!(guard().is_ok(), allowed.contains(&caller), "{} called by {}", name, caller);
+            }
+        }
+
+        // 撤销角色后立即失效；接受转让后新 owner 通过 guard_owner
+        assert!(remove_role(operator, Role::Operator));
+        set_test_caller(operator);
+        assert!(guard_operator().is_err());
+        set_test_caller(nominee);
+        assert_eq!(accept_owner().map(|receipt| receipt.previous), Ok(owner));
+        assert!(guard_owner().is_ok());
+        assert!(guard_pending_owner().is_err());
+        set_test_caller(owner);
+        assert!(guard_owner().is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use bitcoin::opcodes::all;
-use bitcoin::script::{Builder, PushBytesBuf};
-use bitcoin::ScriptBuf;
+use bitcoin::script::{Builder, Instruction, PushBytesBuf};
+use bitcoin::{Script, ScriptBuf};
 
 pub mod alkanes_protostone {
-    use super::{all, Builder, PushBytesBuf, ScriptBuf};
+    use super::{all, Builder, Instruction, PushBytesBuf, Script, ScriptBuf};
+    use std::collections::BTreeMap;
 
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
     pub struct RuneId {
@@ -109,24 +110,12 @@ pub mod alkanes_protostone {
             return Vec::new();
         }
 
+        // 每个 chunk 固定 15 字节；按高位 0 截断会丢掉编码里的 0 值（比如 output 0）。
+        // 最后一个 chunk 多出来的 0 会读成多余的 0，按字段长度解析时会被忽略
         let mut out = Vec::with_capacity(ints.len() * CHUNK_SIZE);
-
         for &v in ints {
-            if v == 0 {
-                out.push(0);
-                continue;
-            }
-
-            let mut x = v;
-            for _ in 0..CHUNK_SIZE {
-                out.push((x & 0xff) as u8);
-                x >>= 8;
-                if x == 0 {
-                    break;
-                }
-            }
+            out.extend_from_slice(&v.to_le_bytes()[..CHUNK_SIZE]);
         }
-
         out
     }
 
@@ -410,4 +399,179 @@ pub mod alkanes_protostone {
             .push_slice(pb)
             .into_script()
     }
+
+    /// Where the alkanes of a transaction end up once a protostone ran.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct TransferOutcome {
+        /// Alkanes each output receives, by output index.
+        pub outputs: Vec<BTreeMap<RuneId, u128>>,
+        pub burned: BTreeMap<RuneId, u128>,
+        /// What no edict moved, and the output it fell to (`None` when burned).
+        pub leftover: BTreeMap<RuneId, u128>,
+        pub leftover_output: Option<u32>,
+        /// Set when an edict or the pointer names an output that does not
+        /// exist. Everything then goes to `refund_pointer`, or is burned.
+        pub invalid: Option<String>,
+    }
+
+    /// Applies the edicts, pointer, refund pointer and burn of `proto` to the
+    /// alkanes held by the inputs. `op_returns[i]` says whether output `i` is
+    /// an OP_RETURN; alkanes sent to one are burned.
+    ///
+    /// Edicts run in order and each takes at most what is left of its alkane;
+    /// an amount of 0 takes all of it. An edict to output `op_returns.len()`
+    /// is split over every other output. What is left goes to `pointer`, or to
+    /// the first non-OP_RETURN output, unless `burn` is set.
+    pub fn apply_protostone(inputs: &BTreeMap<RuneId, u128>, proto: &Protostone, op_returns: &[bool]) -> TransferOutcome {
+        let count = op_returns.len();
+        let mut outcome = TransferOutcome { outputs: vec![BTreeMap::new(); count], ..Default::default() };
+        let mut unallocated: BTreeMap<RuneId, u128> = inputs.iter().filter(|(_, amount)| **amount > 0).map(|(id, amount)| (*id, *amount)).collect();
+
+        // edict 可以指向 count（平分），pointer 只能指向实际存在的输出
+        let no_split_target = op_returns.iter().all(|op_return| *op_return);
+        let out_of_range = proto
+            .edicts
+            .iter()
+            .map(|edict| ("edict", edict.output as usize))
+            .find(|(_, output)| *output > count || (*output == count && no_split_target))
+            .or(proto.pointer.map(|pointer| ("pointer", pointer as usize)).filter(|(_, pointer)| *pointer >= count));
+        if let Some((field, output)) = out_of_range {
+            outcome.invalid = Some(format!("{} points at output {} of {}", field, output, count));
+            let refund = proto.refund_pointer.filter(|refund| (*refund as usize) < count);
+            for (id, amount) in unallocated {
+                outcome.allocate(refund, id, amount, op_returns);
+            }
+            return outcome;
+        }
+
+        for edict in &proto.edicts {
+            let Some(available) = unallocated.get_mut(&edict.id) else {
+                continue;
+            };
+            if edict.output as usize == count {
+                // 平均分给所有非 OP_RETURN 输出，余数从前往后各多分 1
+                let targets: Vec<u32> = (0..count as u32).filter(|i| !op_returns[*i as usize]).collect();
+                let share = if edict.amount == 0 { *available / targets.len() as u128 } else { edict.amount };
+                let mut remainder = if edict.amount == 0 { *available % targets.len() as u128 } else { 0 };
+                for target in targets {
+                    let give = (share + u128::from(remainder > 0)).min(*available);
+                    remainder = remainder.saturating_sub(1);
+                    *available -= give;
+                    outcome.allocate(Some(target), edict.id, give, op_returns);
+                }
+            } else {
+                let give = if edict.amount == 0 { *available } else { edict.amount.min(*available) };
+                *available -= give;
+                outcome.allocate(Some(edict.output), edict.id, give, op_returns);
+            }
+        }
+
+        unallocated.retain(|_, amount| *amount > 0);
+        if unallocated.is_empty() {
+            return outcome;
+        }
+        let target = match proto.burn {
+            Some(_) => None,
+            None => proto.pointer.or_else(|| op_returns.iter().position(|op_return| !op_return).map(|i| i as u32)),
+        };
+        outcome.leftover_output = target.filter(|output| !op_returns[*output as usize]);
+        for (id, amount) in &unallocated {
+            outcome.allocate(target, *id, *amount, op_returns);
+        }
+        outcome.leftover = unallocated;
+        outcome
+    }
+
+    impl TransferOutcome {
+        fn allocate(&mut self, output: Option<u32>, id: RuneId, amount: u128, op_returns: &[bool]) {
+            if amount == 0 {
+                return;
+            }
+            let balances = match output {
+                Some(output) if !op_returns[output as usize] => &mut self.outputs[output as usize],
+                _ => &mut self.burned,
+            };
+            *balances.entry(id).or_insert(0) += amount;
+        }
+    }
+
+    /// `build_alkanes_transfer_script` 的逆过程，不是这个格式的脚本返回 `None`
+    pub fn parse_transfer_script(script: &Script) -> Option<Vec<super::alkanes_protostone::Protostone>> {
+        let mut instructions = script.instructions();
+        match instructions.next()? {
+            Ok(Instruction::Op(op)) if op == all::OP_RETURN => {}
+            _ => return None,
+        }
+        match instructions.next()? {
+            Ok(Instruction::Op(op)) if op == all::OP_PUSHNUM_13 => {}
+            _ => return None,
+        }
+        let payload = match instructions.next()? {
+            Ok(Instruction::PushBytes(bytes)) => bytes.as_bytes().to_vec(),
+            _ => return None,
+        };
+
+        let mut idx = 0;
+        if read_leb128_u128(&payload, &mut idx)? != 16383 {
+            return None;
+        }
+        let mut chunks = Vec::new();
+        while idx < payload.len() {
+            chunks.push(read_leb128_u128(&payload, &mut idx)?);
+        }
+        Some(parse_protostones(&chunks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::alkanes_protostone::*;
+    use std::collections::BTreeMap;
+
+    fn proto(edicts: Vec<(u32, u128, u32)>, pointer: Option<u32>) -> Protostone {
+        Protostone {
+            subprotocol_id: 1,
+            edicts: edicts
+                .into_iter()
+                .map(|(tx, amount, output)| Edict { id: RuneId { block: 2, tx }, amount, output })
+                .collect(),
+            pointer,
+            refund_pointer: Some(0),
+            burn: None,
+            message: None,
+            from: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_protostone() {
+        let id = |tx| RuneId { block: 2, tx };
+        let inputs = BTreeMap::from([(id(1), 1_000), (id(7), 50)]);
+        // output 0 fund, 1 user, 2 OP_RETURN
+        let op_returns = [false, false, true];
+
+        let outcome = apply_protostone(&inputs, &proto(vec![(1, 400, 1), (1, 5_000, 0)], Some(0)), &op_returns);
+        assert_eq!(outcome.outputs[1], BTreeMap::from([(id(1), 400)]));
+        // 第二条 edict 只能拿到剩下的 600，2:7 没有 edict，跟着 pointer 走
+        assert_eq!(outcome.outputs[0], BTreeMap::from([(id(1), 600), (id(7), 50)]));
+        assert_eq!((outcome.leftover_output, outcome.leftover), (Some(0), BTreeMap::from([(id(7), 50)])));
+
+        // 没有 pointer 时剩余的进第一个非 OP_RETURN 输出；指向 OP_RETURN 的被烧掉
+        let outcome = apply_protostone(&inputs, &proto(vec![(1, 0, 2)], None), &[true, false, true]);
+        assert_eq!(outcome.burned, BTreeMap::from([(id(1), 1_000)]));
+        assert_eq!(outcome.outputs[1], BTreeMap::from([(id(7), 50)]));
+
+        // output == 输出个数时平均分给非 OP_RETURN 输出
+        let outcome = apply_protostone(&BTreeMap::from([(id(1), 5)]), &proto(vec![(1, 0, 3)], Some(0)), &op_returns);
+        assert_eq!((outcome.outputs[0][&id(1)], outcome.outputs[1][&id(1)]), (3, 2));
+
+        // 越界的 edict 让整个 protostone 无效，全部退到 refund_pointer
+        let outcome = apply_protostone(&inputs, &proto(vec![(1, 400, 9)], Some(1)), &op_returns);
+        assert!(outcome.invalid.is_some());
+        assert_eq!(outcome.outputs[0], inputs);
+        assert!(apply_protostone(&inputs, &proto(vec![], Some(3)), &op_returns).invalid.is_some());
+
+        let burn = Protostone { burn: Some(1), ..proto(vec![], Some(1)) };
+        assert_eq!(apply_protostone(&inputs, &burn, &op_returns).burned, inputs);
+    }
 }
@@ -1,7 +1,14 @@
 use candid::{CandidType, Deserialize, Principal};
+use serde::de::DeserializeOwned;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
-use ic_cdk::{api::caller, storage};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+use ic_cdk::storage;
+
+use crate::access::{require, Role};
+use crate::conversion::TokenConfig;
 
 pub type AlkaneKey = String;
 
@@ -11,53 +18,314 @@ pub struct AlkaneRecord {
     pub vout: u64,
     pub send_address: String,
     pub alkaneid: String,
-    pub amount: u64,
+    pub amount: u128,
 }
 
 pub type BatchAlkaneData = AlkaneRecord;
 
 #[derive(Clone, CandidType, Deserialize, Debug)]
 pub struct AlkaneUtxoRecord {
-    pub amount: u64,
+    pub amount: u128,
     pub txid: String,
     pub vout: u64,
     pub satoshi: u64,
 }
 
+/// `AlkaneRecord` and `AlkaneUtxoRecord` as snapshots stored them while
+/// amounts were `nat64`. Only read when restoring an old snapshot.
+#[derive(CandidType, Deserialize)]
+struct AlkaneRecordV1 {
+    txid: String,
+    vout: u64,
+    send_address: String,
+    alkaneid: String,
+    amount: u64,
+}
+
+impl From<AlkaneRecordV1> for AlkaneRecord {
+    fn from(v1: AlkaneRecordV1) -> Self {
+        Self { txid: v1.txid, vout: v1.vout, send_address: v1.send_address, alkaneid: v1.alkaneid, amount: v1.amount.into() }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct AlkaneUtxoRecordV1 {
+    amount: u64,
+    txid: String,
+    vout: u64,
+    satoshi: u64,
+}
+
+/// Key format of the ledger before it was indexed, `address:alkaneid:txid:vout`.
+/// Only read when restoring an old snapshot.
 pub type AlkaneUtxoKey = String;
 
+/// `block:tx`, ordered numerically.
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AlkaneId {
+    pub block: u64,
+    pub tx: u64,
+}
+
+impl FromStr for AlkaneId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once(':')
+            .and_then(|(block, tx)| Some(AlkaneId { block: block.parse().ok()?, tx: tx.parse().ok()? }))
+            .ok_or_else(|| format!("Invalid alkane id {}", s))
+    }
+}
+
+impl fmt::Display for AlkaneId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.block, self.tx)
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: u64,
+}
+
+/// One outpoint of the ledger. Entries sort by address, then outpoint,
+/// which is also the page order.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UtxoKey {
+    pub address: String,
+    pub outpoint: OutPoint,
+}
+
+impl UtxoKey {
+    fn new(address: &str, txid: &str, vout: u64) -> Self {
+        Self { address: address.to_string(), outpoint: OutPoint { txid: txid.to_string(), vout } }
+    }
+}
+
+/// Everything one outpoint holds. Spending it moves all of `balances`.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct UtxoBalances {
+    pub satoshi: u64,
+    /// Sorted by alkane id, one entry per alkane.
+    pub balances: Vec<(AlkaneId, u128)>,
+}
+
+impl UtxoBalances {
+    pub fn balance(&self, alkaneid: AlkaneId) -> Option<u128> {
+        self.balances.iter().find(|(id, _)| *id == alkaneid).map(|(_, amount)| *amount)
+    }
+}
+
+/// Key of the ledger when it kept one entry per alkane, `(address, alkaneid,
+/// outpoint)`. Only read when restoring an old snapshot.
+#[derive(CandidType, Deserialize)]
+struct AlkaneEntryKey {
+    address: String,
+    alkaneid: AlkaneId,
+    outpoint: OutPoint,
+}
+
+/// 旧版字符串键：地址取第一段，txid/vout 取最后两段，中间是 alkaneid
+fn parse_legacy_key(key: &str) -> Result<AlkaneEntryKey, String> {
+    let invalid = || format!("Invalid ledger key {}", key);
+    let (address, rest) = key.split_once(':').ok_or_else(invalid)?;
+    let mut parts = rest.rsplitn(3, ':');
+    let vout = parts.next().and_then(|vout| vout.parse().ok()).ok_or_else(invalid)?;
+    let txid = parts.next().ok_or_else(invalid)?;
+    let alkaneid = parts.next().ok_or_else(invalid)?.parse()?;
+    Ok(AlkaneEntryKey { address: address.to_string(), alkaneid, outpoint: OutPoint { txid: txid.to_string(), vout } })
+}
+
+/// All set fields must match; `alkaneid` matches outpoints holding that
+/// alkane. Pages follow the `UtxoKey` order; pass the `next` of the previous
+/// page as `after` to continue.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UtxoQuery {
+    pub address: Option<String>,
+    pub alkaneid: Option<String>,
+    pub outpoint: Option<OutPoint>,
+    pub after: Option<UtxoKey>,
+    pub limit: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UtxoPage {
+    pub entries: Vec<(UtxoKey, UtxoBalances)>,
+    /// Key of the last entry when more may follow.
+    pub next: Option<UtxoKey>,
+}
+
+const MAX_UTXO_PAGE: u64 = 500;
+
+/// The UTXO ledger, one entry per outpoint, with its indexes by alkane and
+/// by outpoint. Lookups by address are ranges of `entries` itself.
+#[derive(Default)]
+struct UtxoLedger {
+    entries: BTreeMap<UtxoKey, UtxoBalances>,
+    by_alkane: BTreeMap<AlkaneId, BTreeSet<UtxoKey>>,
+    by_outpoint: BTreeMap<OutPoint, BTreeSet<UtxoKey>>,
+}
+
+impl UtxoLedger {
+    /// Adds or replaces the outpoint `key` with everything it holds.
+    fn insert(&mut self, key: UtxoKey, utxo: UtxoBalances) {
+        self.remove(&key);
+        for (alkaneid, _) in &utxo.balances {
+            self.by_alkane.entry(*alkaneid).or_default().insert(key.clone());
+        }
+        self.by_outpoint.entry(key.outpoint.clone()).or_default().insert(key.clone());
+        self.entries.insert(key, utxo);
+    }
+
+    fn remove(&mut self, key: &UtxoKey) -> Option<UtxoBalances> {
+        let utxo = self.entries.remove(key)?;
+        for (alkaneid, _) in &utxo.balances {
+            if let Some(keys) = self.by_alkane.get_mut(alkaneid) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.by_alkane.remove(alkaneid);
+                }
+            }
+        }
+        if let Some(keys) = self.by_outpoint.get_mut(&key.outpoint) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.by_outpoint.remove(&key.outpoint);
+            }
+        }
+        Some(utxo)
+    }
+
+    /// Sets the balance of one alkane on `key`, adding the outpoint if it is new.
+    fn set_balance(&mut self, key: UtxoKey, satoshi: u64, alkaneid: AlkaneId, amount: u128) {
+        let mut utxo = self.remove(&key).unwrap_or_default();
+        utxo.satoshi = satoshi;
+        match utxo.balances.binary_search_by_key(&alkaneid, |(id, _)| *id) {
+            Ok(index) => utxo.balances[index].1 = amount,
+            Err(index) => utxo.balances.insert(index, (alkaneid, amount)),
+        }
+        self.insert(key, utxo);
+    }
+
+    /// Drops one alkane from `key`, and the outpoint once it holds nothing.
+    /// Returns the satoshi of the outpoint and the removed balance.
+    fn remove_balance(&mut self, key: &UtxoKey, alkaneid: AlkaneId) -> Option<(u64, u128)> {
+        let mut utxo = self.entries.get(key)?.clone();
+        let index = utxo.balances.iter().position(|(id, _)| *id == alkaneid)?;
+        let (_, amount) = utxo.balances.remove(index);
+        let satoshi = utxo.satoshi;
+        if utxo.balances.is_empty() {
+            self.remove(key);
+        } else {
+            self.insert(key.clone(), utxo);
+        }
+        Some((satoshi, amount))
+    }
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Outpoints of `address` starting after `after`.
+    fn address_range<'a>(
+        &'a self,
+        address: &'a str,
+        after: Option<&UtxoKey>,
+    ) -> impl Iterator<Item = (&'a UtxoKey, &'a UtxoBalances)> + 'a {
+        let first = UtxoKey::new(address, "", 0);
+        let lower = match after {
+            Some(after) if *after >= first => Bound::Excluded(after.clone()),
+            _ => Bound::Included(first),
+        };
+        self.entries.range((lower, Bound::Unbounded)).take_while(move |(key, _)| key.address == address)
+    }
+
+    fn indexed<'a>(
+        &'a self,
+        keys: Option<&'a BTreeSet<UtxoKey>>,
+        after: Option<&UtxoKey>,
+    ) -> impl Iterator<Item = (&'a UtxoKey, &'a UtxoBalances)> + 'a {
+        let lower = after.map_or(Bound::Unbounded, |after| Bound::Excluded(after.clone()));
+        keys.into_iter()
+            .flat_map(move |keys| keys.range((lower.clone(), Bound::Unbounded)))
+            .filter_map(|key| self.entries.get_key_value(key))
+    }
+
+    fn page(&self, query: &UtxoQuery) -> Result<UtxoPage, String> {
+        let alkaneid = query.alkaneid.as_deref().map(AlkaneId::from_str).transpose()?;
+        let limit = query.limit.unwrap_or(100).min(MAX_UTXO_PAGE) as usize;
+        let after = query.after.as_ref();
+        // 先用最窄的索引取候选，再按其余条件过滤
+        let candidates: Box<dyn Iterator<Item = (&UtxoKey, &UtxoBalances)>> =
+            match (&query.outpoint, &query.address, alkaneid) {
+                (Some(outpoint), _, _) => Box::new(self.indexed(self.by_outpoint.get(outpoint), after)),
+                (None, Some(address), _) => Box::new(self.address_range(address, after)),
+                (None, None, Some(id)) => Box::new(self.indexed(self.by_alkane.get(&id), after)),
+                (None, None, None) => {
+                    let lower = after.map_or(Bound::Unbounded, |after| Bound::Excluded(after.clone()));
+                    Box::new(self.entries.range((lower, Bound::Unbounded)))
+                }
+            };
+        let mut entries: Vec<(UtxoKey, UtxoBalances)> = candidates
+            .filter(|(key, _)| query.address.as_ref().is_none_or(|address| key.address == *address))
+            .filter(|(_, utxo)| alkaneid.is_none_or(|id| utxo.balance(id).is_some()))
+            .take(limit + 1)
+            .map(|(key, utxo)| (key.clone(), utxo.clone()))
+            .collect();
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+        Ok(UtxoPage { entries, next })
+    }
+}
+
+/// The per-alkane view of an outpoint the older endpoints return.
+fn alkane_records(key: &UtxoKey, utxo: &UtxoBalances) -> Vec<(String, AlkaneUtxoRecord)> {
+    utxo.balances
+        .iter()
+        .map(|(alkaneid, amount)| {
+            let record = AlkaneUtxoRecord {
+                amount: *amount,
+                txid: key.outpoint.txid.clone(),
+                vout: key.outpoint.vout,
+                satoshi: utxo.satoshi,
+            };
+            (alkaneid.to_string(), record)
+        })
+        .collect()
+}
+
 
 thread_local! {
     static OWNER: RefCell<Principal> = RefCell::new(Principal::anonymous());
     static ALKANE_DATA: RefCell<HashMap<AlkaneKey, AlkaneRecord>> = RefCell::new(HashMap::new());
     static WHITE_TOKEN_LIST: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
     static TOKEN_ID_MAP: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
-    static ALKANE_UTXO_LEDGER: RefCell<HashMap<AlkaneUtxoKey, AlkaneUtxoRecord>> = RefCell::new(HashMap::new());
+    static TOKEN_CONFIGS: RefCell<HashMap<String, TokenConfig>> = RefCell::new(HashMap::new());
+    static ALKANE_UTXO_LEDGER: RefCell<UtxoLedger> = RefCell::new(UtxoLedger::default());
 }
 
 pub fn init(initial_owner: Principal) {
     OWNER.with(|o| *o.borrow_mut() = initial_owner);
 }
 
-pub fn is_authorized() -> bool {
-    caller() == OWNER.with(|o| *o.borrow())
+pub fn get_owner() -> Principal {
+    OWNER.with(|o| *o.borrow())
 }
 
-pub fn set_owner(new_owner: Principal) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
-
+/// Ownership changes go through `access::propose_owner`/`accept_owner`.
+pub fn set_owner(new_owner: Principal) {
     OWNER.with(|o| *o.borrow_mut() = new_owner);
-    Ok("Set Admain successfully".into())
 }
 
 
 
 pub fn add_white_token(token: String) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
+    require(Role::Operator)?;
 
     WHITE_TOKEN_LIST.with(|set| {
         set.borrow_mut().insert(token.clone());
@@ -67,9 +335,7 @@ pub fn add_white_token(token: String) -> Result<String, String> {
 }
 
 pub fn remove_white_token(token: String) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
+    require(Role::Operator)?;
 
     WHITE_TOKEN_LIST.with(|set| {
         set.borrow_mut().remove(&token);
@@ -87,9 +353,7 @@ pub fn get_white_tokens() -> Vec<String> {
 }
 
 pub fn set_token_id_mapping(alkaneid: String, meme_token_id: u64) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
+    require(Role::Operator)?;
 
     TOKEN_ID_MAP.with(|map| {
         map.borrow_mut().insert(alkaneid.clone(), meme_token_id);
@@ -108,82 +372,119 @@ pub fn get_token_id_by_alkaneid(alkaneid: String) -> Result<u64, String> {
     })
 }
 
-fn make_utxo_key(address: &str, alkaneid: &str, txid: &str, vout: u64) -> AlkaneUtxoKey {
-    format!("{}:{}:{}:{}", address, alkaneid, txid, vout)
+/// Runs from an approved `SetTokenConfig` proposal, or when a token is
+/// whitelisted.
+pub fn set_token_config(alkaneid: &str, config: TokenConfig) {
+    TOKEN_CONFIGS.with(|map| {
+        map.borrow_mut().insert(alkaneid.to_string(), config);
+    });
+}
+
+/// 没有配置的 token 按 1:1 换算
+pub fn get_token_config(alkaneid: &str) -> TokenConfig {
+    TOKEN_CONFIGS.with(|map| map.borrow().get(alkaneid).cloned().unwrap_or_default())
+}
+
+pub fn get_token_configs() -> Vec<(String, TokenConfig)> {
+    TOKEN_CONFIGS.with(|map| {
+        let mut configs: Vec<(String, TokenConfig)> = map.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        configs.sort_by(|a, b| a.0.cmp(&b.0));
+        configs
+    })
 }
 
 pub fn set_utxo(address: String, alkaneid: String, utxo: AlkaneUtxoRecord) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
+    require(Role::Uploader)?;
 
-    let key = make_utxo_key(&address, &alkaneid, &utxo.txid, utxo.vout);
-    ALKANE_UTXO_LEDGER.with(|ledger| {
-        ledger.borrow_mut().insert(key.clone(), utxo);
-    });
+    let (txid, vout) = (utxo.txid.clone(), utxo.vout);
+    put_utxo(&address, &alkaneid, utxo)?;
 
     Ok(format!("UTXO set for address: {}, alkaneid: {}, txid: {}, vout: {}", 
-        address, alkaneid, key.split(':').nth(2).unwrap_or(""), key.split(':').nth(3).unwrap_or("")))
+        address, alkaneid, txid, vout))
 }
 
 
-pub fn get_alkane_fund_utxo(address: String, alkaneid: String) -> Vec<AlkaneUtxoRecord> {
+/// Removes the outpoints of `address` in `outpoints` and returns every
+/// `(alkaneid, record)` they held.
+/// Called by the canister itself after broadcasting a spend, so no caller check.
+pub fn take_utxos(address: &str, outpoints: &[(String, u64)]) -> Vec<(String, AlkaneUtxoRecord)> {
     ALKANE_UTXO_LEDGER.with(|ledger| {
-        ledger.borrow()
+        let mut ledger = ledger.borrow_mut();
+        outpoints
             .iter()
-            .filter(|(key, _)| {
-                let parts: Vec<&str> = key.split(':').collect();
-                parts.len() >= 2 && 
-                parts.get(0) == Some(&address.as_str()) && 
-                parts.get(1) == Some(&alkaneid.as_str())
+            .flat_map(|(txid, vout)| {
+                let key = UtxoKey::new(address, txid, *vout);
+                ledger.remove(&key).map(|utxo| alkane_records(&key, &utxo)).unwrap_or_default()
             })
-            .map(|(_, value)| value.clone())
             .collect()
     })
 }
 
-pub fn remove_utxo(address: String, alkaneid: String, txid: String, vout: u64) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
+/// Sets the balance of `alkaneid` on the outpoint of `utxo`, next to whatever
+/// else that outpoint holds. Also records outputs of our own transactions
+/// (e.g. withdraw change).
+pub fn put_utxo(address: &str, alkaneid: &str, utxo: AlkaneUtxoRecord) -> Result<(), String> {
+    let alkaneid = alkaneid.parse()?;
+    let key = UtxoKey::new(address, &utxo.txid, utxo.vout);
+    ALKANE_UTXO_LEDGER.with(|ledger| ledger.borrow_mut().set_balance(key, utxo.satoshi, alkaneid, utxo.amount));
+    Ok(())
+}
 
-    let key = make_utxo_key(&address, &alkaneid, &txid, vout);
-    ALKANE_UTXO_LEDGER.with(|ledger| {
-        ledger.borrow_mut().remove(&key);
-    });
+/// Removes one alkane's balance from an outpoint without checking the caller.
+pub fn delete_utxo(address: &str, alkaneid: &str, txid: &str, vout: u64) -> Option<AlkaneUtxoRecord> {
+    let alkaneid = alkaneid.parse().ok()?;
+    let (satoshi, amount) = ALKANE_UTXO_LEDGER.with(|ledger| ledger.borrow_mut().remove_balance(&UtxoKey::new(address, txid, vout), alkaneid))?;
+    Some(AlkaneUtxoRecord { amount, txid: txid.to_string(), vout, satoshi })
+}
 
-    Ok(format!("UTXO removed for address: {}, alkaneid: {}, txid: {}, vout: {}", 
-        address, alkaneid, txid, vout))
+pub fn get_alkane_fund_utxo(address: String, alkaneid: String) -> Vec<AlkaneUtxoRecord> {
+    let Ok(alkaneid) = alkaneid.parse::<AlkaneId>() else {
+        return Vec::new();
+    };
+    let first = UtxoKey::new(&address, "", 0);
+    ALKANE_UTXO_LEDGER.with(|ledger| {
+        let ledger = ledger.borrow();
+        ledger
+            .indexed(ledger.by_alkane.get(&alkaneid), None)
+            .skip_while(|(key, _)| **key < first)
+            .take_while(|(key, _)| key.address == address)
+            .flat_map(|(key, utxo)| alkane_records(key, utxo))
+            .filter(|(id, _)| *id == alkaneid.to_string())
+            .map(|(_, record)| record)
+            .collect()
+    })
 }
 
+/// Every outpoint of `address` with its whole balance sheet.
+pub fn get_outpoints_by_address(address: &str) -> Vec<(OutPoint, UtxoBalances)> {
+    ALKANE_UTXO_LEDGER.with(|ledger| {
+        ledger.borrow()
+            .address_range(address, None)
+            .map(|(key, utxo)| (key.outpoint.clone(), utxo.clone()))
+            .collect()
+    })
+}
 
 pub fn get_utxos_by_address(address: String) -> Vec<(String, String, AlkaneUtxoRecord)> {
     ALKANE_UTXO_LEDGER.with(|ledger| {
         ledger.borrow()
-            .iter()
-            .filter(|(key, _)| key.starts_with(&format!("{}:", address)))
-            .map(|(key, value)| {
-                let parts: Vec<&str> = key.split(':').collect();
-                let addr = parts.get(0).unwrap_or(&"").to_string();
-                let alkaneid = parts.get(1).unwrap_or(&"").to_string();
-                (addr, alkaneid, value.clone())
-            })
+            .address_range(&address, None)
+            .flat_map(|(key, utxo)| alkane_records(key, utxo))
+            .map(|(alkaneid, record)| (address.clone(), alkaneid, record))
             .collect()
     })
 }
 
 pub fn get_utxos_by_alkaneid(alkaneid: String) -> Vec<(String, AlkaneUtxoRecord)> {
+    let Ok(id) = alkaneid.parse::<AlkaneId>() else {
+        return Vec::new();
+    };
     ALKANE_UTXO_LEDGER.with(|ledger| {
-        ledger.borrow()
-            .iter()
-            .filter(|(key, _)| {
-                let parts: Vec<&str> = key.split(':').collect();
-                parts.len() >= 2 && parts.get(1) == Some(&alkaneid.as_str())
-            })
-            .map(|(key, value)| {
-                // 从键中提取地址
-                let address = key.split(':').next().unwrap_or("").to_string();
-                (address, value.clone())
+        let ledger = ledger.borrow();
+        ledger
+            .indexed(ledger.by_alkane.get(&id), None)
+            .flat_map(|(key, utxo)| {
+                alkane_records(key, utxo).into_iter().filter(|(aid, _)| *aid == id.to_string()).map(|(_, record)| (key.address.clone(), record))
             })
             .collect()
     })
@@ -192,29 +493,27 @@ pub fn get_utxos_by_alkaneid(alkaneid: String) -> Vec<(String, AlkaneUtxoRecord)
 pub fn get_all_utxos() -> Vec<(String, String, AlkaneUtxoRecord)> {
     ALKANE_UTXO_LEDGER.with(|ledger| {
         ledger.borrow()
+            .entries
             .iter()
-            .map(|(key, value)| {
-                let parts: Vec<&str> = key.split(':').collect();
-                let address = parts.get(0).unwrap_or(&"").to_string();
-                let alkaneid = parts.get(1).unwrap_or(&"").to_string();
-                (address, alkaneid, value.clone())
+            .flat_map(|(key, utxo)| {
+                alkane_records(key, utxo).into_iter().map(|(alkaneid, record)| (key.address.clone(), alkaneid, record))
             })
             .collect()
     })
 }
 
-
-
+/// One page of ledger entries matching `query`.
+pub fn query_utxos(query: &UtxoQuery) -> Result<UtxoPage, String> {
+    ALKANE_UTXO_LEDGER.with(|ledger| ledger.borrow().page(query))
+}
 
 pub fn utxo_count() -> u64 {
-    ALKANE_UTXO_LEDGER.with(|ledger| ledger.borrow().len() as u64)
+    ALKANE_UTXO_LEDGER.with(|ledger| ledger.borrow().entries.len() as u64)
 }
 
 
 pub fn batch_upload(batch: Vec<BatchAlkaneData>) -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
+    require(Role::Uploader)?;
 
     let mut uploaded = 0;
 
@@ -249,21 +548,21 @@ pub fn count() -> u64 {
     ALKANE_DATA.with(|db| db.borrow().len() as u64)
 }
 
+/// Runs from an approved `ClearAll` proposal, which does the authorization.
 pub fn clear() -> Result<String, String> {
-    if !is_authorized() {
-        return Err("Unauthorized".into());
-    }
-
     ALKANE_DATA.with(|db| db.borrow_mut().clear());
     WHITE_TOKEN_LIST.with(|set| set.borrow_mut().clear());
     TOKEN_ID_MAP.with(|map| map.borrow_mut().clear());
+    TOKEN_CONFIGS.with(|map| map.borrow_mut().clear());
     ALKANE_UTXO_LEDGER.with(|ledger| ledger.borrow_mut().clear());
 
     Ok("All data cleared".into())
 }
 
-pub fn pre_upgrade() {
-    let state_data: Vec<(AlkaneKey, AlkaneRecord)> = ALKANE_DATA.with(|db| {
+/// `runtime` carries the canister-level state owned by `lib.rs`; it is appended
+/// to the tuple as an `opt` so snapshots taken before it existed still decode.
+pub fn pre_upgrade<R: CandidType>(runtime: R) {
+    let alkane_records: Vec<(AlkaneKey, AlkaneRecord)> = ALKANE_DATA.with(|db| {
         db.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
     });
 
@@ -273,51 +572,223 @@ pub fn pre_upgrade() {
         map.borrow().iter().map(|(k, v)| (k.clone(), *v)).collect()
     });
 
-    let utxo_ledger: Vec<(AlkaneUtxoKey, AlkaneUtxoRecord)> = ALKANE_UTXO_LEDGER.with(|ledger| {
-        ledger.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    // 旧格式（nat64 数量、按 alkane 记账）的位置都留空，新数据存到后面的 opt 里
+    let legacy_records: Vec<(AlkaneKey, AlkaneRecordV1)> = Vec::new();
+    let legacy_utxo_ledger: Vec<(AlkaneUtxoKey, AlkaneUtxoRecordV1)> = Vec::new();
+    let utxo_ledger: Vec<(UtxoKey, UtxoBalances)> = ALKANE_UTXO_LEDGER.with(|ledger| {
+        ledger.borrow().entries.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
     });
 
-    storage::stable_save((state_data, whitelist, token_id_map, utxo_ledger, OWNER.with(|o| *o.borrow()))).unwrap();
+    storage::stable_save((
+        legacy_records,
+        whitelist,
+        token_id_map,
+        legacy_utxo_ledger,
+        OWNER.with(|o| *o.borrow()),
+        Some(runtime),
+        None::<Vec<(AlkaneEntryKey, AlkaneUtxoRecordV1)>>,
+        Some(utxo_ledger),
+        Some(alkane_records),
+        Some(get_token_configs()),
+    ))
+    .unwrap();
 }
 
 
 
-pub fn post_upgrade() {
-    if let Ok((data, whitelist, token_id_map, utxo_ledger, owner)) =
-        storage::stable_restore::<(Vec<(AlkaneKey, AlkaneRecord)>, Vec<String>, Vec<(String, u64)>, Vec<(AlkaneUtxoKey, AlkaneUtxoRecord)>, Principal)>()
-    {
-        OWNER.with(|o| *o.borrow_mut() = owner);
+/// Everything `pre_upgrade` saves, in order. Fields added later are `opt` at
+/// the end so older snapshots still decode.
+type Snapshot<R> = (
+    Vec<(AlkaneKey, AlkaneRecordV1)>,
+    Vec<String>,
+    Vec<(String, u64)>,
+    Vec<(AlkaneUtxoKey, AlkaneUtxoRecordV1)>,
+    Principal,
+    Option<R>,
+    Option<Vec<(AlkaneEntryKey, AlkaneUtxoRecordV1)>>,
+    Option<Vec<(UtxoKey, UtxoBalances)>>,
+    Option<Vec<(AlkaneKey, AlkaneRecord)>>,
+    Option<Vec<(String, TokenConfig)>>,
+);
+
+/// Returns the runtime state saved by `pre_upgrade`, if the snapshot had one.
+///
+/// Traps when the snapshot does not decode: starting over with an empty
+/// ledger would silently lose track of the funds, so the upgrade fails and
+/// the canister keeps running the old version.
+pub fn post_upgrade<R: CandidType + DeserializeOwned>() -> Option<R> {
+    match storage::stable_restore::<Snapshot<R>>() {
+        Ok(snapshot) => restore(snapshot),
+        Err(e) => ic_cdk::trap(&format!("[upgrade] stable snapshot does not decode: {}", e)),
+    }
+}
 
-        ALKANE_DATA.with(|db| {
-            let mut map = db.borrow_mut();
-            map.clear();
-            for (k, v) in data {
-                map.insert(k, v);
-            }
-        });
+/// Older snapshots keep one ledger entry per alkane, under string keys or
+/// under `(address, alkaneid, outpoint)`; their entries are merged into
+/// outpoints. A string key that does not parse is logged and dropped.
+/// Amounts stored as `nat64` by older snapshots are widened.
+fn restore<R>(snapshot: Snapshot<R>) -> Option<R> {
+    let (
+        legacy_records,
+        whitelist,
+        token_id_map,
+        legacy_utxo_ledger,
+        owner,
+        runtime,
+        alkane_entries,
+        utxo_ledger,
+        alkane_records,
+        token_configs,
+    ) = snapshot;
+    OWNER.with(|o| *o.borrow_mut() = owner);
 
-        WHITE_TOKEN_LIST.with(|set| {
-            let mut whitelist_set = set.borrow_mut();
-            whitelist_set.clear();
-            for token in whitelist {
-                whitelist_set.insert(token);
-            }
-        });
-        
-        TOKEN_ID_MAP.with(|map| {
-            let mut token_map = map.borrow_mut();
-            token_map.clear();
-            for (k, v) in token_id_map {
-                token_map.insert(k, v);
+    ALKANE_DATA.with(|db| {
+        let mut map = db.borrow_mut();
+        map.clear();
+        for (k, v) in legacy_records {
+            map.insert(k, v.into());
+        }
+        for (k, v) in alkane_records.unwrap_or_default() {
+            map.insert(k, v);
+        }
+    });
+
+    WHITE_TOKEN_LIST.with(|set| {
+        let mut whitelist_set = set.borrow_mut();
+        whitelist_set.clear();
+        for token in whitelist {
+            whitelist_set.insert(token);
+        }
+    });
+
+    TOKEN_ID_MAP.with(|map| {
+        let mut token_map = map.borrow_mut();
+        token_map.clear();
+        for (k, v) in token_id_map {
+            token_map.insert(k, v);
+        }
+    });
+    TOKEN_CONFIGS.with(|map| *map.borrow_mut() = token_configs.unwrap_or_default().into_iter().collect());
+
+    ALKANE_UTXO_LEDGER.with(|ledger| {
+        let mut ledger = ledger.borrow_mut();
+        ledger.clear();
+        let mut entries = Vec::new();
+        for (k, v) in legacy_utxo_ledger {
+            match parse_legacy_key(&k) {
+                Ok(key) => entries.push((key, v)),
+                Err(e) => crate::append_log(format!("[upgrade] dropped ledger entry: {}", e)),
             }
-        });
+        }
+        entries.extend(alkane_entries.unwrap_or_default());
+        for (k, v) in entries {
+            ledger.set_balance(UtxoKey { address: k.address, outpoint: k.outpoint }, v.satoshi, k.alkaneid, v.amount.into());
+        }
+        for (k, v) in utxo_ledger.unwrap_or_default() {
+            ledger.insert(k, v);
+        }
+    });
+
+    runtime
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        ALKANE_UTXO_LEDGER.with(|ledger| {
-            let mut utxo_map = ledger.borrow_mut();
-            utxo_map.clear();
-            for (k, v) in utxo_ledger {
-                utxo_map.insert(k, v);
+    fn alkane(block: u64, tx: u64) -> AlkaneId {
+        AlkaneId { block, tx }
+    }
+
+    #[test]
+    fn test_legacy_utxo_key_keeps_colons_in_alkaneid() {
+        let key = parse_legacy_key("bc1paddr:2:1:ab:3").unwrap();
+        assert_eq!((key.address.as_str(), key.alkaneid, key.outpoint), ("bc1paddr", alkane(2, 1), OutPoint { txid: "ab".into(), vout: 3 }));
+        let key = parse_legacy_key("bc1paddr:840000:7:ab:0").unwrap();
+        assert_eq!((key.address.as_str(), key.alkaneid.to_string()), ("bc1paddr", "840000:7".to_string()));
+        assert!(parse_legacy_key("bc1paddr:2:ab:0").is_err());
+    }
+
+    #[test]
+    fn test_ledger_indexes_and_pages() {
+        let mut ledger = UtxoLedger::default();
+        // "a" 是 "a:2" 的前缀，按地址查询不能串到另一个地址
+        ledger.set_balance(UtxoKey::new("a", "aa", 0), 330, alkane(2, 1), 1);
+        ledger.set_balance(UtxoKey::new("a", "aa", 0), 330, alkane(2, 10), 2);
+        ledger.set_balance(UtxoKey::new("a", "bb", 1), 330, alkane(2, 1), 3);
+        ledger.set_balance(UtxoKey::new("a:2", "cc", 0), 330, alkane(2, 1), 4);
+        // 同一 outpoint 上的两种 alkane 是一条记录
+        assert_eq!(ledger.entries.len(), 3);
+        let aa = ledger.entries.get(&UtxoKey::new("a", "aa", 0)).unwrap();
+        assert_eq!(aa.balances, vec![(alkane(2, 1), 1), (alkane(2, 10), 2)]);
+        let outpoints = |page: UtxoPage| page.entries.iter().map(|(key, _)| key.outpoint.txid.clone()).collect::<Vec<_>>();
+
+        let query = |address: Option<&str>, alkaneid: Option<&str>, outpoint: Option<(&str, u64)>| UtxoQuery {
+            address: address.map(str::to_string),
+            alkaneid: alkaneid.map(str::to_string),
+            outpoint: outpoint.map(|(txid, vout)| OutPoint { txid: txid.into(), vout }),
+            ..Default::default()
+        };
+        assert_eq!(outpoints(ledger.page(&query(Some("a"), None, None)).unwrap()), ["aa", "bb"]);
+        assert_eq!(outpoints(ledger.page(&query(Some("a"), Some("2:10"), None)).unwrap()), ["aa"]);
+        assert_eq!(outpoints(ledger.page(&query(None, Some("2:1"), None)).unwrap()), ["aa", "bb", "cc"]);
+        assert_eq!(outpoints(ledger.page(&query(None, None, Some(("aa", 0)))).unwrap()), ["aa"]);
+        assert_eq!(outpoints(ledger.page(&query(Some("a:2"), None, Some(("aa", 0)))).unwrap()), Vec::<String>::new());
+        assert!(ledger.page(&query(None, Some("2"), None)).is_err());
+
+        // 分页：next 接着上一页往下翻，直到没有 next
+        let mut after = None;
+        let mut seen = Vec::new();
+        loop {
+            let page = ledger.page(&UtxoQuery { after: after.clone(), limit: Some(2), ..query(None, Some("2:1"), None) }).unwrap();
+            after = page.next.clone();
+            seen.extend(outpoints(page));
+            if after.is_none() {
+                break;
             }
-        });
+        }
+        assert_eq!(seen, ["aa", "bb", "cc"]);
+
+        // 去掉一种 alkane 后 outpoint 还在，最后一种也去掉后三个索引都不再有它
+        assert_eq!(ledger.remove_balance(&UtxoKey::new("a", "aa", 0), alkane(2, 1)), Some((330, 1)));
+        assert_eq!(outpoints(ledger.page(&query(None, Some("2:1"), None)).unwrap()), ["bb", "cc"]);
+        assert_eq!(outpoints(ledger.page(&query(None, None, Some(("aa", 0)))).unwrap()), ["aa"]);
+        assert_eq!(ledger.remove_balance(&UtxoKey::new("a", "aa", 0), alkane(2, 1)), None);
+        assert_eq!(ledger.remove_balance(&UtxoKey::new("a", "aa", 0), alkane(2, 10)), Some((330, 2)));
+        assert!(!ledger.by_outpoint.contains_key(&OutPoint { txid: "aa".into(), vout: 0 }));
+        assert!(!ledger.by_alkane.contains_key(&alkane(2, 10)));
+    }
+
+    #[test]
+    fn test_restore_pre_keyed_ledger_snapshot() {
+        // 按字符串键记账、数量还是 nat64 时 pre_upgrade 保存的 6 元组
+        let record = |amount| AlkaneUtxoRecordV1 { amount, txid: "ab".into(), vout: 0, satoshi: 330 };
+        let legacy = (
+            vec![("r1".to_string(), AlkaneRecordV1 { txid: "cd".into(), vout: 1, send_address: "bc1puser".into(), alkaneid: "2:1".into(), amount: 50 })],
+            vec!["2:1".to_string()],
+            vec![("2:1".to_string(), 7u64)],
+            vec![
+                ("bc1pfund:2:1:ab:0".to_string(), record(u64::MAX)),
+                ("bc1pfund:2:10:ab:0".to_string(), record(5)),
+                ("bc1pfund:bad".to_string(), record(1)),
+            ],
+            Principal::anonymous(),
+            Some("runtime".to_string()),
+        );
+        let bytes = candid::encode_args(legacy).unwrap();
+        let snapshot: Snapshot<String> = candid::decode_args(&bytes).unwrap();
+        assert_eq!(restore(snapshot), Some("runtime".to_string()));
+
+        assert_eq!(get_outpoints_by_address("bc1pfund"), vec![(
+            OutPoint { txid: "ab".into(), vout: 0 },
+            UtxoBalances { satoshi: 330, balances: vec![(alkane(2, 1), u64::MAX as u128), (alkane(2, 10), 5)] },
+        )]);
+        assert_eq!(utxo_count(), 1);
+        assert_eq!(get_all().iter().map(|r| (r.alkaneid.clone(), r.amount)).collect::<Vec<_>>(), vec![("2:1".to_string(), 50)]);
+        assert_eq!(get_token_id_by_alkaneid("2:1".into()), Ok(7));
+        assert!(is_white_token("2:1".into()));
+
+        // 解不出来的快照不会被当成空账本
+        assert!(candid::decode_args::<Snapshot<String>>(&candid::encode_args((vec![1u8],)).unwrap()).is_err());
     }
 }
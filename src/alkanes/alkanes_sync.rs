@@ -0,0 +1,214 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::alkanes::alkanes_storage::{delete_utxo, get_utxos_by_address, put_utxo, AlkaneUtxoRecord};
+use crate::events::{self, EventKind};
+use crate::indexer::{self, AlkaneUtxo, AlkanesIndexer};
+use crate::PendingTx;
+
+/// 需要和 indexer 对账的地址类型
+pub const SYNCED_ADDRESSES: [&str; 2] = ["alkanes_topup", "alkanes_fund"];
+
+/// Last indexer view of one address, kept so the diff can be previewed from a
+/// query without another outcall.
+#[derive(Clone)]
+struct Snapshot {
+    fetched_at: u64,
+    utxos: Vec<AlkaneUtxo>,
+}
+
+thread_local! {
+    static SNAPSHOTS: RefCell<HashMap<String, Snapshot>> = RefCell::new(HashMap::new());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerEntry {
+    pub alkaneid: String,
+    pub utxo: AlkaneUtxoRecord,
+}
+
+/// Differences between the ledger and the indexer for one address. An entry
+/// whose amount changed shows up in both lists.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct LedgerDiff {
+    pub address: String,
+    pub fetched_at: u64,
+    pub added: Vec<LedgerEntry>,
+    pub removed: Vec<LedgerEntry>,
+}
+
+impl LedgerDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// 计算账本和 indexer 之间的差异
+///
+/// Outputs of our own pending txs are never removed (the indexer has not seen
+/// them yet), and outpoints those txs spend are never re-added.
+pub fn diff_ledger(
+    address: &str,
+    ledger: &[(String, AlkaneUtxoRecord)],
+    indexed: &[AlkaneUtxo],
+    pending: &[PendingTx],
+) -> LedgerDiff {
+    let mut expected: Vec<LedgerEntry> = Vec::new();
+    for utxo in indexed {
+        if crate::is_spent_by_pending(&utxo.txid, utxo.vout, pending) {
+            continue;
+        }
+        for balance in &utxo.balances {
+            expected.push(LedgerEntry {
+                alkaneid: balance.alkaneid.clone(),
+                utxo: AlkaneUtxoRecord {
+                    amount: balance.amount,
+                    txid: utxo.txid.clone(),
+                    vout: utxo.vout,
+                    satoshi: utxo.satoshi,
+                },
+            });
+        }
+    }
+
+    let key = |e: &LedgerEntry| (e.alkaneid.clone(), e.utxo.txid.clone(), e.utxo.vout, e.utxo.amount);
+    let current: Vec<LedgerEntry> = ledger
+        .iter()
+        .map(|(alkaneid, utxo)| LedgerEntry { alkaneid: alkaneid.clone(), utxo: utxo.clone() })
+        .collect();
+    let current_keys: HashSet<_> = current.iter().map(key).collect();
+    let expected_keys: HashSet<_> = expected.iter().map(key).collect();
+    let pending_txids: HashSet<&str> = pending.iter().map(|tx| tx.txid.as_str()).collect();
+
+    LedgerDiff {
+        address: address.to_string(),
+        fetched_at: 0,
+        added: expected.into_iter().filter(|e| !current_keys.contains(&key(e))).collect(),
+        removed: current
+            .into_iter()
+            .filter(|e| !expected_keys.contains(&key(e)) && !pending_txids.contains(e.utxo.txid.as_str()))
+            .collect(),
+    }
+}
+
+/// Diffs every snapshot taken so far against the current ledger. Nothing is
+/// applied.
+pub fn preview(pending: &[PendingTx]) -> Vec<LedgerDiff> {
+    let snapshots = SNAPSHOTS.with(|s| s.borrow().clone());
+    let mut diffs: Vec<LedgerDiff> = snapshots
+        .into_iter()
+        .map(|(address, snapshot)| {
+            let mut diff = diff_ledger(&address, &ledger_entries(&address), &snapshot.utxos, pending);
+            diff.fetched_at = snapshot.fetched_at;
+            diff
+        })
+        .collect();
+    diffs.sort_by(|a, b| a.address.cmp(&b.address));
+    diffs
+}
+
+/// Fetches a fresh snapshot for `address` and returns its diff.
+pub async fn fetch_diff(address: &str, pending: &[PendingTx]) -> Result<LedgerDiff, String> {
+    let indexer = indexer::configured().map_err(|e| e.to_string())?;
+    let utxos = indexer.address_utxos(address).await.map_err(|e| e.to_string())?;
+    let fetched_at = ic_cdk::api::time();
+    SNAPSHOTS.with(|s| {
+        s.borrow_mut().insert(address.to_string(), Snapshot { fetched_at, utxos: utxos.clone() })
+    });
+    let mut diff = diff_ledger(address, &ledger_entries(address), &utxos, pending);
+    diff.fetched_at = fetched_at;
+    Ok(diff)
+}
+
+/// 应用差异，每条变更写一条审计日志
+pub fn apply(diff: &LedgerDiff) {
+    for entry in &diff.removed {
+        delete_utxo(&diff.address, &entry.alkaneid, &entry.utxo.txid, entry.utxo.vout);
+        crate::append_log(format!(
+            "[ledger-sync] remove address={} alkaneid={} outpoint={}:{} amount={}",
+            diff.address, entry.alkaneid, entry.utxo.txid, entry.utxo.vout, entry.utxo.amount
+        ));
+        events::event(EventKind::LedgerSynced)
+            .txid(entry.utxo.txid.clone())
+            .alkaneid(entry.alkaneid.clone())
+            .amount(entry.utxo.amount)
+            .detail(format!("remove address={} vout={}", diff.address, entry.utxo.vout))
+            .record();
+    }
+    for entry in &diff.added {
+        if let Err(e) = put_utxo(&diff.address, &entry.alkaneid, entry.utxo.clone()) {
+            crate::append_log(format!("[ledger-sync] skip address={} err={}", diff.address, e));
+            continue;
+        }
+        crate::append_log(format!(
+            "[ledger-sync] add address={} alkaneid={} outpoint={}:{} amount={}",
+            diff.address, entry.alkaneid, entry.utxo.txid, entry.utxo.vout, entry.utxo.amount
+        ));
+        events::event(EventKind::LedgerSynced)
+            .txid(entry.utxo.txid.clone())
+            .alkaneid(entry.alkaneid.clone())
+            .amount(entry.utxo.amount)
+            .detail(format!("add address={} vout={}", diff.address, entry.utxo.vout))
+            .record();
+    }
+}
+
+fn ledger_entries(address: &str) -> Vec<(String, AlkaneUtxoRecord)> {
+    get_utxos_by_address(address.to_string())
+        .into_iter()
+        .map(|(_, alkaneid, utxo)| (alkaneid, utxo))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::AlkaneBalance;
+
+    fn record(txid: &str, vout: u64, amount: u128) -> AlkaneUtxoRecord {
+        AlkaneUtxoRecord { amount, txid: txid.into(), vout, satoshi: 330 }
+    }
+
+    fn indexed(txid: &str, vout: u64, alkaneid: &str, amount: u128) -> AlkaneUtxo {
+        AlkaneUtxo {
+            txid: txid.into(),
+            vout,
+            satoshi: 330,
+            balances: vec![AlkaneBalance { alkaneid: alkaneid.into(), amount }],
+        }
+    }
+
+    #[test]
+    fn test_diff_ledger_respects_pending_txs() {
+        let pending = vec![PendingTx {
+            txid: "cc".into(),
+            kind: "withdraw".into(),
+            spent_outpoints: vec![("dd".into(), 0)],
+            broadcast_at: 0,
+            watch_output: None,
+            in_flight: None,
+            requests: None,
+            spent_entries: None,
+        }];
+        let ledger = vec![
+            ("2:1".to_string(), record("aa", 0, 100)),
+            ("2:1".to_string(), record("bb", 1, 50)),
+            ("2:1".to_string(), record("cc", 0, 10)),
+        ];
+        let indexed = vec![
+            indexed("aa", 0, "2:1", 100),
+            indexed("bb", 1, "2:1", 70),
+            indexed("dd", 0, "2:1", 5),
+            indexed("ee", 2, "2:2", 9),
+            // 超过 u64 的余额原样入账
+            indexed("ff", 0, "2:3", u64::MAX as u128 + 1),
+        ];
+
+        let diff = diff_ledger("addr", &ledger, &indexed, &pending);
+        let added: Vec<_> = diff.added.iter().map(|e| (e.utxo.txid.as_str(), e.utxo.amount)).collect();
+        let removed: Vec<_> = diff.removed.iter().map(|e| (e.utxo.txid.as_str(), e.utxo.amount)).collect();
+        assert_eq!(added, vec![("bb", 70), ("ee", 9), ("ff", u64::MAX as u128 + 1)]);
+        assert_eq!(removed, vec![("bb", 50)]);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod alkanes_storage;
 pub mod alkanes_data;
 
+pub mod alkanes_sync;
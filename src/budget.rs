@@ -0,0 +1,243 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::FomowellError;
+use crate::events::{self, EventKind};
+use crate::time;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const T: u128 = 1_000_000_000_000;
+
+/// At most `max_calls` per principal in each fixed window of `window_secs`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub max_calls: u32,
+    pub window_secs: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BudgetConfig {
+    /// Cycles an operation (as named in `get_metrics`) may spend per UTC day.
+    /// Operations not listed are not limited.
+    pub daily_budgets: Vec<(String, u128)>,
+    pub topup_rate_limit: Option<RateLimit>,
+    /// Applies to the update calls that look up an address and pay for it
+    /// (`get_btc_utxos`, `indexer_balances`, `indexer_address_utxos`).
+    pub address_query_rate_limit: Option<RateLimit>,
+    /// A `LowCycleBalance` event is recorded once the balance drops below this.
+    pub low_balance_threshold: u128,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            daily_budgets: vec![
+                ("sign_with_schnorr".to_string(), 2 * T),
+                ("bitcoin_get_utxos".to_string(), T),
+                ("http_outcall".to_string(), T / 2),
+                ("bitcoin_send_transaction".to_string(), T / 2),
+            ],
+            topup_rate_limit: Some(RateLimit { max_calls: 10, window_secs: 10 * 60 }),
+            address_query_rate_limit: Some(RateLimit { max_calls: 30, window_secs: 60 * 60 }),
+            low_balance_threshold: 5 * T,
+        }
+    }
+}
+
+/// 当天各操作已花费的 cycles
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct DailySpend {
+    /// Days since the unix epoch.
+    pub day: u64,
+    pub spent: Vec<(String, u128)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BudgetStatus {
+    pub config: BudgetConfig,
+    pub today: DailySpend,
+    pub cycle_balance: u128,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateScope {
+    Topup,
+    AddressQuery,
+}
+
+thread_local! {
+    static CONFIG: RefCell<BudgetConfig> = RefCell::new(BudgetConfig::default());
+    static SPENT: RefCell<(u64, BTreeMap<String, u128>)> = const { RefCell::new((0, BTreeMap::new())) };
+    // (窗口起始秒, 次数)，不跨升级保留
+    static RATE_WINDOWS: RefCell<HashMap<(RateScope, Principal), (u64, u32)>> = RefCell::new(HashMap::new());
+    static LOW_BALANCE_ALERTED: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Books `cycles` against today's budget of `operation` before the call is
+/// made. Cycles attached to a call are mostly kept even when it fails, so
+/// there is no refund.
+pub fn charge(operation: &str, cycles: u128) -> Result<(), FomowellError> {
+    let day = time() / NANOS_PER_SEC / SECS_PER_DAY;
+    let budgets = CONFIG.with(|c| c.borrow().daily_budgets.clone());
+    let result = SPENT.with(|s| charge_at(&mut s.borrow_mut(), &budgets, operation, cycles, day));
+    if let Err(e) = &result {
+        crate::append_log(format!("[budget] {}", e));
+    }
+    check_balance();
+    result.map_err(FomowellError::BudgetExceeded)
+}
+
+fn charge_at(
+    spent: &mut (u64, BTreeMap<String, u128>),
+    budgets: &[(String, u128)],
+    operation: &str,
+    cycles: u128,
+    day: u64,
+) -> Result<(), String> {
+    if spent.0 != day {
+        *spent = (day, BTreeMap::new());
+    }
+    let used = spent.1.get(operation).copied().unwrap_or(0);
+    if let Some((_, budget)) = budgets.iter().find(|(op, _)| op == operation)
+        && used.saturating_add(cycles) > *budget
+    {
+        return Err(format!(
+            "Daily cycle budget of {} exhausted: spent {}, budget {}, call needs {}",
+            operation, used, budget, cycles
+        ));
+    }
+    spent.1.insert(operation.to_string(), used.saturating_add(cycles));
+    Ok(())
+}
+
+/// Counts one call by `principal` and rejects it once the scope's limit is hit.
+pub fn check_rate(scope: RateScope, principal: Principal) -> Result<(), FomowellError> {
+    let limit = CONFIG.with(|c| {
+        let config = c.borrow();
+        match scope {
+            RateScope::Topup => config.topup_rate_limit.clone(),
+            RateScope::AddressQuery => config.address_query_rate_limit.clone(),
+        }
+    });
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let now_secs = time() / NANOS_PER_SEC;
+    RATE_WINDOWS
+        .with(|w| check_rate_at(&mut w.borrow_mut(), &limit, (scope, principal), now_secs))
+        .map_err(FomowellError::RateLimited)
+}
+
+fn check_rate_at(
+    windows: &mut HashMap<(RateScope, Principal), (u64, u32)>,
+    limit: &RateLimit,
+    key: (RateScope, Principal),
+    now_secs: u64,
+) -> Result<(), String> {
+    // 过期窗口顺手清掉，避免表无限增长
+    windows.retain(|(scope, _), (start, _)| *scope != key.0 || now_secs < start.saturating_add(limit.window_secs));
+    let (start, calls) = windows.entry(key).or_insert((now_secs, 0));
+    if *calls >= limit.max_calls {
+        return Err(format!(
+            "Rate limit exceeded: {} calls per {}s, retry after {}s",
+            limit.max_calls,
+            limit.window_secs,
+            (*start + limit.window_secs).saturating_sub(now_secs)
+        ));
+    }
+    *calls += 1;
+    Ok(())
+}
+
+/// Records a `LowCycleBalance` event when the balance first drops below the
+/// threshold; it fires again only after the balance has recovered.
+pub fn check_balance() {
+    let balance = ic_cdk::api::canister_balance128();
+    let threshold = CONFIG.with(|c| c.borrow().low_balance_threshold);
+    let low = balance < threshold;
+    let alerted = LOW_BALANCE_ALERTED.with(|a| std::mem::replace(&mut *a.borrow_mut(), low));
+    if low && !alerted {
+        crate::append_log(format!("[budget] low cycle balance balance={} threshold={}", balance, threshold));
+        events::event(EventKind::LowCycleBalance)
+            .detail(format!("balance {} below threshold {}", balance, threshold))
+            .record();
+    }
+}
+
+pub fn validate_config(config: &BudgetConfig) -> Result<(), String> {
+    for limit in [&config.topup_rate_limit, &config.address_query_rate_limit].into_iter().flatten() {
+        if limit.max_calls == 0 || limit.window_secs == 0 {
+            return Err("Rate limits need max_calls and window_secs of at least 1".into());
+        }
+    }
+    let mut operations: Vec<&str> = config.daily_budgets.iter().map(|(op, _)| op.as_str()).collect();
+    operations.sort();
+    if operations.windows(2).any(|w| w[0] == w[1]) {
+        return Err("Duplicate operation in daily_budgets".into());
+    }
+    Ok(())
+}
+
+/// Runs from an approved `SetBudgetConfig` proposal.
+pub fn set_config(config: BudgetConfig) -> Result<(), String> {
+    validate_config(&config)?;
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    Ok(())
+}
+
+pub fn config() -> BudgetConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+pub fn today() -> DailySpend {
+    SPENT.with(|s| {
+        let (day, spent) = &*s.borrow();
+        DailySpend { day: *day, spent: spent.iter().map(|(op, c)| (op.clone(), *c)).collect() }
+    })
+}
+
+pub fn status() -> BudgetStatus {
+    BudgetStatus { config: config(), today: today(), cycle_balance: ic_cdk::api::canister_balance128() }
+}
+
+pub fn restore(config: BudgetConfig, today: DailySpend) {
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    SPENT.with(|s| *s.borrow_mut() = (today.day, today.spent.into_iter().collect()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_and_rate_limit() {
+        let budgets = vec![("sign_with_schnorr".to_string(), 50)];
+        let mut spent = (0, BTreeMap::new());
+        assert!(charge_at(&mut spent, &budgets, "sign_with_schnorr", 25, 1).is_ok());
+        assert!(charge_at(&mut spent, &budgets, "sign_with_schnorr", 25, 1).is_ok());
+        assert!(charge_at(&mut spent, &budgets, "sign_with_schnorr", 25, 1).is_err());
+        assert!(charge_at(&mut spent, &budgets, "http_outcall", 1_000, 1).is_ok());
+        // 新的一天重新计数
+        assert!(charge_at(&mut spent, &budgets, "sign_with_schnorr", 25, 2).is_ok());
+        assert_eq!(spent.1.get("sign_with_schnorr"), Some(&25));
+
+        let limit = RateLimit { max_calls: 2, window_secs: 60 };
+        let alice = (RateScope::Topup, Principal::from_slice(&[1]));
+        let bob = (RateScope::Topup, Principal::from_slice(&[2]));
+        let mut windows = HashMap::new();
+        assert!(check_rate_at(&mut windows, &limit, alice, 100).is_ok());
+        assert!(check_rate_at(&mut windows, &limit, alice, 110).is_ok());
+        assert!(check_rate_at(&mut windows, &limit, alice, 120).is_err());
+        assert!(check_rate_at(&mut windows, &limit, bob, 120).is_ok());
+        assert!(check_rate_at(&mut windows, &limit, alice, 160).is_ok());
+
+        let config = BudgetConfig {
+            topup_rate_limit: Some(RateLimit { max_calls: 0, window_secs: 60 }),
+            ..Default::default()
+        };
+        assert!(validate_config(&config).is_err());
+        assert!(validate_config(&BudgetConfig::default()).is_ok());
+    }
+}
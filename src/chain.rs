@@ -0,0 +1,656 @@
+use std::cell::RefCell;
+
+use crate::error::FomowellError;
+use crate::ic::bitcoin_api::send_transaction;
+use crate::{
+    create_transaction_multi, TransactionInput, TransactionOutput, TransactionResult, UtxoInfo,
+    IC_BITCOIN_NETWORK, SCHNORR_KEY_NAME,
+};
+
+/// What mempool.space knows about a transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TxStatus {
+    Confirmed,
+    Unconfirmed,
+    /// Neither mined nor in the mempool.
+    NotFound,
+}
+
+/// Everything the withdraw and gather flows and the confirmation check ask
+/// of the outside world.
+///
+/// Keeping these calls behind one trait lets the tests fail each step and
+/// check that the flow left the ledger and queues untouched.
+pub(crate) trait Chain {
+    async fn fee_rate(&self) -> Result<f64, FomowellError>;
+    async fn btc_utxos(&self, address: &str) -> Result<Vec<UtxoInfo>, FomowellError>;
+    async fn public_key(&self, derivation: &str) -> Result<Vec<u8>, FomowellError>;
+    /// Builds the PSBT and signs every input with the key named by its `signer`.
+    async fn sign(
+        &self,
+        inputs: Vec<TransactionInput>,
+        outputs: Vec<TransactionOutput>,
+    ) -> Result<TransactionResult, FomowellError>;
+    async fn broadcast(&self, transaction: Vec<u8>) -> Result<(), String>;
+    /// Outpoints `(txid, vout)` of `address` with at least `min_confirmations`.
+    async fn confirmed_outpoints(&self, address: &str, min_confirmations: u32) -> Result<Vec<(String, u32)>, FomowellError>;
+    /// Asks mempool.space over HTTP.
+    async fn tx_status(&self, txid: &str) -> Result<TxStatus, String>;
+}
+
+/// Fee canister, Bitcoin canister and threshold Schnorr.
+pub(crate) struct IcChain;
+
+impl Chain for IcChain {
+    async fn fee_rate(&self) -> Result<f64, FomowellError> {
+        crate::get_feerate().await
+    }
+
+    async fn btc_utxos(&self, address: &str) -> Result<Vec<UtxoInfo>, FomowellError> {
+        crate::fetch_btc_utxos(address).await
+    }
+
+    async fn public_key(&self, derivation: &str) -> Result<Vec<u8>, FomowellError> {
+        crate::ic::schnorr_api::schnorr_public_key(SCHNORR_KEY_NAME.to_string(), crate::get_derivation_path(derivation))
+            .await
+    }
+
+    async fn sign(
+        &self,
+        inputs: Vec<TransactionInput>,
+        outputs: Vec<TransactionOutput>,
+    ) -> Result<TransactionResult, FomowellError> {
+        create_transaction_multi("testnet", inputs, outputs)
+            .await
+            .map_err(FomowellError::TxBuildFailed)
+    }
+
+    async fn broadcast(&self, transaction: Vec<u8>) -> Result<(), String> {
+        send_transaction(IC_BITCOIN_NETWORK, transaction).await
+    }
+
+    async fn confirmed_outpoints(&self, address: &str, min_confirmations: u32) -> Result<Vec<(String, u32)>, FomowellError> {
+        let (utxos, _) = crate::fetch_utxos(address, Some(min_confirmations)).await?;
+        Ok(utxos.into_iter().map(|u| (crate::txid_to_hex(&u.outpoint.txid), u.outpoint.vout)).collect())
+    }
+
+    async fn tx_status(&self, txid: &str) -> Result<TxStatus, String> {
+        crate::check_tx_status_http(txid).await
+    }
+}
+
+thread_local! {
+    // 正在构建交易的流程名，withdraw 和 gather 共用 btc utxo，不能并发
+    static RUNNING_FLOW: RefCell<Option<&'static str>> = const { RefCell::new(None) };
+}
+
+/// Held while a withdraw or gather run picks inputs, signs and broadcasts.
+///
+/// Released on drop. If the run traps after an await, ic-cdk drops the
+/// future during cleanup, so a trap does not leave the lock taken either.
+pub(crate) struct FlowGuard;
+
+impl FlowGuard {
+    pub fn acquire(flow: &'static str) -> Result<Self, FomowellError> {
+        RUNNING_FLOW.with(|r| {
+            let mut running = r.borrow_mut();
+            match *running {
+                Some(other) => Err(FomowellError::Busy(other.to_string())),
+                None => {
+                    *running = Some(flow);
+                    Ok(FlowGuard)
+                }
+            }
+        })
+    }
+}
+
+impl Drop for FlowGuard {
+    fn drop(&mut self) {
+        RUNNING_FLOW.with(|r| *r.borrow_mut() = None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alkanes::alkanes_storage::{get_utxos_by_address, put_utxo, AlkaneUtxoRecord};
+    use crate::alkanes::alkanes_storage::set_token_config;
+    use crate::conversion::TokenConfig;
+    use crate::events::{self, EventFilter, EventKind};
+    use crate::fees;
+    use crate::gather::GatherPolicy;
+    use crate::txplan::test_address;
+    use crate::{WithdrawRequest, ADDRESSES, PENDING_TXS, WITHDRAW_REQUESTS};
+    use candid::Principal;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Step {
+        FeeRate,
+        BtcUtxos,
+        PublicKey,
+        Sign,
+        Broadcast,
+    }
+
+    struct MockChain(Option<Step>);
+
+    impl MockChain {
+        fn check(&self, step: Step) -> Result<(), FomowellError> {
+            match self.0 {
+                Some(failing) if failing == step => Err(FomowellError::Internal(format!("{:?} injected", step))),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Chain for MockChain {
+        async fn fee_rate(&self) -> Result<f64, FomowellError> {
+            self.check(Step::FeeRate).map(|()| 2.0)
+        }
+
+        async fn btc_utxos(&self, _address: &str) -> Result<Vec<UtxoInfo>, FomowellError> {
+            self.check(Step::BtcUtxos)?;
+            Ok(vec![UtxoInfo { value: 100_000, txid: "cc".repeat(32), vout: 1 }])
+        }
+
+        async fn public_key(&self, _derivation: &str) -> Result<Vec<u8>, FomowellError> {
+            self.check(Step::PublicKey).map(|()| vec![2; 33])
+        }
+
+        async fn sign(
+            &self,
+            _inputs: Vec<TransactionInput>,
+            _outputs: Vec<TransactionOutput>,
+        ) -> Result<TransactionResult, FomowellError> {
+            self.check(Step::Sign)?;
+            Ok(TransactionResult { txid: "dd".repeat(32), psbt_base64: String::new(), psbt_hex: "00".into(), vsize: 0 })
+        }
+
+        async fn broadcast(&self, _transaction: Vec<u8>) -> Result<(), String> {
+            self.check(Step::Broadcast).map_err(|e| e.to_string())
+        }
+
+        async fn confirmed_outpoints(&self, _address: &str, _min_confirmations: u32) -> Result<Vec<(String, u32)>, FomowellError> {
+            Ok(MINED.with(|m| m.borrow().clone()))
+        }
+
+        async fn tx_status(&self, txid: &str) -> Result<TxStatus, String> {
+            Ok(MEMPOOL.with(|m| m.borrow().get(txid).copied().unwrap_or(TxStatus::NotFound)))
+        }
+    }
+
+    thread_local! {
+        // bitcoin canister 报告为已确认的输出，以及 mempool.space 对每个 txid 的回答
+        static MINED: RefCell<Vec<(String, u32)>> = const { RefCell::new(Vec::new()) };
+        static MEMPOOL: RefCell<HashMap<String, TxStatus>> = RefCell::new(HashMap::new());
+    }
+
+    // MockChain 从不挂起，poll 一次就完成
+    fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("mock chain suspended"),
+        }
+    }
+
+    fn ledger(address: &str) -> Vec<(String, u128)> {
+        let mut entries: Vec<(String, u128)> = get_utxos_by_address(address.to_string())
+            .into_iter()
+            .map(|(_, alkaneid, record)| (format!("{}/{}:{}", alkaneid, record.txid, record.vout), record.amount))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    // 交易在签名前会先构建成 PSBT，地址必须有效
+    fn set_addresses() -> (String, String) {
+        let (topup_address, fund_address, btc_address) = (test_address(1), test_address(2), test_address(3));
+        ADDRESSES.with(|a| {
+            let mut addresses = a.borrow_mut();
+            for (key, address) in [("alkanes_topup", &topup_address), ("alkanes_fund", &fund_address), ("btc", &btc_address)] {
+                addresses.insert(key.to_string(), address.to_string());
+            }
+        });
+        (topup_address, fund_address)
+    }
+
+    #[test]
+    fn test_failed_flows_commit_nothing() {
+        let (topup_address, fund_address) = set_addresses();
+        let record = |txid: &str, amount| AlkaneUtxoRecord { amount, txid: txid.repeat(32), vout: 0, satoshi: 330 };
+        put_utxo(&fund_address, "2:1", record("aa", 1_000)).unwrap();
+        put_utxo(&topup_address, "2:1", record("bb", 500)).unwrap();
+        let requests: HashMap<Principal, Vec<WithdrawRequest>> = HashMap::from([(
+            Principal::from_slice(&[1]),
+            vec![WithdrawRequest {
+                ic_txid: "ic-1".into(),
+                token_type: "alkanes".into(),
+                token_id: "2:1".into(),
+                token_amount: 400,
+                withdraw_address: test_address(4),
+                fee: None,
+            }],
+        )]);
+        WITHDRAW_REQUESTS.with(|r| *r.borrow_mut() = requests.clone());
+        let (fund, topup) = (ledger(&fund_address), ledger(&topup_address));
+
+        for step in [Step::FeeRate, Step::BtcUtxos, Step::PublicKey, Step::Sign, Step::Broadcast] {
+            let chain = MockChain(Some(step));
+            let withdraw = block_on(crate::run_tx_flow("withdraw", crate::send_withdraw_request(&chain, requests.clone())));
+            let gather = block_on(crate::run_tx_flow("gather", crate::gather_alkanes_utxo(&chain, &GatherPolicy::default(), false)))
+                .map(|report| report.txid.unwrap_or_default());
+            for result in [withdraw, gather] {
+                match (step, result) {
+                    (Step::Broadcast, Err(FomowellError::BroadcastFailed { txid, .. })) => assert_eq!(txid, "dd".repeat(32)),
+                    (_, Err(FomowellError::Internal(e))) => assert_eq!(e, format!("{:?} injected", step)),
+                    (_, other) => panic!("{:?}: unexpected {:?}", step, other),
+                }
+            }
+            assert_eq!(ledger(&fund_address), fund);
+            assert_eq!(ledger(&topup_address), topup);
+            assert_eq!(WITHDRAW_REQUESTS.with(|r| r.borrow().get(&Principal::from_slice(&[1])).map(Vec::len)), Some(1));
+            assert!(PENDING_TXS.with(|p| p.borrow().is_empty()));
+        }
+        let failed = EventFilter { kinds: Some(vec![EventKind::TxFlowFailed]), ..Default::default() };
+        assert_eq!(events::query(&failed).len(), 8);
+
+        // 锁在失败后已经释放；被占用时另一个流程直接返回 Busy
+        let guard = FlowGuard::acquire("gather").unwrap();
+        let busy = block_on(crate::run_tx_flow("withdraw", crate::send_withdraw_request(&MockChain(None), requests.clone())));
+        assert_eq!(busy, Err(FomowellError::Busy("gather".into())));
+        drop(guard);
+
+        let txid = block_on(crate::run_tx_flow("withdraw", crate::send_withdraw_request(&MockChain(None), requests))).unwrap();
+        assert!(WITHDRAW_REQUESTS.with(|r| r.borrow().is_empty()));
+        assert_eq!(ledger(&fund_address), vec![(format!("2:1/{}:0", txid), 600)]);
+        assert_eq!(PENDING_TXS.with(|p| p.borrow().len()), 1);
+    }
+
+    #[test]
+    fn test_batches_pay_every_output() {
+        let (topup_address, fund_address) = set_addresses();
+        let record = |txid: &str, vout, amount| AlkaneUtxoRecord { amount, txid: txid.repeat(32), vout, satoshi: 330 };
+        put_utxo(&fund_address, "2:1", record("a1", 0, 700)).unwrap();
+        put_utxo(&fund_address, "2:1", record("a2", 0, 300)).unwrap();
+        // 同一个 outpoint 上有 2:10 和 2:7
+        put_utxo(&fund_address, "2:10", record("a3", 0, 90)).unwrap();
+        put_utxo(&fund_address, "2:7", record("a3", 0, 40)).unwrap();
+        put_utxo(&fund_address, "2:7", record("a4", 1, 200)).unwrap();
+        put_utxo(&fund_address, "9:9", record("a5", 0, 1)).unwrap();
+
+        let mut requests: HashMap<Principal, Vec<WithdrawRequest>> = HashMap::new();
+        for p in 0..4u8 {
+            let queue = ["2:1", "2:7", "2:10"]
+                .iter()
+                .enumerate()
+                .map(|(k, token_id)| WithdrawRequest {
+                    ic_txid: format!("ic-{}-{}", p, k),
+                    token_type: "alkanes".into(),
+                    token_id: token_id.to_string(),
+                    token_amount: [100, 20, 10][k] + p as u128,
+                    withdraw_address: test_address(10 + 3 * p + k as u8),
+                    fee: None,
+                })
+                .collect();
+            requests.insert(Principal::from_slice(&[p + 1]), queue);
+        }
+        // 12 个请求的 protostone 超出 OP_RETURN 上限，先发按 principal 排序后放得下的前几个
+        let (plan, batch) = block_on(crate::plan_withdraw(&MockChain(None), &requests)).unwrap();
+        assert!(plan.protostone.len() <= crate::txplan::MAX_OP_RETURN_BYTES);
+        let sent: Vec<&WithdrawRequest> = batch.values().flatten().collect();
+        assert!(sent.len() > 3 && sent.len() < 12, "{} requests in the batch", sent.len());
+        assert_eq!(batch.get(&Principal::from_slice(&[1])).map(Vec::len), Some(3));
+        assert!(!sent.iter().any(|request| request.ic_txid == "ic-3-2"));
+
+        let simulation = crate::txplan::simulate(&plan, bitcoin::Network::Testnet, &fund_address).unwrap();
+        assert_eq!(simulation.problems, Vec::<String>::new());
+        let received = |output: usize| -> Vec<(String, u128)> {
+            simulation.output_alkanes.iter().find(|o| o.output as usize == output).map(|o| o.alkanes.clone()).unwrap_or_default()
+        };
+        for request in &sent {
+            let output = plan.outputs.iter().position(|o| o.address == request.withdraw_address).unwrap();
+            assert_eq!(received(output), vec![(request.token_id.clone(), request.token_amount)]);
+        }
+        // 除了付给请求的，花掉的输入上其余 alkanes 全部回到 fund 的 output 0
+        let mut held: HashMap<String, u128> = HashMap::new();
+        for (alkaneid, amount) in plan.inputs.iter().flat_map(|input| &input.alkanes) {
+            *held.entry(alkaneid.clone()).or_insert(0) += *amount;
+        }
+        for request in &sent {
+            *held.get_mut(&request.token_id).unwrap() -= request.token_amount;
+        }
+        held.retain(|_, amount| *amount > 0);
+        let mut change: Vec<(String, u128)> = held.into_iter().collect();
+        change.sort_by_key(|(alkaneid, _)| alkaneid.split_once(':').map(|(b, t)| (b.parse::<u64>().unwrap(), t.parse::<u32>().unwrap())));
+        assert_eq!(received(0), change);
+        // 找零不靠 pointer：每种 alkane 都有自己的找零 edict
+        let mut planned_change = plan.outputs[0].alkanes.clone();
+        planned_change.sort_by_key(|(alkaneid, _)| alkaneid.split_once(':').map(|(b, t)| (b.parse::<u64>().unwrap(), t.parse::<u32>().unwrap())));
+        assert_eq!(planned_change, change);
+        let outpoints: HashSet<(&str, u64)> = plan.inputs.iter().map(|input| (input.txid.as_str(), input.vout)).collect();
+        assert_eq!(outpoints.len(), plan.inputs.len());
+
+        put_utxo(&topup_address, "2:1", record("b1", 0, 5)).unwrap();
+        put_utxo(&topup_address, "2:10", record("b1", 0, 6)).unwrap();
+        put_utxo(&topup_address, "2:7", record("b2", 1, 7)).unwrap();
+        let report = block_on(crate::gather_alkanes_utxo(&MockChain(None), &GatherPolicy::default(), true)).unwrap();
+        let simulation = report.simulation.unwrap();
+        assert_eq!(simulation.problems, Vec::<String>::new());
+        for (index, output) in simulation.plan.outputs.iter().enumerate().filter(|(_, o)| !o.alkanes.is_empty()) {
+            let got = simulation.output_alkanes.iter().find(|o| o.output as usize == index).unwrap();
+            assert_eq!(got.alkanes, output.alkanes);
+        }
+    }
+
+    #[test]
+    fn test_confirmed_gather_stays_held_before_sync() {
+        let (topup_address, fund_address) = set_addresses();
+        let record = |txid: &str, vout, amount| AlkaneUtxoRecord { amount, txid: txid.repeat(32), vout, satoshi: 330 };
+        put_utxo(&topup_address, "2:1", record("b1", 0, 500)).unwrap();
+        put_utxo(&topup_address, "2:7", record("b1", 0, 40)).unwrap();
+        put_utxo(&topup_address, "2:1", record("b2", 1, 25)).unwrap();
+        let held = || -> Vec<(String, u128)> {
+            let mut held: Vec<(String, u128)> = crate::collect_reserves().into_iter().map(|r| (r.alkaneid, r.held)).collect();
+            held.sort();
+            held
+        };
+        let before = held();
+
+        let report = block_on(crate::run_tx_flow("gather", crate::gather_alkanes_utxo(&MockChain(None), &GatherPolicy::default(), false))).unwrap();
+        let txid = report.txid.unwrap();
+        assert!(ledger(&topup_address).is_empty());
+        assert_eq!(ledger(&fund_address), vec![(format!("2:1/{}:0", txid), 525), (format!("2:7/{}:1", txid), 40)]);
+        assert_eq!(held(), before);
+
+        // refresh_pending_txs 看到确认后移除 pending；下一次同步之前储备不变
+        PENDING_TXS.with(|p| p.borrow_mut().clear());
+        assert_eq!(held(), before);
+    }
+
+    #[test]
+    fn test_withdraw_batch_skips_requests_that_do_not_fit() {
+        let (_, fund_address) = set_addresses();
+        let record = |txid: &str, amount| AlkaneUtxoRecord { amount, txid: txid.repeat(32), vout: 0, satoshi: 330 };
+        let request = |ic_txid: &str, token_id: &str, amount| WithdrawRequest {
+            ic_txid: ic_txid.into(),
+            token_type: "alkanes".into(),
+            token_id: token_id.into(),
+            token_amount: amount,
+            withdraw_address: test_address(20),
+            fee: None,
+        };
+        let sent = |batch: &HashMap<Principal, Vec<WithdrawRequest>>| -> Vec<String> {
+            let mut sent: Vec<String> = batch.values().flatten().map(|r| r.ic_txid.clone()).collect();
+            sent.sort();
+            sent
+        };
+        put_utxo(&fund_address, "2:1", record("a1", 1_000)).unwrap();
+
+        // 排在最前面的请求 fund 还付不起、地址无效，或者和前面的请求一起超出余额：跳过它们，其余照发
+        let requests = HashMap::from([
+            (Principal::from_slice(&[1]), vec![request("ic-1", "2:1", 5_000), request("ic-2", "2:1", 400)]),
+            (Principal::from_slice(&[2]), vec![WithdrawRequest { withdraw_address: "not-an-address".into(), ..request("ic-3", "2:1", 1) }]),
+            (Principal::from_slice(&[3]), vec![request("ic-4", "2:1", 700), request("ic-5", "2:1", 600)]),
+        ]);
+        let (_, batch) = block_on(crate::plan_withdraw(&MockChain(None), &requests)).unwrap();
+        assert_eq!(sent(&batch), vec!["ic-2", "ic-5"]);
+
+        // 单独一个请求的 protostone 就超出 OP_RETURN 时也只跳过它：同一个 outpoint 上的其余 alkanes 都要找零 edict
+        let huge = |shift: u32| format!("{}:{}", u64::MAX >> shift, u32::MAX);
+        for shift in [0, 8, 16, 24] {
+            put_utxo(&fund_address, &huge(shift), record("a2", u128::MAX)).unwrap();
+        }
+        let requests = HashMap::from([
+            (Principal::from_slice(&[1]), vec![request("ic-6", &huge(0), u128::MAX - 1)]),
+            (Principal::from_slice(&[2]), vec![request("ic-7", "2:1", 1)]),
+        ]);
+        let (plan, batch) = block_on(crate::plan_withdraw(&MockChain(None), &requests)).unwrap();
+        assert_eq!(sent(&batch), vec!["ic-7"]);
+        assert!(plan.protostone.len() <= crate::txplan::MAX_OP_RETURN_BYTES);
+
+        // 没有一个请求能发时返回第一个请求的错误
+        let requests = HashMap::from([
+            (Principal::from_slice(&[1]), vec![request("ic-8", "2:1", 5_000)]),
+            (Principal::from_slice(&[2]), vec![request("ic-9", &huge(0), u128::MAX - 1)]),
+        ]);
+        assert!(matches!(
+            block_on(crate::plan_withdraw(&MockChain(None), &requests)),
+            Err(FomowellError::InsufficientAlkanes { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unbuildable_head_request_stays_queued() {
+        let (_, fund_address) = set_addresses();
+        let record = |txid: &str, amount| AlkaneUtxoRecord { amount, txid: txid.repeat(32), vout: 0, satoshi: 330 };
+        let request = |ic_txid: &str, amount| WithdrawRequest {
+            ic_txid: ic_txid.into(),
+            token_type: "alkanes".into(),
+            token_id: "2:1".into(),
+            token_amount: amount,
+            withdraw_address: test_address(21),
+            fee: None,
+        };
+        put_utxo(&fund_address, "2:1", record("a1", 1_000)).unwrap();
+        let requests = HashMap::from([
+            (Principal::from_slice(&[1]), vec![request("ic-1", 5_000)]),
+            (Principal::from_slice(&[2]), vec![request("ic-2", 300)]),
+        ]);
+        WITHDRAW_REQUESTS.with(|r| *r.borrow_mut() = requests.clone());
+
+        let txid = block_on(crate::run_tx_flow("withdraw", crate::send_withdraw_request(&MockChain(None), requests))).unwrap();
+        let queued: Vec<(Principal, Vec<String>)> = WITHDRAW_REQUESTS.with(|r| {
+            r.borrow().iter().map(|(pid, queue)| (*pid, queue.iter().map(|q| q.ic_txid.clone()).collect())).collect()
+        });
+        assert_eq!(queued, vec![(Principal::from_slice(&[1]), vec!["ic-1".to_string()])]);
+        assert_eq!(ledger(&fund_address), vec![(format!("2:1/{}:0", txid), 700)]);
+    }
+
+    #[test]
+    fn test_fees_booked_at_broadcast() {
+        let (_, fund_address) = set_addresses();
+        put_utxo(&fund_address, "2:1", AlkaneUtxoRecord { amount: 1_000, txid: "aa".repeat(32), vout: 0, satoshi: 330 }).unwrap();
+        set_token_config("2:1", TokenConfig { withdraw_fee: Some(10), ..Default::default() });
+        let (pid, admin) = (Principal::from_slice(&[1]), Principal::from_slice(&[9]));
+        let reserve = || crate::collect_reserves().into_iter().find(|r| r.alkaneid == "2:1").unwrap();
+        let send = || {
+            let requests = WITHDRAW_REQUESTS.with(|r| r.borrow().clone());
+            block_on(crate::run_tx_flow("withdraw", crate::send_withdraw_request(&MockChain(None), requests))).unwrap()
+        };
+
+        // 排队时手续费只算在待提现里，广播后才计入已提现和 accrued
+        let request = WithdrawRequest {
+            ic_txid: "ic-1".into(),
+            token_type: "alkanes".into(),
+            token_id: "2:1".into(),
+            token_amount: 100,
+            withdraw_address: test_address(30),
+            fee: Some(1_000),
+        };
+        let receipt = crate::enqueue_withdraw(pid, request).unwrap();
+        assert_eq!((receipt.amount, receipt.fee), (90, 10));
+        assert_eq!((reserve().withdraw_requested, reserve().withdrawn, fees::accrued("2:1")), (100, 0, 0));
+        send();
+        assert_eq!((reserve().withdraw_requested, reserve().withdrawn, reserve().fees), (0, 100, 10));
+
+        // 排队的 sweep 不清掉 accrued，也不能把同一笔手续费再排一次
+        let first = crate::queue_fee_sweep(admin, "2:1", test_address(31)).unwrap();
+        assert_eq!(first.amount, 10);
+        assert!(matches!(crate::queue_fee_sweep(admin, "2:1", test_address(31)), Err(FomowellError::NotFound(_))));
+        fees::accrue("2:1", 5);
+        let second = crate::queue_fee_sweep(admin, "2:1", test_address(31)).unwrap();
+        assert_eq!(second.amount, 5);
+        assert_ne!(first.ic_txid, second.ic_txid);
+        assert_eq!(fees::accrued("2:1"), 15);
+        assert_eq!(reserve().fees, 15);
+
+        // 第一笔确认后 MockChain 的 btc utxo 才能再用
+        PENDING_TXS.with(|p| p.borrow_mut().clear());
+        send();
+        assert!(WITHDRAW_REQUESTS.with(|r| r.borrow().is_empty()));
+        assert_eq!((reserve().fees, reserve().withdrawn, reserve().held), (0, 100, 895));
+    }
+
+    #[test]
+    fn test_dropped_withdraw_is_rolled_back() {
+        let (_, fund_address) = set_addresses();
+        put_utxo(&fund_address, "2:1", AlkaneUtxoRecord { amount: 1_000, txid: "aa".repeat(32), vout: 0, satoshi: 330 }).unwrap();
+        set_token_config("2:1", TokenConfig { withdraw_fee: Some(10), ..Default::default() });
+        let (pid, admin) = (Principal::from_slice(&[1]), Principal::from_slice(&[9]));
+        let request = WithdrawRequest {
+            ic_txid: "ic-1".into(),
+            token_type: "alkanes".into(),
+            token_id: "2:1".into(),
+            token_amount: 100,
+            withdraw_address: test_address(30),
+            fee: Some(1_000),
+        };
+        crate::enqueue_withdraw(pid, request).unwrap();
+        fees::accrue("2:1", 5);
+        crate::queue_fee_sweep(admin, "2:1", test_address(31)).unwrap();
+        let queue = || -> Vec<(Principal, Vec<String>)> {
+            let mut queue: Vec<(Principal, Vec<String>)> = WITHDRAW_REQUESTS.with(|r| {
+                r.borrow().iter().map(|(pid, q)| (*pid, q.iter().map(|r| r.ic_txid.clone()).collect())).collect()
+            });
+            queue.sort();
+            queue
+        };
+        let reserve = || crate::collect_reserves().into_iter().find(|r| r.alkaneid == "2:1").unwrap();
+        let send = || {
+            let requests = WITHDRAW_REQUESTS.with(|r| r.borrow().clone());
+            block_on(crate::run_tx_flow("withdraw", crate::send_withdraw_request(&MockChain(None), requests))).unwrap()
+        };
+        let before = (queue(), ledger(&fund_address), reserve(), fees::accrued("2:1"));
+
+        // 提现和 sweep 在同一笔交易里发出
+        let txid = send();
+        assert!(queue().is_empty());
+        assert_eq!(ledger(&fund_address), vec![(format!("2:1/{}:0", txid), 905)]);
+        assert_eq!((reserve().withdrawn, fees::accrued("2:1")), (100, 10));
+
+        // 默认不开 http 交叉校验，超时后 mempool.space 不认识这笔交易，整笔回滚
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert_eq!(PENDING_TXS.with(|p| p.borrow().len()), 1);
+        crate::set_test_time(crate::DROPPED_AFTER_SECS * 1_000_000_000);
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert!(PENDING_TXS.with(|p| p.borrow().is_empty()));
+        assert_eq!((queue(), ledger(&fund_address), reserve(), fees::accrued("2:1")), before);
+
+        // 请求重新排队，下一次照常发出
+        send();
+        assert!(queue().is_empty());
+    }
+
+    #[test]
+    fn test_withdraw_skips_deep_and_spent_outpoints() {
+        let (_, fund_address) = set_addresses();
+        let (a, b, c) = ("a1".repeat(32), "b1".repeat(32), "c1".repeat(32));
+        let pending = |txid: &str, spent: Vec<(String, u64)>| crate::PendingTx {
+            txid: txid.to_string(),
+            kind: "withdraw".into(),
+            spent_outpoints: spent,
+            broadcast_at: 0,
+            watch_output: None,
+            in_flight: None,
+            requests: None,
+            spent_entries: None,
+        };
+        // a <- b <- c 是一条未确认链，fund 上 c 的找零深度 3，b 的输出深度 2
+        PENDING_TXS.with(|p| {
+            *p.borrow_mut() = vec![
+                pending(&a, vec![("f0".repeat(32), 0)]),
+                pending(&b, vec![(a.clone(), 0)]),
+                pending(&c, vec![(b.clone(), 0)]),
+            ]
+        });
+        put_utxo(&fund_address, "2:1", AlkaneUtxoRecord { amount: 100, txid: c.clone(), vout: 0, satoshi: 330 }).unwrap();
+        put_utxo(&fund_address, "2:1", AlkaneUtxoRecord { amount: 50, txid: b.clone(), vout: 1, satoshi: 330 }).unwrap();
+        let requests = HashMap::from([(
+            Principal::from_slice(&[1]),
+            vec![WithdrawRequest {
+                ic_txid: "ic-1".into(),
+                token_type: "alkanes".into(),
+                token_id: "2:1".into(),
+                token_amount: 120,
+                withdraw_address: test_address(40),
+                fee: None,
+            }],
+        )]);
+
+        crate::MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow_mut() = 3);
+        match block_on(crate::plan_withdraw(&MockChain(None), &requests)) {
+            Err(FomowellError::InsufficientAlkanes { available, .. }) => assert_eq!(available, 50),
+            other => panic!("unexpected {:?}", other.map(|(_, batch)| batch)),
+        }
+        crate::MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow_mut() = 4);
+        let (plan, _) = block_on(crate::plan_withdraw(&MockChain(None), &requests)).unwrap();
+        assert!(plan.inputs.iter().any(|input| input.txid == c));
+
+        // MockChain 唯一的 btc utxo 已被未确认交易花掉，不会再被选中
+        PENDING_TXS.with(|p| p.borrow_mut().push(pending(&"d1".repeat(32), vec![("cc".repeat(32), 1)])));
+        assert!(matches!(
+            block_on(crate::plan_withdraw(&MockChain(None), &requests)),
+            Err(FomowellError::TxBuildFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_refresh_confirms_children_after_parents_and_releases_dropped_txs() {
+        let (_, fund_address) = set_addresses();
+        let (a, b, c, d) = ("a1".repeat(32), "b1".repeat(32), "c1".repeat(32), "d1".repeat(32));
+        let pending = |txid: &str, parent: &str| crate::PendingTx {
+            txid: txid.to_string(),
+            kind: "withdraw".into(),
+            spent_outpoints: vec![(parent.to_string(), 0)],
+            broadcast_at: 0,
+            watch_output: Some((fund_address.clone(), 0)),
+            in_flight: None,
+            requests: None,
+            spent_entries: None,
+        };
+        let still_pending = || -> Vec<String> { PENDING_TXS.with(|p| p.borrow().iter().map(|tx| tx.txid.clone()).collect()) };
+        // a <- b 是一条未确认链，c <- d 是另一条
+        PENDING_TXS.with(|p| *p.borrow_mut() = vec![pending(&a, "f0"), pending(&b, &a), pending(&c, "f1"), pending(&d, &c)]);
+
+        // 子交易还没确认时父交易单独确认
+        MINED.with(|m| *m.borrow_mut() = vec![(a.clone(), 0)]);
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert_eq!(still_pending(), vec![b.clone(), c.clone(), d.clone()]);
+
+        // b 的输出确认；b 花掉的 a:0 虽然不再是 utxo，a 已经不在 pending 里
+        MINED.with(|m| *m.borrow_mut() = vec![(b.clone(), 0)]);
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert_eq!(still_pending(), vec![c.clone(), d.clone()]);
+
+        // 打开 http 交叉校验：bitcoin canister 报告 d 已确认，mempool.space 说 c 还没确认，子交易 d 也不算确认
+        crate::HTTP_CROSS_CHECK.with(|x| *x.borrow_mut() = true);
+        MINED.with(|m| *m.borrow_mut() = vec![(d.clone(), 0)]);
+        MEMPOOL.with(|m| *m.borrow_mut() = HashMap::from([(c.clone(), TxStatus::Unconfirmed), (d.clone(), TxStatus::Confirmed)]));
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert_eq!(still_pending(), vec![c.clone(), d.clone()]);
+        MEMPOOL.with(|m| m.borrow_mut().insert(c.clone(), TxStatus::Confirmed));
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert!(still_pending().is_empty());
+
+        // c <- d 重新广播后 c 被 mempool 丢弃
+        PENDING_TXS.with(|p| *p.borrow_mut() = vec![pending(&c, "f1"), pending(&d, &c)]);
+
+        // c 被 mempool 丢弃：刚广播时不算，过了 DROPPED_AFTER_SECS 才释放，花费 c 的 d 一起释放
+        MINED.with(|m| m.borrow_mut().clear());
+        MEMPOOL.with(|m| *m.borrow_mut() = HashMap::from([(d.clone(), TxStatus::Unconfirmed)]));
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert_eq!(still_pending(), vec![c.clone(), d.clone()]);
+        crate::set_test_time(crate::DROPPED_AFTER_SECS * 1_000_000_000);
+        block_on(crate::refresh_pending_txs(&MockChain(None)));
+        assert!(still_pending().is_empty());
+        assert!(!crate::is_spent_by_pending("f1", 0, &PENDING_TXS.with(|p| p.borrow().clone())));
+        let dropped = EventFilter { kinds: Some(vec![EventKind::TxDropped]), ..Default::default() };
+        assert_eq!(events::query(&dropped).len(), 2);
+    }
+}
@@ -0,0 +1,255 @@
+use candid::{CandidType, Deserialize};
+
+// 10^38 是 u128 能放下的最大 10 的幂
+const MAX_DECIMAL_SHIFT: u32 = 38;
+const BPS: u128 = 10_000;
+
+/// `alkane` whole alkanes are worth `meme` whole meme tokens.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ratio {
+    pub meme: u128,
+    pub alkane: u128,
+}
+
+/// Where topup fees go.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FeeDestination {
+    /// Kept as alkanes in the fund until swept.
+    Fund,
+    /// Credited as meme tokens to the treasury account.
+    Treasury,
+}
+
+/// How amounts of one alkane map onto its meme token. Stored next to the
+/// alkane → meme token id mapping.
+///
+/// Amounts here are alkane base units. A token without a config converts
+/// 1:1, which is how every token was credited before.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TokenConfig {
+    /// Decimal places of the alkane, as reported by the indexer.
+    pub divisibility: u8,
+    /// Decimal places of the meme token.
+    pub decimals: u8,
+    /// Applied on top of the decimal shift; `None` is 1:1.
+    pub ratio: Option<Ratio>,
+    pub min_topup: Option<u128>,
+    pub max_topup: Option<u128>,
+    /// Flat part of the fee kept back from every topup before conversion.
+    pub topup_fee: u128,
+    /// Proportional part of the topup fee, added to `topup_fee`.
+    pub topup_fee_bps: Option<u16>,
+    /// Flat part of the fee kept back from every withdraw.
+    pub withdraw_fee: Option<u128>,
+    pub withdraw_fee_bps: Option<u16>,
+    /// Where topup fees go; `None` is `Fund`. Withdraw fees always stay in
+    /// the fund, the meme tokens they were paid with are already gone.
+    pub fee_destination: Option<FeeDestination>,
+}
+
+/// Amounts of one topup, in alkane base units except `meme_amount`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopupAmounts {
+    pub fee: u128,
+    pub credited: u128,
+    pub meme_amount: u128,
+}
+
+pub fn validate(config: &TokenConfig) -> Result<(), String> {
+    if config.divisibility.abs_diff(config.decimals) as u32 > MAX_DECIMAL_SHIFT {
+        return Err(format!(
+            "divisibility {} and decimals {} differ by more than {}",
+            config.divisibility, config.decimals, MAX_DECIMAL_SHIFT
+        ));
+    }
+    if let Some(ratio) = &config.ratio
+        && (ratio.meme == 0 || ratio.alkane == 0)
+    {
+        return Err("ratio terms must be at least 1".into());
+    }
+    if let (Some(min), Some(max)) = (config.min_topup, config.max_topup)
+        && min > max
+    {
+        return Err(format!("min_topup {} exceeds max_topup {}", min, max));
+    }
+    for bps in [config.topup_fee_bps, config.withdraw_fee_bps].into_iter().flatten() {
+        if bps as u128 > BPS {
+            return Err(format!("fee of {} bps exceeds 100%", bps));
+        }
+    }
+    if config.min_topup.is_some_and(|min| min <= config.topup_fee) {
+        return Err(format!("min_topup must exceed topup_fee {}", config.topup_fee));
+    }
+    Ok(())
+}
+
+/// Stand-in for a config `validate` rejects: the decimal shift clamped to
+/// what u128 can hold and `max_topup` at 0, so the token takes no topups
+/// until a `SetTokenConfig` proposal stores a config that was checked.
+pub fn held_back(config: &TokenConfig) -> TokenConfig {
+    let shift = MAX_DECIMAL_SHIFT as u8;
+    let decimals = config.decimals.clamp(config.divisibility.saturating_sub(shift), config.divisibility.saturating_add(shift));
+    TokenConfig { divisibility: config.divisibility, decimals, max_topup: Some(0), ..Default::default() }
+}
+
+/// Alkane divisibility for a new token config. The operator's value must
+/// agree with the indexer's when both are known; one of them is required.
+pub fn resolve_divisibility(operator: Option<u8>, indexer: Option<u8>) -> Result<u8, String> {
+    match (operator, indexer) {
+        (Some(given), Some(reported)) if given != reported => {
+            Err(format!("divisibility {} does not match {} reported by the indexer", given, reported))
+        }
+        (Some(divisibility), _) | (None, Some(divisibility)) => Ok(divisibility),
+        (None, None) => Err("the indexer does not report divisibility, pass it explicitly".into()),
+    }
+}
+
+/// `flat` plus `bps` of `amount`, rounded down.
+fn fee(flat: u128, bps: Option<u16>, amount: u128) -> Result<u128, String> {
+    let bps = bps.unwrap_or(0) as u128;
+    // bps 不超过 10000，拆开算不会溢出
+    let proportional = amount / BPS * bps + amount % BPS * bps / BPS;
+    flat.checked_add(proportional).ok_or_else(|| format!("fee on {} overflows u128", amount))
+}
+
+impl TokenConfig {
+    /// Meme base units per alkane base unit as `numerator / denominator`,
+    /// with the decimal shift folded in.
+    fn rate(&self) -> (u128, u128) {
+        let (meme, alkane) = self.ratio.as_ref().map(|r| (r.meme, r.alkane)).unwrap_or((1, 1));
+        let shift = 10u128.pow(self.divisibility.abs_diff(self.decimals) as u32);
+        if self.decimals >= self.divisibility {
+            (meme.saturating_mul(shift), alkane)
+        } else {
+            (meme, alkane.saturating_mul(shift))
+        }
+    }
+
+    /// Meme base units for `amount` alkane base units, rounded down.
+    pub fn to_meme(&self, amount: u128) -> Result<u128, String> {
+        let (numerator, denominator) = self.rate();
+        // 先除后乘，中间值不超过结果
+        let whole = (amount / denominator).checked_mul(numerator);
+        let part = (amount % denominator).checked_mul(numerator).map(|p| p / denominator);
+        whole
+            .zip(part)
+            .and_then(|(whole, part)| whole.checked_add(part))
+            .ok_or_else(|| format!("{} alkane units overflow u128 once converted", amount))
+    }
+
+    /// Alkane base units worth exactly `meme_amount`. An amount that falls
+    /// between two alkane units is rejected rather than rounded.
+    pub fn to_alkane(&self, meme_amount: u128) -> Result<u128, String> {
+        let (numerator, denominator) = self.rate();
+        let whole = (meme_amount / numerator).checked_mul(denominator);
+        let part = (meme_amount % numerator).checked_mul(denominator).map(|p| p / numerator);
+        let amount = whole
+            .zip(part)
+            .and_then(|(whole, part)| whole.checked_add(part))
+            .ok_or_else(|| format!("{} meme units overflow u128 once converted", meme_amount))?;
+        if self.to_meme(amount)? != meme_amount {
+            return Err(format!("{} meme units is not a whole number of alkane units", meme_amount));
+        }
+        Ok(amount)
+    }
+
+    /// Checks the topup limits and splits `amount` into fee and credit.
+    pub fn topup(&self, amount: u128) -> Result<TopupAmounts, String> {
+        if let Some(min) = self.min_topup
+            && amount < min
+        {
+            return Err(format!("topup of {} below min_topup {}", amount, min));
+        }
+        if let Some(max) = self.max_topup
+            && amount > max
+        {
+            return Err(format!("topup of {} above max_topup {}", amount, max));
+        }
+        let fee = fee(self.topup_fee, self.topup_fee_bps, amount)?;
+        let credited = amount
+            .checked_sub(fee)
+            .filter(|credited| *credited > 0)
+            .ok_or_else(|| format!("topup of {} does not cover the fee of {}", amount, fee))?;
+        let meme_amount = self.to_meme(credited)?;
+        if meme_amount == 0 {
+            return Err(format!("topup of {} converts to 0 meme units", amount));
+        }
+        Ok(TopupAmounts { fee, credited, meme_amount })
+    }
+
+    /// Splits a withdraw of `amount` alkane base units into what is sent and
+    /// the fee kept in the fund.
+    pub fn withdraw(&self, amount: u128) -> Result<(u128, u128), String> {
+        let fee = fee(self.withdraw_fee.unwrap_or(0), self.withdraw_fee_bps, amount)?;
+        let sent = amount
+            .checked_sub(fee)
+            .filter(|sent| *sent > 0)
+            .ok_or_else(|| format!("withdraw of {} does not cover the fee of {}", amount, fee))?;
+        Ok((sent, fee))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversion() {
+        // 没有配置时 1:1
+        let plain = TokenConfig::default();
+        assert_eq!(plain.to_meme(u64::MAX as u128 + 1), Ok(u64::MAX as u128 + 1));
+        assert_eq!(plain.to_alkane(7), Ok(7));
+
+        // alkane 8 位小数，meme 18 位
+        let scaled = TokenConfig { divisibility: 8, decimals: 18, ..Default::default() };
+        assert_eq!(scaled.to_meme(3), Ok(30_000_000_000));
+        assert_eq!(scaled.to_alkane(30_000_000_000), Ok(3));
+        assert!(scaled.to_alkane(30_000_000_001).is_err());
+        assert!(scaled.to_meme(u128::MAX).is_err());
+
+        // 反方向向下取整
+        let coarse = TokenConfig { divisibility: 8, decimals: 6, ..Default::default() };
+        assert_eq!(coarse.to_meme(12_345), Ok(123));
+        assert_eq!(coarse.to_alkane(123), Ok(12_300));
+
+        let ratio = TokenConfig { ratio: Some(Ratio { meme: 1_000, alkane: 3 }), ..Default::default() };
+        assert_eq!(ratio.to_meme(7), Ok(2_333));
+        assert_eq!(ratio.to_alkane(1_000), Ok(3));
+        assert!(ratio.to_alkane(2_333).is_err());
+
+        let limited = TokenConfig { min_topup: Some(100), max_topup: Some(1_000), topup_fee: 10, ..Default::default() };
+        assert_eq!(limited.topup(100), Ok(TopupAmounts { fee: 10, credited: 90, meme_amount: 90 }));
+        assert!(limited.topup(99).is_err());
+        assert!(limited.topup(1_001).is_err());
+        assert!(validate(&limited).is_ok());
+        assert!(validate(&TokenConfig { min_topup: Some(10), ..limited.clone() }).is_err());
+        assert!(validate(&TokenConfig { max_topup: Some(50), ..limited }).is_err());
+        assert!(validate(&TokenConfig { ratio: Some(Ratio { meme: 0, alkane: 1 }), ..Default::default() }).is_err());
+        assert!(validate(&TokenConfig { decimals: 40, ..Default::default() }).is_err());
+        // 创建 meme token 之前只知道 divisibility
+        assert!(validate(&TokenConfig { divisibility: 40, ..Default::default() }).is_err());
+
+        // 固定费用加比例费用，比例部分向下取整
+        let fees = TokenConfig { topup_fee: 5, topup_fee_bps: Some(250), withdraw_fee_bps: Some(10_000), ..Default::default() };
+        assert_eq!(fees.topup(1_000).map(|t| (t.fee, t.credited)), Ok((30, 970)));
+        assert_eq!(fees.topup(u128::MAX).map(|t| t.fee), Ok(u128::MAX / 10_000 * 250 + u128::MAX % 10_000 * 250 / 10_000 + 5));
+        assert!(fees.withdraw(1_000).is_err());
+        let fees = TokenConfig { withdraw_fee: Some(3), withdraw_fee_bps: Some(100), ..Default::default() };
+        assert_eq!(fees.withdraw(1_000), Ok((987, 13)));
+        assert!(validate(&TokenConfig { withdraw_fee_bps: Some(10_001), ..Default::default() }).is_err());
+
+        assert_eq!(resolve_divisibility(None, Some(8)), Ok(8));
+        assert_eq!(resolve_divisibility(Some(6), None), Ok(6));
+        assert_eq!(resolve_divisibility(Some(8), Some(8)), Ok(8));
+        assert!(resolve_divisibility(Some(6), Some(8)).is_err());
+        assert!(resolve_divisibility(None, None).is_err());
+
+        // meme token 创建后才发现精度差太大：收紧后不接受任何充值
+        let rejected = TokenConfig { divisibility: 0, decimals: 40, ..Default::default() };
+        assert!(validate(&rejected).is_err());
+        let held = held_back(&rejected);
+        assert_eq!((held.divisibility, held.decimals), (0, 38));
+        assert!(validate(&held).is_ok());
+        assert!(held.topup(1).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::fmt;
+
+use crate::indexer::IndexerError;
+use crate::pause::PauseSwitch;
+use crate::proposals::ProposalStatus;
+
+/// Error returned by every public method. Internal helpers still use
+/// `String` errors; those surface as `Internal`.
+///
+/// Guards run before the method and can only reject with a message, so a
+/// caller without the right role gets a reject, not `Unauthorized`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum FomowellError {
+    NotFound(String),
+    Unauthorized(String),
+    InvalidArgument(String),
+    AlreadyExists(String),
+    /// The topup for this txid was credited before.
+    AlreadyProcessed { txid: String },
+    /// The deposit was sent from a different BTC address than the caller registered.
+    AddressMismatch { expected: String, actual: String },
+    InsufficientAlkanes { alkaneid: String, requested: u128, available: u128 },
+    FeeOracleUnavailable(String),
+    /// The Fomowell token canister failed or rejected a transfer / token creation.
+    LedgerCallFailed { code: String, message: String },
+    CanisterCallFailed { method: String, code: String, message: String },
+    Indexer(IndexerError),
+    /// Building or signing a withdraw / gather transaction failed before broadcast.
+    TxBuildFailed(String),
+    BroadcastFailed { txid: String, message: String },
+    /// Another withdraw / gather run holds the transaction lock.
+    Busy(String),
+    Paused { switch: PauseSwitch, reason: String },
+    RateLimited(String),
+    BudgetExceeded(String),
+    QueueFull,
+    Internal(String),
+}
+
+impl fmt::Display for FomowellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FomowellError::NotFound(what) => write!(f, "Not found: {}", what),
+            FomowellError::Unauthorized(e) => write!(f, "Unauthorized: {}", e),
+            FomowellError::InvalidArgument(e) => write!(f, "Invalid argument: {}", e),
+            FomowellError::AlreadyExists(what) => write!(f, "Already exists: {}", what),
+            FomowellError::AlreadyProcessed { txid } => write!(f, "Transaction {} already processed", txid),
+            FomowellError::AddressMismatch { expected, actual } => {
+                write!(f, "BTC address mismatch: expected {}, got {}", expected, actual)
+            }
+            FomowellError::InsufficientAlkanes { alkaneid, requested, available } => write!(
+                f,
+                "Insufficient {}: requested {}, available {}",
+                alkaneid, requested, available
+            ),
+            FomowellError::FeeOracleUnavailable(e) => write!(f, "Fee rate unavailable: {}", e),
+            FomowellError::LedgerCallFailed { code, message } => {
+                write!(f, "Ledger call failed: code={}, message={}", code, message)
+            }
+            FomowellError::CanisterCallFailed { method, code, message } => {
+                write!(f, "Call to {} failed: code={}, message={}", method, code, message)
+            }
+            FomowellError::Indexer(e) => write!(f, "Indexer error: {}", e),
+            FomowellError::TxBuildFailed(e) => write!(f, "Failed to build transaction: {}", e),
+            FomowellError::BroadcastFailed { txid, message } => write!(f, "Broadcast of {} failed: {}", txid, message),
+            FomowellError::Busy(flow) => write!(f, "{} is already running", flow),
+            FomowellError::Paused { switch, reason } => write!(f, "{:?} is paused: {}", switch, reason),
+            FomowellError::RateLimited(e) => write!(f, "{}", e),
+            FomowellError::BudgetExceeded(e) => write!(f, "{}", e),
+            FomowellError::QueueFull => write!(f, "Withdraw request queue is full"),
+            FomowellError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for FomowellError {
+    fn from(e: String) -> Self {
+        FomowellError::Internal(e)
+    }
+}
+
+/// Lets typed errors flow through helpers that still return `String`.
+impl From<FomowellError> for String {
+    fn from(e: FomowellError) -> Self {
+        e.to_string()
+    }
+}
+
+impl From<IndexerError> for FomowellError {
+    fn from(e: IndexerError) -> Self {
+        FomowellError::Indexer(e)
+    }
+}
+
+/// `(code, message)` of a rejected inter-canister call.
+pub fn call_failed(method: &str, (code, message): (ic_cdk::api::call::RejectionCode, String)) -> FomowellError {
+    FomowellError::CanisterCallFailed { method: method.to_string(), code: format!("{:?}", code), message }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TopupReceipt {
+    pub txid: String,
+    /// Deposited alkane base units, `fee` included.
+    pub amount: u128,
+    /// Alkane base units kept back as the topup fee.
+    pub fee: u128,
+    /// Meme token base units credited, after conversion.
+    pub meme_amount: u128,
+    pub alkaneid: String,
+    pub meme_token_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawReceipt {
+    pub ic_txid: String,
+    pub alkaneid: String,
+    /// Alkane base units sent, converted from the requested meme amount,
+    /// `fee` taken off.
+    pub amount: u128,
+    /// Alkane base units kept in the fund as the withdraw fee.
+    pub fee: u128,
+    pub withdraw_address: String,
+    /// Requests queued ahead of this one, itself included.
+    pub queue_position: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenReceipt {
+    pub alkaneid: String,
+    pub meme_token_id: u64,
+}
+
+/// 提案提交或投票后的状态。`status` 为 `Executed` 时动作已经生效
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProposalReceipt {
+    pub id: u64,
+    pub status: ProposalStatus,
+    pub approvals: u32,
+    pub required: u32,
+}
+
+/// The new owner, returned by `accept_owner_ic`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OwnerReceipt {
+    pub owner: Principal,
+    pub previous: Principal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_convert_at_the_boundary() {
+        let e: FomowellError = String::from("boom").into();
+        assert_eq!(e, FomowellError::Internal("boom".into()));
+
+        fn typed() -> Result<(), FomowellError> {
+            Err(FomowellError::AlreadyProcessed { txid: "aa".into() })
+        }
+        fn untyped() -> Result<(), String> {
+            typed()?;
+            Ok(())
+        }
+        assert_eq!(untyped().unwrap_err(), "Transaction aa already processed");
+    }
+}
@@ -0,0 +1,297 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::time;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const DEFAULT_MAX_EVENTS: u64 = 10_000;
+// 事件在 pre_upgrade 时整体编码进快照，条数太多会超出升级的指令上限
+const MAX_EVENTS_LIMIT: u64 = 50_000;
+// 单次查询最多返回的条数
+const MAX_PAGE: u64 = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    TopupCredited,
+    TopupRejected,
+    WithdrawQueued,
+    TxBroadcast,
+    TxBroadcastFailed,
+    /// A withdraw or gather run stopped before broadcast; nothing was committed.
+    TxFlowFailed,
+    TxConfirmed,
+    /// An unconfirmed tx the mempool no longer knows; its inputs were released.
+    TxDropped,
+    TokenWhitelisted,
+    TokenRemoved,
+    LedgerSynced,
+    Paused,
+    Resumed,
+    SolvencyMismatch,
+    LowCycleBalance,
+    AdminAction,
+}
+
+/// One audit event. The optional fields are the ones `get_events` filters on;
+/// whatever does not fit them goes into `detail`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: u64,
+    pub timestamp_nanos: u64,
+    pub kind: EventKind,
+    pub principal: Option<Principal>,
+    pub txid: Option<String>,
+    pub alkaneid: Option<String>,
+    pub amount: Option<u128>,
+    pub detail: String,
+}
+
+/// `Event` as stored before amounts were widened to u128, only read at upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EventV1 {
+    pub id: u64,
+    pub timestamp_nanos: u64,
+    pub kind: EventKind,
+    pub principal: Option<Principal>,
+    pub txid: Option<String>,
+    pub alkaneid: Option<String>,
+    pub amount: Option<u64>,
+    pub detail: String,
+}
+
+impl From<EventV1> for Event {
+    fn from(e: EventV1) -> Self {
+        Event {
+            id: e.id,
+            timestamp_nanos: e.timestamp_nanos,
+            kind: e.kind,
+            principal: e.principal,
+            txid: e.txid,
+            alkaneid: e.alkaneid,
+            amount: e.amount.map(u128::from),
+            detail: e.detail,
+        }
+    }
+}
+
+/// All set fields must match. Results are newest first; `before_id` pages
+/// backwards from a previous result.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EventFilter {
+    pub kinds: Option<Vec<EventKind>>,
+    pub from_nanos: Option<u64>,
+    pub to_nanos: Option<u64>,
+    pub txid: Option<String>,
+    pub principal: Option<Principal>,
+    pub alkaneid: Option<String>,
+    pub before_id: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RetentionConfig {
+    pub max_events: u64,
+    /// Events older than this are dropped; `None` keeps them until
+    /// `max_events` pushes them out.
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self { max_events: DEFAULT_MAX_EVENTS, max_age_secs: None }
+    }
+}
+
+thread_local! {
+    static EVENTS: RefCell<VecDeque<Event>> = const { RefCell::new(VecDeque::new()) };
+    static NEXT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static RETENTION: RefCell<RetentionConfig> = RefCell::new(RetentionConfig::default());
+}
+
+/// Builder for a new event, written by `record`.
+pub struct NewEvent {
+    kind: EventKind,
+    principal: Option<Principal>,
+    txid: Option<String>,
+    alkaneid: Option<String>,
+    amount: Option<u128>,
+    detail: String,
+}
+
+pub fn event(kind: EventKind) -> NewEvent {
+    NewEvent { kind, principal: None, txid: None, alkaneid: None, amount: None, detail: String::new() }
+}
+
+impl NewEvent {
+    pub fn principal(mut self, principal: Principal) -> Self {
+        self.principal = Some(principal);
+        self
+    }
+
+    pub fn txid(mut self, txid: impl Into<String>) -> Self {
+        self.txid = Some(txid.into());
+        self
+    }
+
+    pub fn alkaneid(mut self, alkaneid: impl Into<String>) -> Self {
+        self.alkaneid = Some(alkaneid.into());
+        self
+    }
+
+    pub fn amount(mut self, amount: u128) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = detail.into();
+        self
+    }
+
+    pub fn record(self) {
+        let now = time();
+        let id = NEXT_ID.with(|n| {
+            let mut next = n.borrow_mut();
+            let id = *next;
+            *next += 1;
+            id
+        });
+        EVENTS.with(|e| {
+            let mut events = e.borrow_mut();
+            events.push_back(Event {
+                id,
+                timestamp_nanos: now,
+                kind: self.kind,
+                principal: self.principal,
+                txid: self.txid,
+                alkaneid: self.alkaneid,
+                amount: self.amount,
+                detail: self.detail,
+            });
+            prune(&mut events, &RETENTION.with(|r| r.borrow().clone()), now);
+        });
+    }
+}
+
+fn prune(events: &mut VecDeque<Event>, retention: &RetentionConfig, now: u64) {
+    while events.len() as u64 > retention.max_events {
+        events.pop_front();
+    }
+    if let Some(max_age) = retention.max_age_secs {
+        let cutoff = now.saturating_sub(max_age.saturating_mul(NANOS_PER_SEC));
+        while events.front().is_some_and(|e| e.timestamp_nanos < cutoff) {
+            events.pop_front();
+        }
+    }
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        self.kinds.as_ref().is_none_or(|kinds| kinds.contains(&event.kind))
+            && self.from_nanos.is_none_or(|from| event.timestamp_nanos >= from)
+            && self.to_nanos.is_none_or(|to| event.timestamp_nanos <= to)
+            && self.txid.as_ref().is_none_or(|txid| event.txid.as_ref() == Some(txid))
+            && self.principal.is_none_or(|p| event.principal == Some(p))
+            && self.alkaneid.as_ref().is_none_or(|id| event.alkaneid.as_ref() == Some(id))
+            && self.before_id.is_none_or(|before| event.id < before)
+    }
+}
+
+pub fn query(filter: &EventFilter) -> Vec<Event> {
+    let limit = filter.limit.unwrap_or(100).min(MAX_PAGE) as usize;
+    EVENTS.with(|e| {
+        e.borrow()
+            .iter()
+            .rev()
+            .filter(|event| filter.matches(event))
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn retention() -> RetentionConfig {
+    RETENTION.with(|r| r.borrow().clone())
+}
+
+pub fn validate_retention(retention: &RetentionConfig) -> Result<(), String> {
+    if retention.max_events == 0 {
+        return Err("max_events must be at least 1".into());
+    }
+    if retention.max_events > MAX_EVENTS_LIMIT {
+        return Err(format!("max_events must be at most {}", MAX_EVENTS_LIMIT));
+    }
+    Ok(())
+}
+
+pub fn set_retention(retention: RetentionConfig) -> Result<(), String> {
+    validate_retention(&retention)?;
+    let now = time();
+    EVENTS.with(|e| prune(&mut e.borrow_mut(), &retention, now));
+    RETENTION.with(|r| *r.borrow_mut() = retention);
+    Ok(())
+}
+
+pub fn export() -> Vec<Event> {
+    EVENTS.with(|e| e.borrow().iter().cloned().collect())
+}
+
+/// Also applies the upper bound on `max_events` to a retention stored
+/// before it existed.
+pub fn restore(events: Vec<Event>, mut retention: RetentionConfig) {
+    let next_id = events.last().map(|e| e.id + 1).unwrap_or(1);
+    retention.max_events = retention.max_events.min(MAX_EVENTS_LIMIT);
+    let mut events: VecDeque<Event> = events.into();
+    prune(&mut events, &retention, time());
+    EVENTS.with(|e| *e.borrow_mut() = events);
+    NEXT_ID.with(|n| *n.borrow_mut() = next_id);
+    RETENTION.with(|r| *r.borrow_mut() = retention);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(id: u64, secs: u64, kind: EventKind, txid: &str) -> Event {
+        Event {
+            id,
+            timestamp_nanos: secs * NANOS_PER_SEC,
+            kind,
+            principal: None,
+            txid: Some(txid.into()),
+            alkaneid: Some("2:1".into()),
+            amount: None,
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_filter_and_retention() {
+        let mut events: VecDeque<Event> = vec![
+            at(1, 10, EventKind::TopupCredited, "aa"),
+            at(2, 20, EventKind::TxBroadcast, "bb"),
+            at(3, 30, EventKind::TxConfirmed, "bb"),
+            at(4, 40, EventKind::TopupCredited, "cc"),
+        ]
+        .into();
+
+        let filter = EventFilter { txid: Some("bb".into()), ..Default::default() };
+        assert_eq!(events.iter().filter(|e| filter.matches(e)).count(), 2);
+        let filter = EventFilter {
+            kinds: Some(vec![EventKind::TopupCredited]),
+            from_nanos: Some(15 * NANOS_PER_SEC),
+            ..Default::default()
+        };
+        let ids: Vec<u64> = events.iter().filter(|e| filter.matches(e)).map(|e| e.id).collect();
+        assert_eq!(ids, vec![4]);
+
+        prune(&mut events, &RetentionConfig { max_events: 3, max_age_secs: Some(15) }, 40 * NANOS_PER_SEC);
+        let ids: Vec<u64> = events.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![3, 4]);
+
+        assert!(validate_retention(&RetentionConfig { max_events: 0, max_age_secs: None }).is_err());
+        assert!(validate_retention(&RetentionConfig { max_events: MAX_EVENTS_LIMIT + 1, max_age_secs: None }).is_err());
+        assert!(validate_retention(&RetentionConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::did::fomowell_token::Account;
+
+/// `token_type` of the withdraw requests `sweep_fees` queues. Their alkanes
+/// come out of the accrued fees instead of a credited balance.
+pub const SWEEP_TOKEN_TYPE: &str = "fee_sweep";
+
+/// Receives topup fees of tokens whose `fee_destination` is `Treasury`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TreasuryAccount {
+    pub owner: Principal,
+    pub subaccount: Option<ByteBuf>,
+}
+
+impl From<TreasuryAccount> for Account {
+    fn from(account: TreasuryAccount) -> Self {
+        Account { owner: account.owner, subaccount: account.subaccount }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct FeeState {
+    pub treasury: Option<TreasuryAccount>,
+    /// Fees held as alkanes in the fund. Amounts queued for a sweep stay
+    /// here until the sweep is broadcast.
+    pub accrued: Vec<(String, u128)>,
+}
+
+thread_local! {
+    static TREASURY: RefCell<Option<TreasuryAccount>> = const { RefCell::new(None) };
+    static ACCRUED: RefCell<BTreeMap<String, u128>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_SWEEP: RefCell<u64> = const { RefCell::new(0) };
+}
+
+/// Books a fee kept in the fund. Runs after the transfer or broadcast it
+/// belongs to, so it cannot fail; the total stops at u128::MAX.
+pub fn accrue(alkaneid: &str, amount: u128) {
+    if amount == 0 {
+        return;
+    }
+    ACCRUED.with(|a| {
+        let mut accrued = a.borrow_mut();
+        let total = accrued.entry(alkaneid.to_string()).or_insert(0);
+        *total = total.saturating_add(amount);
+    });
+}
+
+pub fn accrued(alkaneid: &str) -> u128 {
+    ACCRUED.with(|a| a.borrow().get(alkaneid).copied().unwrap_or(0))
+}
+
+/// Books `amount` of `alkaneid` as swept, once the sweep is broadcast.
+pub fn deduct(alkaneid: &str, amount: u128) {
+    ACCRUED.with(|a| {
+        let mut accrued = a.borrow_mut();
+        if let Some(total) = accrued.get_mut(alkaneid) {
+            *total = total.saturating_sub(amount);
+            if *total == 0 {
+                accrued.remove(alkaneid);
+            }
+        }
+    });
+}
+
+/// Sequence number for the `ic_txid` of the next sweep.
+pub fn next_sweep() -> u64 {
+    NEXT_SWEEP.with(|n| {
+        let mut next = n.borrow_mut();
+        *next += 1;
+        *next
+    })
+}
+
+pub fn sweep_seq() -> u64 {
+    NEXT_SWEEP.with(|n| *n.borrow())
+}
+
+pub fn treasury() -> Option<TreasuryAccount> {
+    TREASURY.with(|t| t.borrow().clone())
+}
+
+/// Runs from an approved `SetTreasury` proposal.
+pub fn set_treasury(account: Option<TreasuryAccount>) {
+    TREASURY.with(|t| *t.borrow_mut() = account);
+}
+
+pub fn state() -> FeeState {
+    FeeState { treasury: treasury(), accrued: ACCRUED.with(|a| a.borrow().iter().map(|(k, v)| (k.clone(), *v)).collect()) }
+}
+
+pub fn restore(state: FeeState, sweep_seq: u64) {
+    NEXT_SWEEP.with(|n| *n.borrow_mut() = sweep_seq);
+    TREASURY.with(|t| *t.borrow_mut() = state.treasury);
+    ACCRUED.with(|a| *a.borrow_mut() = state.accrued.into_iter().collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accrue_and_deduct() {
+        restore(FeeState::default(), 0);
+        accrue("2:1", 10);
+        accrue("2:1", 5);
+        accrue("2:2", 0);
+        assert_eq!(state().accrued, vec![("2:1".to_string(), 15)]);
+        deduct("2:1", 6);
+        assert_eq!(accrued("2:1"), 9);
+        deduct("2:1", 20);
+        assert_eq!(state().accrued, vec![]);
+        assert_eq!((next_sweep(), next_sweep()), (1, 2));
+    }
+}
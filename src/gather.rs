@@ -0,0 +1,244 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::txplan::{MAX_OP_RETURN_BYTES, OutputPlan, PlannedInput, TxSimulation, checked_total};
+
+/// 归集策略，决定 `gather_alkanes_utxo` 什么时候扫、扫哪些 utxo
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct GatherPolicy {
+    /// Sweep once the topup address holds at least this many UTXOs...
+    pub min_utxos: u32,
+    /// ...or at least this amount of any one alkane. `None` only looks at the count.
+    pub min_amount: Option<u128>,
+    /// Skip the run while the fee rate (sat/vB) is above this.
+    pub max_fee_rate: Option<u64>,
+    /// Also spend the fund UTXOs of the swept alkanes, so each alkane ends up
+    /// on a single fund UTXO instead of one more per sweep.
+    pub merge_fund_utxos: bool,
+    /// Inputs per transaction, BTC inputs included. What does not fit is left
+    /// for the next run.
+    pub max_inputs: u32,
+}
+
+/// `GatherPolicy` as stored before amounts were widened to u128, only read at
+/// upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GatherPolicyV1 {
+    pub min_utxos: u32,
+    pub min_amount: Option<u64>,
+    pub max_fee_rate: Option<u64>,
+    pub merge_fund_utxos: bool,
+    pub max_inputs: u32,
+}
+
+impl From<GatherPolicyV1> for GatherPolicy {
+    fn from(p: GatherPolicyV1) -> Self {
+        GatherPolicy {
+            min_utxos: p.min_utxos,
+            min_amount: p.min_amount.map(u128::from),
+            max_fee_rate: p.max_fee_rate,
+            merge_fund_utxos: p.merge_fund_utxos,
+            max_inputs: p.max_inputs,
+        }
+    }
+}
+
+impl Default for GatherPolicy {
+    fn default() -> Self {
+        Self { min_utxos: 1, min_amount: None, max_fee_rate: None, merge_fund_utxos: true, max_inputs: 50 }
+    }
+}
+
+/// Result of one gather run. `skipped` says why nothing was built; `txid` is
+/// set once the transaction was broadcast, so a dry run only has `simulation`.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct GatherReport {
+    pub skipped: Option<String>,
+    pub simulation: Option<TxSimulation>,
+    pub txid: Option<String>,
+}
+
+impl GatherReport {
+    pub fn skipped(reason: impl Into<String>) -> Self {
+        Self { skipped: Some(reason.into()), ..Default::default() }
+    }
+}
+
+thread_local! {
+    static POLICY: RefCell<GatherPolicy> = RefCell::new(GatherPolicy::default());
+}
+
+pub fn validate_policy(policy: &GatherPolicy) -> Result<(), String> {
+    if policy.min_utxos == 0 {
+        return Err("min_utxos must be at least 1".into());
+    }
+    // 至少一个 alkane 输入加一个付手续费的 btc 输入
+    if policy.max_inputs < 2 {
+        return Err("max_inputs must be at least 2".into());
+    }
+    Ok(())
+}
+
+/// Runs from an approved `SetGatherPolicy` proposal.
+pub fn set_policy(policy: GatherPolicy) -> Result<(), String> {
+    validate_policy(&policy)?;
+    POLICY.with(|p| *p.borrow_mut() = policy);
+    Ok(())
+}
+
+pub fn policy() -> GatherPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+pub fn restore(policy: GatherPolicy) {
+    POLICY.with(|p| *p.borrow_mut() = policy);
+}
+
+fn totals(inputs: &[PlannedInput]) -> Result<BTreeMap<String, u128>, String> {
+    let mut totals: BTreeMap<String, u128> = BTreeMap::new();
+    for (alkaneid, amount) in inputs.iter().flat_map(|input| &input.alkanes) {
+        let total = totals.entry(alkaneid.clone()).or_insert(0);
+        *total = checked_total(*total, *amount, alkaneid)?;
+    }
+    Ok(totals)
+}
+
+/// Why the topup UTXOs are not worth sweeping yet, if they are not.
+pub fn skip_reason(topup: &[PlannedInput], policy: &GatherPolicy) -> Option<String> {
+    if topup.is_empty() {
+        return Some("no topup UTXOs".into());
+    }
+    if topup.len() >= policy.min_utxos as usize {
+        return None;
+    }
+    if let Some(min_amount) = policy.min_amount
+        && totals(topup).is_ok_and(|totals| totals.values().any(|amount| *amount >= min_amount))
+    {
+        return None;
+    }
+    Some(format!("{} topup UTXOs, below min_utxos {}", topup.len(), policy.min_utxos))
+}
+
+/// Whether the protostone sweeping `inputs` fits in the OP_RETURN output.
+/// Totals that do not add up count as fitting; `plan_outputs` reports them.
+fn protostone_fits(inputs: &[PlannedInput]) -> bool {
+    if inputs.is_empty() {
+        return true;
+    }
+    match plan_outputs(inputs, "", 0).and_then(|plan| plan.finish(0, "")) {
+        Ok((_, protostone)) => protostone.len() <= MAX_OP_RETURN_BYTES,
+        Err(_) => true,
+    }
+}
+
+/// Alkane inputs of one sweep: the topup outpoints holding the most first,
+/// then, when merging, the fund outpoints holding a swept alkane, smallest
+/// first. One slot of `max_inputs` is kept for a BTC input, and inputs are
+/// dropped from the end until the protostone (one edict per alkane) fits in
+/// `MAX_OP_RETURN_BYTES`.
+pub fn select_inputs(mut topup: Vec<PlannedInput>, mut fund: Vec<PlannedInput>, policy: &GatherPolicy) -> Vec<PlannedInput> {
+    let limit = policy.max_inputs.saturating_sub(1) as usize;
+    // 只用来排序，溢出时按最大值算
+    let held = |input: &PlannedInput| input.alkanes.iter().fold(0u128, |sum, (_, amount)| sum.saturating_add(*amount));
+    topup.sort_by(|a, b| held(b).cmp(&held(a)).then_with(|| (&a.txid, a.vout).cmp(&(&b.txid, b.vout))));
+    topup.truncate(limit);
+    while !protostone_fits(&topup) {
+        topup.pop();
+    }
+    if !policy.merge_fund_utxos {
+        return topup;
+    }
+    let swept: HashSet<String> =
+        topup.iter().flat_map(|input| input.alkanes.iter().map(|(alkaneid, _)| alkaneid.clone())).collect();
+    fund.retain(|input| input.alkanes.iter().any(|(alkaneid, _)| swept.contains(alkaneid)));
+    fund.sort_by(|a, b| held(a).cmp(&held(b)).then_with(|| (&a.txid, a.vout).cmp(&(&b.txid, b.vout))));
+    let room = limit - topup.len();
+    topup.extend(fund.into_iter().take(room));
+    // 合并后总量变大，edict 的 varint 可能变长
+    while !protostone_fits(&topup) {
+        topup.pop();
+    }
+    topup
+}
+
+/// One fund output per alkane in `inputs`, ordered by alkane id, each with
+/// the edict moving that alkane's total to it.
+pub fn plan_outputs(inputs: &[PlannedInput], fund_address: &str, postage: u64) -> Result<OutputPlan, String> {
+    let mut plan = OutputPlan::default();
+    for (alkaneid, amount) in totals(inputs)? {
+        plan.pay(fund_address, postage, &alkaneid, amount)?;
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(address: &str, txid: &str, vout: u64, alkanes: &[(&str, u128)]) -> PlannedInput {
+        PlannedInput {
+            address: address.into(),
+            txid: txid.into(),
+            vout,
+            satoshi: 330,
+            alkanes: alkanes.iter().map(|(alkaneid, amount)| (alkaneid.to_string(), *amount)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_gather_selection() {
+        let topup = vec![
+            input("topup", "aa", 0, &[("2:1", 10)]),
+            // 同一个 outpoint 上有两种 alkane，只算一个输入
+            input("topup", "bb", 0, &[("2:1", 50), ("2:7", 5)]),
+            input("topup", "cc", 1, &[("2:1", 30)]),
+        ];
+        let fund = vec![input("fund", "dd", 0, &[("2:1", 500)]), input("fund", "ee", 0, &[("9:9", 1)])];
+
+        let policy = GatherPolicy { min_utxos: 5, ..Default::default() };
+        assert!(skip_reason(&topup, &policy).is_some());
+        assert!(skip_reason(&[], &GatherPolicy::default()).is_some());
+        let policy = GatherPolicy { min_utxos: 5, min_amount: Some(60), ..Default::default() };
+        assert_eq!(skip_reason(&topup, &policy), None);
+
+        // 合并时只带上同种 alkane 的 fund utxo
+        let selected = select_inputs(topup.clone(), fund.clone(), &GatherPolicy::default());
+        let outpoints: Vec<&str> = selected.iter().map(|input| input.txid.as_str()).collect();
+        assert_eq!(outpoints, ["bb", "cc", "aa", "dd"]);
+
+        let policy = GatherPolicy { max_inputs: 3, ..Default::default() };
+        let selected = select_inputs(topup.clone(), fund.clone(), &policy);
+        let outpoints: Vec<&str> = selected.iter().map(|input| input.txid.as_str()).collect();
+        assert_eq!(outpoints, ["bb", "cc"]);
+
+        let policy = GatherPolicy { merge_fund_utxos: false, ..Default::default() };
+        assert_eq!(select_inputs(topup.clone(), fund, &policy).len(), 3);
+
+        let (outputs, _) = plan_outputs(&topup, "fund", 330).unwrap().finish(0, "fund").unwrap();
+        let alkanes: Vec<(String, u128)> = outputs.into_iter().flat_map(|output| output.alkanes).collect();
+        assert_eq!(alkanes, vec![("2:1".to_string(), 90), ("2:7".to_string(), 5)]);
+
+        // 超过 u64 的总量照常合并，超过 u128 则拒绝
+        let large = vec![input("topup", "aa", 0, &[("2:1", u64::MAX as u128)]), input("topup", "bb", 0, &[("2:1", 1)])];
+        let (outputs, _) = plan_outputs(&large, "fund", 330).unwrap().finish(0, "fund").unwrap();
+        assert_eq!(outputs[0].alkanes, vec![("2:1".to_string(), u64::MAX as u128 + 1)]);
+        let overflow = vec![input("topup", "aa", 0, &[("2:1", u128::MAX)]), input("topup", "bb", 0, &[("2:1", 1)])];
+        assert!(plan_outputs(&overflow, "fund", 330).is_err());
+
+        // 每种 alkane 一个 edict，放不进 OP_RETURN 的输入留到下一次
+        let many: Vec<PlannedInput> = (0..30u64)
+            .map(|n| input("topup", &format!("{:02x}", n), 0, &[(&format!("2:{}", 100 + n), 1_000_000 - n as u128)]))
+            .collect();
+        let (_, protostone) = plan_outputs(&many, "fund", 330).unwrap().finish(0, "fund").unwrap();
+        assert!(protostone.len() > MAX_OP_RETURN_BYTES);
+        let selected = select_inputs(many.clone(), Vec::new(), &GatherPolicy::default());
+        assert!(!selected.is_empty() && selected.len() < many.len());
+        assert_eq!(selected[..], many[..selected.len()]);
+        let (_, protostone) = plan_outputs(&selected, "fund", 330).unwrap().finish(0, "fund").unwrap();
+        assert!(protostone.len() <= MAX_OP_RETURN_BYTES);
+
+        assert!(validate_policy(&GatherPolicy { max_inputs: 1, ..Default::default() }).is_err());
+        assert!(validate_policy(&GatherPolicy::default()).is_ok());
+    }
+}
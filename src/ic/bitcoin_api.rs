@@ -55,9 +55,10 @@ pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResp
 ///
 /// Relies on the `bitcoin_send_transaction` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_send_transaction
-pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
+pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) -> Result<(), String> {
     let transaction_fee = SEND_TRANSACTION_BASE_CYCLES
         + (transaction.len() as u64) * SEND_TRANSACTION_PER_BYTE_CYCLES;
+    crate::budget::charge("bitcoin_send_transaction", transaction_fee as u128)?;
 
     let res: Result<(), _> = call_with_payment(
         Principal::management_canister(),
@@ -69,6 +70,7 @@ pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
         transaction_fee,
     )
     .await;
+    crate::metrics::record("bitcoin_send_transaction", res.is_ok(), transaction_fee as u128);
 
-    res.unwrap();
+    res.map_err(|(code, msg)| format!("bitcoin_send_transaction rejected: {:?} {}", code, msg))
 }
\ No newline at end of file
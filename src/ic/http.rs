@@ -0,0 +1,374 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk_macros::query;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+// HTTPS outcall pricing on a 13-node application subnet.
+// See https://internetcomputer.org/docs/current/developer-docs/gas-cost#https-outcalls
+const SUBNET_SIZE: u128 = 13;
+const HTTP_REQUEST_BASE_CYCLES: u128 = (3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE;
+const HTTP_REQUEST_PER_BYTE_CYCLES: u128 = 400 * SUBNET_SIZE;
+const HTTP_RESPONSE_PER_BYTE_CYCLES: u128 = 800 * SUBNET_SIZE;
+
+/// Hard ceiling enforced by the management canister.
+pub const MAX_RESPONSE_BYTES_LIMIT: u64 = 2_000_000;
+
+const TRANSFORM_METHOD: &str = "transform_http_response";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum HttpError {
+    /// The management canister rejected the outcall (timeout, no consensus, ...).
+    Rejected { code: String, message: String },
+    /// The remote answered with a non-2xx status.
+    Status { status: u16, body: String },
+    /// `max_response_bytes` is above what the management canister accepts.
+    ResponseCapTooLarge { requested: u64, limit: u64 },
+    /// The canister cannot pay for the outcall.
+    InsufficientCycles { required: u128, available: u128 },
+    /// The body could not be decoded as JSON.
+    InvalidBody(String),
+    /// Today's `http_outcall` cycle budget is used up.
+    BudgetExceeded(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Rejected { code, message } => {
+                write!(f, "HTTP request failed: code={}, message={}", code, message)
+            }
+            HttpError::Status { status, body } => write!(f, "HTTP status {}: {}", status, body),
+            HttpError::ResponseCapTooLarge { requested, limit } => write!(
+                f,
+                "max_response_bytes {} exceeds limit {}",
+                requested, limit
+            ),
+            HttpError::InsufficientCycles { required, available } => write!(
+                f,
+                "Insufficient cycles for HTTP outcall: required {}, available {}",
+                required, available
+            ),
+            HttpError::InvalidBody(e) => write!(f, "Invalid response body: {}", e),
+            HttpError::BudgetExceeded(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl HttpError {
+    /// Transient failures that are worth another attempt.
+    fn is_retryable(&self) -> bool {
+        match self {
+            HttpError::Rejected { code, .. } => code == "SysTransient",
+            HttpError::Status { status, .. } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub multiplier: u32,
+}
+
+impl RetryPolicy {
+    pub const NONE: RetryPolicy = RetryPolicy {
+        max_attempts: 1,
+        initial_backoff: Duration::ZERO,
+        multiplier: 1,
+    };
+
+    pub const DEFAULT: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_secs(2),
+        multiplier: 2,
+    };
+}
+
+/// A JSON outcall. Only the fields listed in `keep_fields` (JSON pointers such
+/// as `/data/tokenData/symbol`) survive the transform; an empty list keeps the
+/// whole body. Either way the body is re-serialized with sorted keys, so all
+/// replicas see identical bytes.
+#[derive(Clone, Debug)]
+pub struct JsonRequest {
+    pub url: String,
+    pub method: HttpMethod,
+    pub headers: Vec<HttpHeader>,
+    pub body: Option<Value>,
+    pub max_response_bytes: u64,
+    pub keep_fields: Vec<String>,
+    pub retry: RetryPolicy,
+}
+
+impl JsonRequest {
+    pub fn get(url: impl Into<String>, max_response_bytes: u64) -> Self {
+        Self {
+            url: url.into(),
+            method: HttpMethod::GET,
+            headers: vec![],
+            body: None,
+            max_response_bytes,
+            keep_fields: vec![],
+            retry: RetryPolicy::DEFAULT,
+        }
+    }
+
+    pub fn post(url: impl Into<String>, body: Value, max_response_bytes: u64) -> Self {
+        Self {
+            method: HttpMethod::POST,
+            headers: vec![HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            }],
+            body: Some(body),
+            ..Self::get(url, max_response_bytes)
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push(HttpHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+        self
+    }
+
+    pub fn keep(mut self, fields: &[&str]) -> Self {
+        self.keep_fields = fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    fn transform_context(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.keep_fields).unwrap_or_default()
+    }
+
+    /// Bytes counted by the management canister when pricing the request.
+    pub fn request_bytes(&self) -> usize {
+        self.url.len()
+            + self
+                .headers
+                .iter()
+                .map(|h| h.name.len() + h.value.len())
+                .sum::<usize>()
+            + self.body.as_ref().map(|b| b.to_string().len()).unwrap_or(0)
+            + TRANSFORM_METHOD.len()
+            + self.transform_context().len()
+    }
+
+    fn to_argument(&self) -> CanisterHttpRequestArgument {
+        CanisterHttpRequestArgument {
+            url: self.url.clone(),
+            max_response_bytes: Some(self.max_response_bytes),
+            method: self.method,
+            headers: self.headers.clone(),
+            body: self.body.as_ref().map(|b| b.to_string().into_bytes()),
+            transform: Some(TransformContext::from_name(
+                TRANSFORM_METHOD.to_string(),
+                self.transform_context(),
+            )),
+        }
+    }
+}
+
+/// Cycles the management canister charges for an outcall, based on the
+/// request size and the response cap.
+pub fn outcall_cycles(request_bytes: usize, max_response_bytes: u64) -> u128 {
+    HTTP_REQUEST_BASE_CYCLES
+        + HTTP_REQUEST_PER_BYTE_CYCLES * request_bytes as u128
+        + HTTP_RESPONSE_PER_BYTE_CYCLES * max_response_bytes as u128
+}
+
+/// Sends `request`, retrying transient failures with exponential backoff, and
+/// returns the normalized JSON body.
+pub async fn send_json(request: &JsonRequest) -> Result<Value, HttpError> {
+    if request.max_response_bytes > MAX_RESPONSE_BYTES_LIMIT {
+        return Err(HttpError::ResponseCapTooLarge {
+            requested: request.max_response_bytes,
+            limit: MAX_RESPONSE_BYTES_LIMIT,
+        });
+    }
+
+    let arg = request.to_argument();
+    let cycles = outcall_cycles(request.request_bytes(), request.max_response_bytes);
+    let mut backoff = request.retry.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        let available = ic_cdk::api::canister_balance128();
+        if available < cycles {
+            return Err(HttpError::InsufficientCycles {
+                required: cycles,
+                available,
+            });
+        }
+        crate::budget::charge("http_outcall", cycles).map_err(|e| HttpError::BudgetExceeded(e.to_string()))?;
+
+        let result = match http_request(arg.clone(), cycles).await {
+            Ok((response,)) => parse_response(response),
+            Err((code, message)) => Err(HttpError::Rejected {
+                code: rejection_code_name(code),
+                message,
+            }),
+        };
+        crate::metrics::record("http_outcall", result.is_ok(), cycles);
+
+        match result {
+            Err(e) if e.is_retryable() && attempt < request.retry.max_attempts => {
+                attempt += 1;
+                if !backoff.is_zero() {
+                    sleep(backoff).await;
+                }
+                backoff *= request.retry.multiplier;
+            }
+            other => return other,
+        }
+    }
+}
+
+fn parse_response(response: HttpResponse) -> Result<Value, HttpError> {
+    let status: u16 = u16::try_from(response.status.0).unwrap_or(u16::MAX);
+    if !(200..300).contains(&status) {
+        return Err(HttpError::Status {
+            status,
+            body: String::from_utf8_lossy(&response.body).into_owned(),
+        });
+    }
+    serde_json::from_slice(&response.body).map_err(|e| HttpError::InvalidBody(e.to_string()))
+}
+
+fn rejection_code_name(code: RejectionCode) -> String {
+    format!("{:?}", code)
+}
+
+/// Transform applied by every replica before consensus: drops all headers and
+/// rewrites JSON bodies into a canonical form restricted to the requested fields.
+#[query]
+fn transform_http_response(args: TransformArgs) -> HttpResponse {
+    let keep_fields: Vec<String> = serde_json::from_slice(&args.context).unwrap_or_default();
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: normalize_json_body(&args.response.body, &keep_fields),
+    }
+}
+
+/// Non-JSON bodies are passed through unchanged.
+pub fn normalize_json_body(body: &[u8], keep_fields: &[String]) -> Vec<u8> {
+    let Ok(json) = serde_json::from_slice::<Value>(body) else {
+        return body.to_vec();
+    };
+    let json = if keep_fields.is_empty() {
+        json
+    } else {
+        let mut kept = Value::Object(Default::default());
+        for pointer in keep_fields {
+            if let Some(value) = json.pointer(pointer) {
+                insert_at_pointer(&mut kept, pointer, value.clone());
+            }
+        }
+        kept
+    };
+    // serde_json 的 Map 默认按 key 排序，序列化结果在各副本间一致
+    json.to_string().into_bytes()
+}
+
+fn insert_at_pointer(root: &mut Value, pointer: &str, value: Value) {
+    let mut current = root;
+    let segments: Vec<&str> = pointer.split('/').skip(1).collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let Value::Object(map) = current else {
+            return;
+        };
+        if i + 1 == segments.len() {
+            map.insert(segment.to_string(), value);
+            return;
+        }
+        current = map
+            .entry(segment.to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+}
+
+/// Resolves after `delay`, driven by a one-shot timer.
+fn sleep(delay: Duration) -> impl Future<Output = ()> {
+    struct Sleep {
+        state: Rc<RefCell<(bool, Option<Waker>)>>,
+    }
+    impl Future for Sleep {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut state = self.state.borrow_mut();
+            if state.0 {
+                Poll::Ready(())
+            } else {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    let state = Rc::new(RefCell::new((false, None::<Waker>)));
+    let timer_state = state.clone();
+    ic_cdk_timers::set_timer(delay, move || {
+        let waker = {
+            let mut state = timer_state.borrow_mut();
+            state.0 = true;
+            state.1.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    Sleep { state }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_keeps_only_requested_fields_sorted() {
+        let body = br#"{"z":1,"data":{"tokenData":{"symbol":"DIESEL","name":"x"},"extra":2},"code":0}"#;
+        let keep = vec!["/code".to_string(), "/data/tokenData/symbol".to_string()];
+        let normalized = String::from_utf8(normalize_json_body(body, &keep)).unwrap();
+        assert_eq!(normalized, r#"{"code":0,"data":{"tokenData":{"symbol":"DIESEL"}}}"#);
+    }
+
+    #[test]
+    fn test_normalize_sorts_whole_body_and_passes_through_non_json() {
+        let normalized = normalize_json_body(br#"{"b":1,"a":2}"#, &[]);
+        assert_eq!(normalized, br#"{"a":2,"b":1}"#.to_vec());
+        assert_eq!(normalize_json_body(b"not json", &[]), b"not json".to_vec());
+    }
+
+    #[test]
+    fn test_cycles_grow_with_request_and_response_size() {
+        let small = JsonRequest::get("https://example.com", 1_000);
+        let large_request = JsonRequest::post(
+            "https://example.com",
+            serde_json::json!({ "k": "v".repeat(1_000) }),
+            1_000,
+        );
+        assert!(large_request.request_bytes() > small.request_bytes() + 1_000);
+        assert_eq!(
+            outcall_cycles(small.request_bytes(), 100_000) - outcall_cycles(small.request_bytes(), 1_000),
+            99_000 * HTTP_RESPONSE_PER_BYTE_CYCLES
+        );
+    }
+}
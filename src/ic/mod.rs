@@ -12,4 +12,5 @@ mod common;
 pub mod p2tr_key_only;
 pub mod schnorr_api;
 pub mod utxo_api;
-pub mod bitcoin_api;
\ No newline at end of file
+pub mod bitcoin_api;
+pub mod http;
\ No newline at end of file
@@ -21,7 +21,7 @@ pub async fn get_address(
     network: BitcoinNetwork,
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
-) -> Address {
+) -> Result<Address, crate::error::FomowellError> {
     let public_key = ic_cdk::api::management_canister::schnorr::schnorr_public_key(SchnorrPublicKeyArgument {
         canister_id: None,
         derivation_path: derivation_path,
@@ -29,16 +29,20 @@ pub async fn get_address(
             algorithm: SchnorrAlgorithm::Bip340secp256k1,
             name: key_name
         }
-    }).await.unwrap().0.public_key;
-    let x_only_pubkey =
-        bitcoin::key::XOnlyPublicKey::from(PublicKey::from_slice(&public_key).unwrap());
+    }).await
+    .map_err(|e| crate::error::call_failed("schnorr_public_key", e))?
+    .0
+    .public_key;
+    let public_key = PublicKey::from_slice(&public_key)
+        .map_err(|e| crate::error::FomowellError::Internal(format!("Invalid schnorr public key: {}", e)))?;
+    let x_only_pubkey = bitcoin::key::XOnlyPublicKey::from(public_key);
     let secp256k1_engine = Secp256k1::new();
-    Address::p2tr(
+    Ok(Address::p2tr(
         &secp256k1_engine,
         x_only_pubkey,
         None,
         super::common::transform_network(network),
-    )
+    ))
 }
 
 // pub async fn sign_tx(
@@ -56,7 +56,10 @@ struct SignWithSchnorrReply {
 }
 
 /// Returns the Schnorr public key of this canister at the given derivation path.
-pub async fn schnorr_public_key(key_name: String, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+pub async fn schnorr_public_key(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, crate::error::FomowellError> {
     let res: Result<(SchnorrPublicKeyReply,), _> = ic_cdk::call(
         candid::Principal::management_canister(),
         "schnorr_public_key",
@@ -71,7 +74,8 @@ pub async fn schnorr_public_key(key_name: String, derivation_path: Vec<Vec<u8>>)
     )
     .await;
 
-    res.unwrap().0.public_key
+    res.map(|(reply,)| reply.public_key)
+        .map_err(|e| crate::error::call_failed("schnorr_public_key", e))
 }
 
 /// Returns the signature for `message` by a private and *distributed* private
@@ -83,7 +87,8 @@ pub async fn sign_with_schnorr(
     derivation_path: Vec<Vec<u8>>,
     merkle_root_hash: Option<Vec<u8>>,
     message: Vec<u8>,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, String> {
+    crate::budget::charge("sign_with_schnorr", SIGN_WITH_SCHNORR_FEE)?;
     let aux = merkle_root_hash.map(|bytes| {
         SignWithSchnorrAux::Bip341(SignWithBip341Aux {
             merkle_root_hash: ByteBuf::from(bytes),
@@ -104,6 +109,8 @@ pub async fn sign_with_schnorr(
         SIGN_WITH_SCHNORR_FEE,
     )
     .await;
+    crate::metrics::record("sign_with_schnorr", res.is_ok(), SIGN_WITH_SCHNORR_FEE);
 
-    res.unwrap().0.signature
+    res.map(|(reply,)| reply.signature)
+        .map_err(|(code, msg)| format!("sign_with_schnorr rejected: {:?} {}", code, msg))
 }
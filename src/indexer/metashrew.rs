@@ -0,0 +1,234 @@
+use serde_json::{json, Value};
+
+use super::{parse_u128, AlkaneBalance, AlkaneTokenInfo, AlkaneTrace, AlkaneUtxo, AlkanesIndexer, IndexerError};
+use crate::ic::http::{send_json, JsonRequest};
+
+const MAX_RESPONSE_BYTES: u64 = 500_000;
+const PROTOCOL_TAG: &str = "1";
+
+// 标准 alkanes 合约的只读 opcode
+const OPCODE_NAME: &str = "99";
+const OPCODE_SYMBOL: &str = "100";
+
+/// Self-hosted metashrew / alkanes JSON-RPC endpoint (the `alkanes_*` methods).
+pub struct MetashrewIndexer {
+    pub url: String,
+}
+
+impl MetashrewIndexer {
+    async fn call(&self, method: &str, params: Value) -> Result<Value, IndexerError> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let json = send_json(&JsonRequest::post(&self.url, body, MAX_RESPONSE_BYTES)).await?;
+        match json.get("error") {
+            Some(error) if !error.is_null() => Err(IndexerError::Api(
+                error["message"].as_str().unwrap_or("Unknown error").to_string(),
+            )),
+            _ => Ok(json["result"].clone()),
+        }
+    }
+
+    /// Runs a read-only opcode against the alkane and decodes the returned bytes as text.
+    async fn simulate_text(&self, alkaneid: &str, opcode: &str) -> Result<String, IndexerError> {
+        let (block, tx) = alkaneid
+            .split_once(':')
+            .ok_or_else(|| IndexerError::Parse(format!("invalid alkaneid {}", alkaneid)))?;
+        let result = self
+            .call(
+                "alkanes_simulate",
+                json!([{
+                    "alkanes": [],
+                    "transaction": "0x",
+                    "block": "0x",
+                    "height": "0",
+                    "txindex": 0,
+                    "target": { "block": block, "tx": tx },
+                    "inputs": [opcode],
+                    "pointer": 0,
+                    "refundPointer": 0,
+                    "vout": 0,
+                }]),
+            )
+            .await?;
+        let data = result["execution"]["data"]
+            .as_str()
+            .ok_or_else(|| IndexerError::Parse("missing execution data".into()))?;
+        let bytes = hex::decode(data.trim_start_matches("0x"))
+            .map_err(|e| IndexerError::Parse(e.to_string()))?;
+        Ok(String::from_utf8_lossy(&bytes).trim_end_matches('\0').to_string())
+    }
+}
+
+/// metashrew 的 outpoint 使用内部字节序的 txid，其余代码用显示字节序。
+/// 两个方向都经过这里转换。
+fn reversed_txid(txid: &str) -> Result<String, IndexerError> {
+    let mut bytes = hex::decode(txid).map_err(|e| IndexerError::Parse(e.to_string()))?;
+    bytes.reverse();
+    Ok(hex::encode(bytes))
+}
+
+fn parse_runes(runes: &Value) -> Result<Vec<AlkaneBalance>, IndexerError> {
+    runes
+        .as_array()
+        .map(|items| {
+            items
+                .iter()
+                .map(|item| {
+                    let id = &item["rune"]["id"];
+                    let block = parse_u128(&id["block"])
+                        .ok_or_else(|| IndexerError::Parse("missing rune block".into()))?;
+                    let tx = parse_u128(&id["tx"])
+                        .ok_or_else(|| IndexerError::Parse("missing rune tx".into()))?;
+                    Ok(AlkaneBalance {
+                        alkaneid: format!("{}:{}", block, tx),
+                        amount: parse_u128(&item["balance"])
+                            .ok_or_else(|| IndexerError::Parse("missing balance".into()))?,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_else(|| Ok(Vec::new()))
+}
+
+fn parse_outpoint(item: &Value) -> Result<AlkaneUtxo, IndexerError> {
+    Ok(AlkaneUtxo {
+        txid: reversed_txid(
+            item["outpoint"]["txid"]
+                .as_str()
+                .ok_or_else(|| IndexerError::Parse("missing outpoint txid".into()))?,
+        )?,
+        vout: item["outpoint"]["vout"]
+            .as_u64()
+            .ok_or_else(|| IndexerError::Parse("missing outpoint vout".into()))?,
+        satoshi: item["output"]["value"].as_u64().unwrap_or(0),
+        balances: parse_runes(&item["runes"])?,
+    })
+}
+
+impl AlkanesIndexer for MetashrewIndexer {
+    async fn token_info(&self, alkaneid: &str) -> Result<AlkaneTokenInfo, IndexerError> {
+        let name = self.simulate_text(alkaneid, OPCODE_NAME).await?;
+        let symbol = self.simulate_text(alkaneid, OPCODE_SYMBOL).await?;
+        if name.is_empty() && symbol.is_empty() {
+            return Err(IndexerError::NotFound(alkaneid.to_string()));
+        }
+        Ok(AlkaneTokenInfo {
+            alkaneid: alkaneid.to_string(),
+            name,
+            symbol,
+            // alkanes 没有链上精度字段，由 operator 在加白名单时给出
+            divisibility: None,
+        })
+    }
+
+    async fn balances(&self, address: &str) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        let mut totals: Vec<AlkaneBalance> = Vec::new();
+        for utxo in self.address_utxos(address).await? {
+            for balance in utxo.balances {
+                match totals.iter_mut().find(|t| t.alkaneid == balance.alkaneid) {
+                    Some(total) => {
+                        total.amount = total.amount.checked_add(balance.amount).ok_or_else(|| {
+                            IndexerError::Parse(format!("balance overflow for {}", balance.alkaneid))
+                        })?
+                    }
+                    None => totals.push(balance),
+                }
+            }
+        }
+        Ok(totals)
+    }
+
+    async fn address_utxos(&self, address: &str) -> Result<Vec<AlkaneUtxo>, IndexerError> {
+        let result = self
+            .call(
+                "alkanes_protorunesbyaddress",
+                json!([{ "address": address, "protocolTag": PROTOCOL_TAG }]),
+            )
+            .await?;
+        result["outpoints"]
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .map(parse_outpoint)
+                    .filter(|utxo| !matches!(utxo, Ok(u) if u.balances.is_empty()))
+                    .collect()
+            })
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+
+    async fn outpoint_balances(&self, txid: &str, vout: u32) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        let result = self
+            .call(
+                "alkanes_protorunesbyoutpoint",
+                json!([{ "txid": reversed_txid(txid)?, "vout": vout, "protocolTag": PROTOCOL_TAG }]),
+            )
+            .await?;
+        parse_runes(&result["runes"])
+    }
+
+    async fn trace(&self, txid: &str, vout: u32) -> Result<AlkaneTrace, IndexerError> {
+        let result = self
+            .call(
+                "alkanes_trace",
+                json!([{ "txid": reversed_txid(txid)?, "vout": vout }]),
+            )
+            .await?;
+        let events = result
+            .as_array()
+            .ok_or_else(|| IndexerError::Parse("trace is not an array".into()))?;
+        Ok(AlkaneTrace {
+            txid: txid.to_string(),
+            vout,
+            reverted: events
+                .iter()
+                .any(|e| e["event"] == "return" && e["data"]["status"] == "revert"),
+            events: events.iter().map(|e| e.to_string()).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_protorunes_outpoint_with_hex_ids() {
+        let item = json!({
+            "outpoint": { "txid": "ab", "vout": 1 },
+            "output": { "value": 546, "script": "5120" },
+            "runes": [
+                { "rune": { "id": { "block": "0x2", "tx": "0x1" } }, "balance": "0x64" },
+                { "rune": { "id": { "block": "2", "tx": "16" } }, "balance": "7" }
+            ]
+        });
+        let utxo = parse_outpoint(&item).unwrap();
+        assert_eq!(utxo.vout, 1);
+        assert_eq!(utxo.satoshi, 546);
+        assert_eq!(
+            utxo.balances,
+            vec![
+                AlkaneBalance { alkaneid: "2:1".into(), amount: 100 },
+                AlkaneBalance { alkaneid: "2:16".into(), amount: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outpoint_txid_round_trips_through_internal_byte_order() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let sent = reversed_txid(txid).unwrap();
+        assert_eq!(sent, "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a");
+        let item = json!({
+            "outpoint": { "txid": sent, "vout": 0 },
+            "output": { "value": 546 },
+            "runes": []
+        });
+        assert_eq!(parse_outpoint(&item).unwrap().txid, txid);
+        assert!(reversed_txid("zz").is_err());
+    }
+}
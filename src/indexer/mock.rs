@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use super::{AlkaneBalance, AlkaneTokenInfo, AlkaneTrace, AlkaneUtxo, AlkanesIndexer, IndexerError};
+
+#[derive(Default)]
+struct MockData {
+    tokens: HashMap<String, AlkaneTokenInfo>,
+    utxos: HashMap<String, Vec<AlkaneUtxo>>,
+    traces: HashMap<(String, u32), AlkaneTrace>,
+}
+
+thread_local! {
+    static DATA: RefCell<MockData> = RefCell::new(MockData::default());
+}
+
+/// In-memory indexer for tests and local replicas, seeded through the
+/// `mock_*` functions below (exposed to the owner as `seed_mock_indexer`).
+pub struct MockIndexer;
+
+pub fn mock_token(info: AlkaneTokenInfo) {
+    DATA.with(|d| d.borrow_mut().tokens.insert(info.alkaneid.clone(), info));
+}
+
+pub fn mock_address_utxos(address: &str, utxos: Vec<AlkaneUtxo>) {
+    DATA.with(|d| d.borrow_mut().utxos.insert(address.to_string(), utxos));
+}
+
+pub fn mock_trace(trace: AlkaneTrace) {
+    DATA.with(|d| d.borrow_mut().traces.insert((trace.txid.clone(), trace.vout), trace));
+}
+
+impl AlkanesIndexer for MockIndexer {
+    async fn token_info(&self, alkaneid: &str) -> Result<AlkaneTokenInfo, IndexerError> {
+        DATA.with(|d| d.borrow().tokens.get(alkaneid).cloned())
+            .ok_or_else(|| IndexerError::NotFound(alkaneid.to_string()))
+    }
+
+    async fn balances(&self, address: &str) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        let mut totals: Vec<AlkaneBalance> = Vec::new();
+        for utxo in self.address_utxos(address).await? {
+            for balance in utxo.balances {
+                match totals.iter_mut().find(|t| t.alkaneid == balance.alkaneid) {
+                    Some(total) => {
+                        total.amount = total.amount.checked_add(balance.amount).ok_or_else(|| {
+                            IndexerError::Parse(format!("balance overflow for {}", balance.alkaneid))
+                        })?
+                    }
+                    None => totals.push(balance),
+                }
+            }
+        }
+        Ok(totals)
+    }
+
+    async fn address_utxos(&self, address: &str) -> Result<Vec<AlkaneUtxo>, IndexerError> {
+        Ok(DATA.with(|d| d.borrow().utxos.get(address).cloned().unwrap_or_default()))
+    }
+
+    async fn outpoint_balances(&self, txid: &str, vout: u32) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        DATA.with(|d| {
+            d.borrow()
+                .utxos
+                .values()
+                .flatten()
+                .find(|u| u.txid == txid && u.vout == vout as u64)
+                .map(|u| u.balances.clone())
+        })
+        .ok_or_else(|| IndexerError::NotFound(format!("{}:{}", txid, vout)))
+    }
+
+    async fn trace(&self, txid: &str, vout: u32) -> Result<AlkaneTrace, IndexerError> {
+        DATA.with(|d| d.borrow().traces.get(&(txid.to_string(), vout)).cloned())
+            .ok_or_else(|| IndexerError::NotFound(format!("{}:{}", txid, vout)))
+    }
+}
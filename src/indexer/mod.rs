@@ -0,0 +1,239 @@
+use candid::{CandidType, Deserialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::ic::http::HttpError;
+
+pub mod metashrew;
+pub mod mock;
+pub mod unisat;
+
+pub use metashrew::MetashrewIndexer;
+pub use mock::MockIndexer;
+pub use unisat::UnisatIndexer;
+
+const DEFAULT_UNISAT_BASE_URL: &str = "https://open-api.unisat.io";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum IndexerProvider {
+    Unisat,
+    Metashrew,
+    Mock,
+}
+
+/// Which indexer the canister talks to. `unisat_api_key` is sent as a bearer
+/// token and never returned by `get_indexer_config`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IndexerConfig {
+    pub provider: IndexerProvider,
+    pub unisat_base_url: Option<String>,
+    pub unisat_api_key: Option<String>,
+    pub metashrew_url: Option<String>,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            provider: IndexerProvider::Unisat,
+            unisat_base_url: None,
+            unisat_api_key: None,
+            metashrew_url: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlkaneTokenInfo {
+    pub alkaneid: String,
+    pub name: String,
+    pub symbol: String,
+    /// `None` when the provider has no way to tell.
+    pub divisibility: Option<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlkaneBalance {
+    pub alkaneid: String,
+    pub amount: u128,
+}
+
+/// An output holding alkanes, with every alkane balance it carries.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlkaneUtxo {
+    pub txid: String,
+    pub vout: u64,
+    pub satoshi: u64,
+    pub balances: Vec<AlkaneBalance>,
+}
+
+/// Execution trace of the protostone at `txid:vout`; `events` are kept as the
+/// indexer returned them.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlkaneTrace {
+    pub txid: String,
+    pub vout: u32,
+    pub reverted: bool,
+    pub events: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum IndexerError {
+    Http(HttpError),
+    /// The provider answered but reported an error.
+    Api(String),
+    /// The response did not have the expected shape.
+    Parse(String),
+    /// The provider has no endpoint for this lookup.
+    Unsupported(String),
+    NotConfigured(String),
+    NotFound(String),
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexerError::Http(e) => write!(f, "{}", e),
+            IndexerError::Api(e) => write!(f, "API error: {}", e),
+            IndexerError::Parse(e) => write!(f, "Failed to parse indexer response: {}", e),
+            IndexerError::Unsupported(e) => write!(f, "Not supported by indexer: {}", e),
+            IndexerError::NotConfigured(e) => write!(f, "Indexer not configured: {}", e),
+            IndexerError::NotFound(e) => write!(f, "Not found: {}", e),
+        }
+    }
+}
+
+impl From<HttpError> for IndexerError {
+    fn from(e: HttpError) -> Self {
+        IndexerError::Http(e)
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait AlkanesIndexer {
+    async fn token_info(&self, alkaneid: &str) -> Result<AlkaneTokenInfo, IndexerError>;
+
+    /// Total alkane balances held by `address`.
+    async fn balances(&self, address: &str) -> Result<Vec<AlkaneBalance>, IndexerError>;
+
+    /// Every alkane-bearing UTXO of `address`.
+    async fn address_utxos(&self, address: &str) -> Result<Vec<AlkaneUtxo>, IndexerError>;
+
+    /// Alkane balances sitting on a single outpoint.
+    async fn outpoint_balances(&self, txid: &str, vout: u32) -> Result<Vec<AlkaneBalance>, IndexerError>;
+
+    async fn trace(&self, txid: &str, vout: u32) -> Result<AlkaneTrace, IndexerError>;
+}
+
+/// The configured provider, dispatched statically.
+pub enum Indexer {
+    Unisat(UnisatIndexer),
+    Metashrew(MetashrewIndexer),
+    Mock(MockIndexer),
+}
+
+impl AlkanesIndexer for Indexer {
+    async fn token_info(&self, alkaneid: &str) -> Result<AlkaneTokenInfo, IndexerError> {
+        match self {
+            Indexer::Unisat(i) => i.token_info(alkaneid).await,
+            Indexer::Metashrew(i) => i.token_info(alkaneid).await,
+            Indexer::Mock(i) => i.token_info(alkaneid).await,
+        }
+    }
+
+    async fn balances(&self, address: &str) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        match self {
+            Indexer::Unisat(i) => i.balances(address).await,
+            Indexer::Metashrew(i) => i.balances(address).await,
+            Indexer::Mock(i) => i.balances(address).await,
+        }
+    }
+
+    async fn address_utxos(&self, address: &str) -> Result<Vec<AlkaneUtxo>, IndexerError> {
+        match self {
+            Indexer::Unisat(i) => i.address_utxos(address).await,
+            Indexer::Metashrew(i) => i.address_utxos(address).await,
+            Indexer::Mock(i) => i.address_utxos(address).await,
+        }
+    }
+
+    async fn outpoint_balances(&self, txid: &str, vout: u32) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        match self {
+            Indexer::Unisat(i) => i.outpoint_balances(txid, vout).await,
+            Indexer::Metashrew(i) => i.outpoint_balances(txid, vout).await,
+            Indexer::Mock(i) => i.outpoint_balances(txid, vout).await,
+        }
+    }
+
+    async fn trace(&self, txid: &str, vout: u32) -> Result<AlkaneTrace, IndexerError> {
+        match self {
+            Indexer::Unisat(i) => i.trace(txid, vout).await,
+            Indexer::Metashrew(i) => i.trace(txid, vout).await,
+            Indexer::Mock(i) => i.trace(txid, vout).await,
+        }
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<IndexerConfig> = RefCell::new(IndexerConfig::default());
+}
+
+pub fn config() -> IndexerConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+pub fn set_config(config: IndexerConfig) -> Result<(), String> {
+    if config.provider == IndexerProvider::Metashrew && config.metashrew_url.is_none() {
+        return Err("metashrew_url is required for the Metashrew provider".into());
+    }
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    Ok(())
+}
+
+/// Builds the indexer selected in the current config.
+pub fn configured() -> Result<Indexer, IndexerError> {
+    let config = config();
+    match config.provider {
+        IndexerProvider::Unisat => Ok(Indexer::Unisat(UnisatIndexer {
+            base_url: config
+                .unisat_base_url
+                .unwrap_or_else(|| DEFAULT_UNISAT_BASE_URL.to_string()),
+            api_key: config.unisat_api_key,
+        })),
+        IndexerProvider::Metashrew => config
+            .metashrew_url
+            .map(|url| Indexer::Metashrew(MetashrewIndexer { url }))
+            .ok_or_else(|| IndexerError::NotConfigured("metashrew_url".into())),
+        IndexerProvider::Mock => Ok(Indexer::Mock(MockIndexer)),
+    }
+}
+
+/// Indexers encode amounts and ids as JSON numbers, decimal strings or
+/// `0x`-prefixed hex strings.
+pub(crate) fn parse_u128(value: &Value) -> Option<u128> {
+    match value {
+        Value::Number(n) => n.as_u64().map(u128::from),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some("") => Some(0),
+            Some(hex) => u128::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_u128_accepts_numbers_decimal_and_hex_strings() {
+        assert_eq!(parse_u128(&json!(42)), Some(42));
+        assert_eq!(parse_u128(&json!("340282366920938463463374607431768211455")), Some(u128::MAX));
+        assert_eq!(parse_u128(&json!("0x1f")), Some(31));
+        assert_eq!(parse_u128(&json!("0x")), Some(0));
+        assert_eq!(parse_u128(&json!("abc")), None);
+        assert_eq!(parse_u128(&json!(null)), None);
+    }
+}
@@ -0,0 +1,175 @@
+use serde_json::Value;
+
+use super::{parse_u128, AlkaneBalance, AlkaneTokenInfo, AlkaneUtxo, AlkanesIndexer, IndexerError};
+use crate::ic::http::{send_json, JsonRequest};
+
+const PAGE_SIZE: u64 = 100;
+const MAX_RESPONSE_BYTES: u64 = 200_000;
+
+/// Unisat open API. Endpoints follow `https://open-api.unisat.io/v1/indexer/...`.
+pub struct UnisatIndexer {
+    pub base_url: String,
+    pub api_key: Option<String>,
+}
+
+impl UnisatIndexer {
+    /// GETs `path` and returns the `data` field after checking `code == 0`.
+    async fn get(&self, path: &str, keep: &[&str]) -> Result<Value, IndexerError> {
+        let mut request = JsonRequest::get(format!("{}{}", self.base_url, path), MAX_RESPONSE_BYTES);
+        if let Some(key) = &self.api_key {
+            request = request.header("Authorization", &format!("Bearer {}", key));
+        }
+        if !keep.is_empty() {
+            request = request.keep(&[&["/code", "/msg"], keep].concat());
+        }
+        let json = send_json(&request).await?;
+        if json["code"].as_i64() != Some(0) {
+            return Err(IndexerError::Api(
+                json["msg"].as_str().unwrap_or("Unknown error").to_string(),
+            ));
+        }
+        Ok(json["data"].clone())
+    }
+
+    async fn alkane_utxos(&self, address: &str, alkaneid: &str) -> Result<Vec<AlkaneUtxo>, IndexerError> {
+        let mut utxos = Vec::new();
+        let mut start = 0;
+        loop {
+            let data = self
+                .get(
+                    &format!(
+                        "/v1/indexer/address/{}/alkanes/{}/utxo?start={}&limit={}",
+                        address, alkaneid, start, PAGE_SIZE
+                    ),
+                    &[],
+                )
+                .await?;
+            let page = data["utxo"].as_array().cloned().unwrap_or_default();
+            for item in &page {
+                utxos.push(parse_utxo(item)?);
+            }
+            start += PAGE_SIZE;
+            if (page.len() as u64) < PAGE_SIZE || start >= data["total"].as_u64().unwrap_or(0) {
+                return Ok(utxos);
+            }
+        }
+    }
+}
+
+fn parse_balance(item: &Value) -> Result<AlkaneBalance, IndexerError> {
+    Ok(AlkaneBalance {
+        alkaneid: item["alkaneid"]
+            .as_str()
+            .ok_or_else(|| IndexerError::Parse("missing alkaneid".into()))?
+            .to_string(),
+        amount: parse_u128(&item["amount"])
+            .ok_or_else(|| IndexerError::Parse("missing amount".into()))?,
+    })
+}
+
+fn parse_utxo(item: &Value) -> Result<AlkaneUtxo, IndexerError> {
+    Ok(AlkaneUtxo {
+        txid: item["txid"]
+            .as_str()
+            .ok_or_else(|| IndexerError::Parse("missing txid".into()))?
+            .to_string(),
+        vout: item["vout"]
+            .as_u64()
+            .ok_or_else(|| IndexerError::Parse("missing vout".into()))?,
+        satoshi: item["satoshi"].as_u64().unwrap_or(0),
+        balances: item["alkanes"]
+            .as_array()
+            .map(|alkanes| alkanes.iter().map(parse_balance).collect::<Result<Vec<_>, _>>())
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+impl AlkanesIndexer for UnisatIndexer {
+    async fn token_info(&self, alkaneid: &str) -> Result<AlkaneTokenInfo, IndexerError> {
+        let data = self
+            .get(
+                &format!("/v1/indexer/alkanes/{}/info", alkaneid),
+                &[
+                    "/data/tokenData/symbol",
+                    "/data/tokenData/name",
+                    "/data/tokenData/divisibility",
+                ],
+            )
+            .await?;
+        let token_data = data["tokenData"]
+            .as_object()
+            .ok_or_else(|| IndexerError::Parse("Missing tokenData field".into()))?;
+        Ok(AlkaneTokenInfo {
+            alkaneid: alkaneid.to_string(),
+            symbol: token_data["symbol"]
+                .as_str()
+                .ok_or_else(|| IndexerError::Parse("Missing symbol field".into()))?
+                .to_string(),
+            name: token_data["name"]
+                .as_str()
+                .ok_or_else(|| IndexerError::Parse("Missing name field".into()))?
+                .to_string(),
+            divisibility: Some(
+                token_data
+                    .get("divisibility")
+                    .and_then(|d| d.as_u64())
+                    .and_then(|d| u8::try_from(d).ok())
+                    .ok_or_else(|| IndexerError::Parse("Missing or invalid divisibility field".into()))?,
+            ),
+        })
+    }
+
+    async fn balances(&self, address: &str) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        let mut balances = Vec::new();
+        let mut start = 0;
+        loop {
+            let data = self
+                .get(
+                    &format!(
+                        "/v1/indexer/address/{}/alkanes/token-list?start={}&limit={}",
+                        address, start, PAGE_SIZE
+                    ),
+                    &[],
+                )
+                .await?;
+            let page = data["detail"].as_array().cloned().unwrap_or_default();
+            for item in &page {
+                balances.push(parse_balance(item)?);
+            }
+            start += PAGE_SIZE;
+            if (page.len() as u64) < PAGE_SIZE || start >= data["total"].as_u64().unwrap_or(0) {
+                return Ok(balances);
+            }
+        }
+    }
+
+    /// Unisat lists UTXOs per alkane, so outpoints holding several alkanes are
+    /// merged back into one entry.
+    async fn address_utxos(&self, address: &str) -> Result<Vec<AlkaneUtxo>, IndexerError> {
+        let mut merged: Vec<AlkaneUtxo> = Vec::new();
+        for balance in self.balances(address).await? {
+            for utxo in self.alkane_utxos(address, &balance.alkaneid).await? {
+                match merged.iter_mut().find(|u| u.txid == utxo.txid && u.vout == utxo.vout) {
+                    Some(existing) => {
+                        for b in utxo.balances {
+                            if !existing.balances.iter().any(|e| e.alkaneid == b.alkaneid) {
+                                existing.balances.push(b);
+                            }
+                        }
+                    }
+                    None => merged.push(utxo),
+                }
+            }
+        }
+        Ok(merged)
+    }
+
+    async fn outpoint_balances(&self, _txid: &str, _vout: u32) -> Result<Vec<AlkaneBalance>, IndexerError> {
+        Err(IndexerError::Unsupported("unisat outpoint lookup".into()))
+    }
+
+    async fn trace(&self, _txid: &str, _vout: u32) -> Result<super::AlkaneTrace, IndexerError> {
+        Err(IndexerError::Unsupported("unisat trace lookup".into()))
+    }
+}
@@ -1,11 +1,10 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::caller;
-use ic_cdk::api::management_canister::http_request::{http_request, CanisterHttpRequestArgument};
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
-use serde_json::Value;
-use ic_cdk::api::time;
+// export_candid! 需要 transform 的参数类型在此作用域内
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use ic_cdk_timers::set_timer_interval;
 use std::time::Duration;
 
@@ -13,12 +12,11 @@ mod ic;
 use ic_cdk::api::management_canister::bitcoin::{
     bitcoin_get_balance, bitcoin_get_current_fee_percentiles, bitcoin_get_utxos,
     bitcoin_send_transaction, BitcoinNetwork, GetBalanceRequest, GetCurrentFeePercentilesRequest,
-    GetUtxosRequest, GetUtxosResponse, MillisatoshiPerByte, SendTransactionRequest,
+    GetUtxosRequest, GetUtxosResponse, MillisatoshiPerByte, SendTransactionRequest, Utxo, UtxoFilter,
 };
 use ic_cdk::api::management_canister::schnorr::{
     SchnorrAlgorithm, SchnorrKeyId, SignWithSchnorrArgument,
 };
-use ic::bitcoin_api::send_transaction;
 use bitcoin::{
     key::{PublicKey, Secp256k1},
     secp256k1::{schnorr, XOnlyPublicKey},
@@ -29,6 +27,20 @@ use bitcoin::{
 mod psbt;
 mod alkanes;
 mod did;
+mod indexer;
+mod access;
+mod proposals;
+mod pause;
+mod reserves;
+mod events;
+mod metrics;
+mod budget;
+mod error;
+mod chain;
+mod gather;
+mod txplan;
+mod conversion;
+mod fees;
 
 pub use psbt::{
     builder::PsbtBuilder,
@@ -41,18 +53,62 @@ use crate::alkanes::alkanes_data::alkanes_protostone::{Protostone, Edict, RuneId
 use crate::alkanes::alkanes_storage::{
     add_white_token, batch_upload, clear, get_all, get_white_tokens,
     init as storage_init, is_white_token, post_upgrade as storage_post_upgrade,
-    pre_upgrade as storage_pre_upgrade, remove_white_token, alkanes_query, set_owner, AlkaneRecord,
+    pre_upgrade as storage_pre_upgrade, remove_white_token, alkanes_query, get_owner, AlkaneRecord,
     set_token_id_mapping, get_token_id_by_alkaneid, get_alkane_fund_utxo, get_all_utxos, 
-    AlkaneUtxoRecord, get_utxos_by_address, set_utxo, remove_utxo, get_utxos_by_alkaneid, utxo_count
+    AlkaneUtxoRecord, get_utxos_by_address, set_utxo, delete_utxo, get_utxos_by_alkaneid, utxo_count,
+    take_utxos, put_utxo, count, query_utxos, get_outpoints_by_address, UtxoPage, UtxoQuery,
+    get_token_config, get_token_configs, set_token_config,
+};
+use crate::conversion::{FeeDestination, TokenConfig};
+use crate::fees::{FeeState, TreasuryAccount};
+use crate::proposals::{ApprovalConfig, ApprovalConfigV1, Proposal, ProposalAction};
+use crate::pause::{PauseState, PauseSwitch};
+use crate::reserves::{AlkaneReserve, ReserveCounters, ReserveCountersV1};
+use crate::events::{Event, EventFilter, EventKind, EventV1, RetentionConfig};
+use crate::metrics::{HttpGatewayRequest, HttpGatewayResponse, Metrics, OperationStats};
+use crate::budget::{BudgetConfig, BudgetStatus, DailySpend, RateScope};
+use crate::gather::{GatherPolicy, GatherPolicyV1, GatherReport};
+use crate::txplan::{OutputPlan, PlannedInput, PlannedOutput, TxPlan, TxSimulation};
+use crate::chain::TxStatus;
+use crate::error::{
+    FomowellError, OwnerReceipt, ProposalReceipt, TokenReceipt, TopupReceipt, WithdrawReceipt,
+};
+use crate::access::{
+    guard_admin, guard_admin_or_auditor, guard_operator, guard_operator_or_auditor, guard_owner,
+    guard_pending_owner, guard_uploader, guard_withdraw_caller, Role,
 };
 
 use crate::did::fomowell_token::{CreateMemeTokenArg, MemeTokenType, Service, InternalTransferArg, Account, LedgerType};
 use crate::did::user_canister_did::Service as UserCanisterService;
+use crate::alkanes::alkanes_sync::{self, LedgerDiff};
+use crate::indexer::{AlkaneBalance, AlkaneTokenInfo, AlkaneTrace, AlkaneUtxo, AlkanesIndexer, IndexerConfig};
 
 
+// ic_cdk::api::time 在 canister 外会 panic，单元测试里从 0 开始，由测试拨动
+#[cfg(not(test))]
+pub(crate) use ic_cdk::api::time;
+#[cfg(test)]
+thread_local! {
+    static TEST_TIME: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+#[cfg(test)]
+pub(crate) fn time() -> u64 {
+    TEST_TIME.with(|t| t.get())
+}
+#[cfg(test)]
+pub(crate) fn set_test_time(nanos: u64) {
+    TEST_TIME.with(|t| t.set(nanos));
+}
+
 const IC_BITCOIN_NETWORK: ic_cdk::api::management_canister::bitcoin::BitcoinNetwork =
     ic_cdk::api::management_canister::bitcoin::BitcoinNetwork::Testnet;
 
+// bitcoin_get_utxos 附带的 cycles，由 ic-cdk 按网络收取
+const GET_UTXOS_CYCLES: u128 = match IC_BITCOIN_NETWORK {
+    ic_cdk::api::management_canister::bitcoin::BitcoinNetwork::Mainnet => 10_000_000_000,
+    _ => 4_000_000_000,
+};
+
 const SCHNORR_KEY_NAME: &str = "test_key_1";
 const FOMOWELLL_MAINNET_CANISTER_ID: &str = "fw4iq-diaaa-aaaah-arela-cai";
 const FEE_RATE_CANISTER_ID: &str = "kqs64-paaaa-aaaar-qamza-cai";
@@ -61,11 +117,24 @@ const fomowell_alkanes_topup_address: &str = "fomowell-alkanes-topup-address";
 const fomowell_alkanes_fund_address: &str = "fomowell-alkanes-fund-address";
 const fomowell_btc_address: &str = "fomowell-btc-address";
 
+// 默认允许的未确认交易链深度，1 表示只花费已确认的 fund utxo
+const DEFAULT_MAX_UNCONFIRMED_DEPTH: u32 = 3;
+const DEFAULT_MIN_CONFIRMATIONS: u32 = 1;
+// alkane 输出（提现、fund 找零、归集）带的 sats
+const DEFAULT_POSTAGE: u64 = txplan::DUST_LIMIT;
+const LEDGER_SYNC_INTERVAL_SECS: u64 = 1800;
+// 刚广播的交易 mempool.space 可能还查不到，超过这个时间仍查不到才算被丢弃
+const DROPPED_AFTER_SECS: u64 = 6 * 3600;
+const GATHER_INTERVAL_SECS: u64 = 3600;
 
 thread_local! {
     static ADDRESSES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
     static PROCESSED_TRANSACTIONS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
-    static PENDING_TX_ID: RefCell<Option<String>> = RefCell::new(None); //假设一笔
+    static PENDING_TXS: RefCell<Vec<PendingTx>> = const { RefCell::new(Vec::new()) };
+    static MAX_UNCONFIRMED_DEPTH: RefCell<u32> = const { RefCell::new(DEFAULT_MAX_UNCONFIRMED_DEPTH) };
+    static MIN_CONFIRMATIONS: RefCell<u32> = const { RefCell::new(DEFAULT_MIN_CONFIRMATIONS) };
+    static HTTP_CROSS_CHECK: RefCell<bool> = const { RefCell::new(false) };
+    static POSTAGE: RefCell<u64> = const { RefCell::new(DEFAULT_POSTAGE) };
     static WITHDRAW_REQUESTS: RefCell<HashMap<Principal, Vec<WithdrawRequest>>> = RefCell::new(HashMap::new());
     static LOGS: RefCell<Vec<LogEntry>> = RefCell::new(Vec::new());
 }
@@ -74,43 +143,188 @@ thread_local! {
 async fn init() {
     let owner: Principal = Principal::from_text("tvz33-ke3fp-pkev4-7zlcz-e6la2-nuoxp-ogkve-udz64-65zrs-rr34c-5qe").unwrap();
     storage_init(owner);
+    access::insert_role(fomowell_canister(), Role::WithdrawCaller);
 
-    let in_fomowell_alkanes_topup_address = generate_address(fomowell_alkanes_topup_address.to_string()).await;
-    let in_fomowell_alkans_fund_address = generate_address(fomowell_alkanes_fund_address.to_string()).await;
-    let in_fomowell_btc_address = generate_address(fomowell_btc_address.to_string()).await;
+    if let Err(e) = derive_addresses().await {
+        append_log(format!("[address] derive failed, retried by the next timer: {}", e));
+    }
+    start_timers();
+}
 
-    ADDRESSES.with(|addrs| {
-        let mut map = addrs.borrow_mut();
-        map.insert("alkanes_topup".to_string(), in_fomowell_alkanes_topup_address);
-        map.insert("alkanes_fund".to_string(), in_fomowell_alkans_fund_address);
-        map.insert("btc".to_string(), in_fomowell_btc_address);
-    });
-    set_timer_interval(Duration::from_secs(36000), || {
+// 升级后 timer 会被清空，init 和 post_upgrade 都要重新注册
+fn start_timers() {
+    set_timer_interval(Duration::from_secs(GATHER_INTERVAL_SECS), || {
         ic_cdk::spawn(gather_alkanes_utxo_timer());
     });
     set_timer_interval(Duration::from_secs(7200), || {
+        budget::check_balance();
         ic_cdk::spawn(check_withdraw_request());
     });
+    // 对账后账本最新，紧接着做一次偿付检查
+    set_timer_interval(Duration::from_secs(LEDGER_SYNC_INTERVAL_SECS), || {
+        ic_cdk::spawn(async {
+            let _ = sync_utxo_ledger().await;
+            check_solvency("periodic");
+        });
+    });
+}
+
+
+/// State owned by this file that has to survive upgrades. Every field is
+/// optional so that snapshots written by older versions keep decoding.
+#[derive(CandidType, Deserialize, Default)]
+struct RuntimeState {
+    // 金额改成 u128 之前的格式，只在升级时读取
+    pending_txs: Option<Vec<PendingTxV1>>,
+    withdraw_requests: Option<Vec<(Principal, Vec<WithdrawRequestV1>)>>,
+    max_unconfirmed_depth: Option<u32>,
+    min_confirmations: Option<u32>,
+    http_cross_check: Option<bool>,
+    indexer_config: Option<IndexerConfig>,
+    roles: Option<Vec<(Principal, Vec<Role>)>>,
+    pending_owner: Option<Principal>,
+    approval_config: Option<ApprovalConfigV1>,
+    proposals: Option<Vec<Proposal>>,
+    pause_state: Option<PauseState>,
+    // 旧版本记录的已入账未提现数量，只在升级时读取
+    credited: Option<Vec<(String, u64)>>,
+    reserves: Option<Vec<(String, ReserveCountersV1)>>,
+    events: Option<Vec<EventV1>>,
+    event_retention: Option<RetentionConfig>,
+    operation_stats: Option<Vec<OperationStats>>,
+    budget_config: Option<BudgetConfig>,
+    budget_spent: Option<DailySpend>,
+    gather_policy: Option<GatherPolicyV1>,
+    postage: Option<u64>,
+    pending: Option<Vec<PendingTx>>,
+    withdraw_queue: Option<Vec<(Principal, Vec<WithdrawRequest>)>>,
+    approval: Option<ApprovalConfig>,
+    reserve_counters: Option<Vec<(String, ReserveCounters)>>,
+    event_log: Option<Vec<Event>>,
+    gather: Option<GatherPolicy>,
+    fees: Option<FeeState>,
+    fee_sweep_seq: Option<u64>,
 }
 
+fn fomowell_canister() -> Principal {
+    Principal::from_text(FOMOWELLL_MAINNET_CANISTER_ID).unwrap()
+}
 
 #[pre_upgrade]
 fn pre_upgrade_hook() {
-    storage_pre_upgrade();
+    let runtime = RuntimeState {
+        pending_txs: None,
+        withdraw_requests: None,
+        max_unconfirmed_depth: Some(MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow())),
+        min_confirmations: Some(MIN_CONFIRMATIONS.with(|c| *c.borrow())),
+        http_cross_check: Some(HTTP_CROSS_CHECK.with(|c| *c.borrow())),
+        indexer_config: Some(indexer::config()),
+        roles: Some(access::list_roles()),
+        pending_owner: access::pending_owner(),
+        approval_config: None,
+        proposals: Some(proposals::export()),
+        pause_state: Some(pause::state()),
+        credited: None,
+        reserves: None,
+        events: None,
+        event_retention: Some(events::retention()),
+        operation_stats: Some(metrics::operations()),
+        budget_config: Some(budget::config()),
+        budget_spent: Some(budget::today()),
+        gather_policy: None,
+        postage: Some(postage()),
+        pending: Some(PENDING_TXS.with(|p| p.borrow().clone())),
+        withdraw_queue: Some(WITHDRAW_REQUESTS.with(|r| {
+            r.borrow().iter().map(|(k, v)| (*k, v.clone())).collect()
+        })),
+        approval: Some(proposals::config()),
+        reserve_counters: Some(reserves::export()),
+        event_log: Some(events::export()),
+        gather: Some(gather::policy()),
+        fees: Some(fees::state()),
+        fee_sweep_seq: Some(fees::sweep_seq()),
+    };
+    storage_pre_upgrade(runtime);
 }
 
 #[post_upgrade]
 async fn post_upgrade_hook() {
-    storage_post_upgrade();
-    let in_fomowell_alkanes_topup_address = generate_address(fomowell_alkanes_topup_address.to_string()).await;
-    let in_fomowell_alkanes_fund_address = generate_address(fomowell_alkanes_fund_address.to_string()).await;
-    let in_fomowell_btc_address = generate_address(fomowell_btc_address.to_string()).await;
-    ADDRESSES.with(|addrs| {
-        let mut map = addrs.borrow_mut();
-        map.insert("alkanes_topup".to_string(), in_fomowell_alkanes_topup_address);
-        map.insert("alkanes_fund".to_string(), in_fomowell_alkanes_fund_address);
-        map.insert("btc".to_string(), in_fomowell_btc_address);
-    });
+    if let Some(runtime) = storage_post_upgrade::<RuntimeState>() {
+        let pending = runtime
+            .pending
+            .or_else(|| runtime.pending_txs.map(|txs| txs.into_iter().map(PendingTx::from).collect()));
+        if let Some(pending) = pending {
+            PENDING_TXS.with(|p| *p.borrow_mut() = pending);
+        }
+        let requests = runtime.withdraw_queue.or_else(|| {
+            runtime.withdraw_requests.map(|queues| {
+                queues
+                    .into_iter()
+                    .map(|(pid, queue)| (pid, queue.into_iter().map(WithdrawRequest::from).collect()))
+                    .collect()
+            })
+        });
+        if let Some(requests) = requests {
+            WITHDRAW_REQUESTS.with(|r| *r.borrow_mut() = requests.into_iter().collect());
+        }
+        if let Some(depth) = runtime.max_unconfirmed_depth {
+            MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow_mut() = depth);
+        }
+        if let Some(confirmations) = runtime.min_confirmations {
+            MIN_CONFIRMATIONS.with(|c| *c.borrow_mut() = confirmations);
+        }
+        if let Some(cross_check) = runtime.http_cross_check {
+            HTTP_CROSS_CHECK.with(|c| *c.borrow_mut() = cross_check);
+        }
+        if let Some(postage) = runtime.postage {
+            POSTAGE.with(|p| *p.borrow_mut() = postage);
+        }
+        if let Some(config) = runtime.indexer_config {
+            let _ = indexer::set_config(config);
+        }
+        // 旧版本没有角色表，保留原先硬编码的 fomowell canister 提现权限
+        let migrate_roles = runtime.roles.is_none();
+        access::restore(runtime.roles.unwrap_or_default(), runtime.pending_owner);
+        if migrate_roles {
+            access::insert_role(fomowell_canister(), Role::WithdrawCaller);
+        }
+        // 金额改成 u128 之前提交的提案解不出来，升级后需要重新提交
+        let approval = runtime.approval.or_else(|| runtime.approval_config.map(ApprovalConfig::from));
+        proposals::restore(approval.unwrap_or_default(), runtime.proposals.unwrap_or_default());
+        if let Some(state) = runtime.pause_state {
+            pause::restore(state);
+        }
+        let events = runtime
+            .event_log
+            .or_else(|| runtime.events.map(|events| events.into_iter().map(Event::from).collect()));
+        events::restore(events.unwrap_or_default(), runtime.event_retention.unwrap_or_default());
+        metrics::restore(runtime.operation_stats.unwrap_or_default());
+        budget::restore(runtime.budget_config.unwrap_or_default(), runtime.budget_spent.unwrap_or_default());
+        let policy = runtime.gather.or_else(|| runtime.gather_policy.map(GatherPolicy::from));
+        gather::restore(policy.unwrap_or_default());
+        fees::restore(runtime.fees.unwrap_or_default(), runtime.fee_sweep_seq.unwrap_or(0));
+        match (runtime.reserve_counters, runtime.reserves, runtime.credited) {
+            (Some(counters), _, _) => reserves::restore(counters),
+            (None, Some(counters), _) => {
+                reserves::restore(counters.into_iter().map(|(alkaneid, c)| (alkaneid, c.into())).collect())
+            }
+            (None, None, Some(credited)) => reserves::restore(
+                credited
+                    .into_iter()
+                    .map(|(alkaneid, outstanding)| {
+                        (alkaneid, ReserveCounters { credited: outstanding.into(), withdrawn: 0 })
+                    })
+                    .collect(),
+            ),
+            (None, None, None) => {}
+        }
+    } else {
+        access::insert_role(fomowell_canister(), Role::WithdrawCaller);
+    }
+    if let Err(e) = derive_addresses().await {
+        append_log(format!("[address] derive failed, retried by the next timer: {}", e));
+    }
+    start_timers();
 }
 #[derive(CandidType, Deserialize, Clone)]
 pub struct EdictInput {
@@ -171,100 +385,169 @@ pub fn generate_protostone(edicts: Vec<EdictInput>) -> (ScriptBuf) {
 }
 
 #[update]
-async fn topup_alkanes(txid: String) -> Result<String, String> {
+async fn topup_alkanes(txid: String) -> Result<TopupReceipt, FomowellError> {
+    // 超限的调用直接拒绝，不写事件，避免刷屏
+    budget::check_rate(RateScope::Topup, caller())?;
+    let result = credit_topup(txid.clone()).await;
+    metrics::record("topup", result.is_ok(), 0);
+    if let Err(e) = &result {
+        events::event(EventKind::TopupRejected).principal(caller()).txid(txid).detail(e.to_string()).record();
+    }
+    result
+}
+
+async fn credit_topup(txid: String) -> Result<TopupReceipt, FomowellError> {
+    pause::ensure_running(PauseSwitch::Topup)?;
     if PROCESSED_TRANSACTIONS.with(|set| set.borrow().contains(&txid)) {
-        return Ok("Transaction already processed".into());
+        return Err(FomowellError::AlreadyProcessed { txid });
     }// 防止重放攻击
-    match get_alkane(txid.clone()) {
-        Ok(record) => {
-            let user_canister_id = Principal::from_text("a7ady-jiaaa-aaaah-arexa-cai")
-                .map_err(|e| format!("Invalid canister ID: {}", e))?;
-            
-            let user_service = UserCanisterService(user_canister_id);
-            
-            let mut all_deposits = Vec::new();
-            let page_size = 500u64;
-            let mut offset = 0u64;
-            
-            loop {
-                match user_service.query_list_deposits_paginated(offset, offset + page_size).await {
-                    Ok((deposits,)) => {
-                        if deposits.is_empty() {
-                            break;
-                        }
-                        all_deposits.extend(deposits);
-                        offset += page_size;
-                    }
-                    Err((code, msg)) => {
-                        return Err(format!("Failed to query deposits: {:?}, {}", code, msg));
-                    }
-                }
-            }
-            
-            let caller_pid = caller();
-            let found_deposit = all_deposits.iter().find(|d| d.pid == caller_pid);
-            
-            match found_deposit {
-                Some(deposit) => {
-                    if deposit.address != record.send_address {
-                        return Err(format!("BTC address mismatch: expected {}, got {}", record.send_address, deposit.address));
-                    }
-                    append_log(format!("[topup] BTC address  txid={} caller_pid={} address={}", 
-                        txid, caller_pid, deposit.address));
-                }
-                None => {
-                    append_log(format!("[topup] Caller deposit not found txid={} caller_pid={}", txid, caller_pid));
-                    return Err(format!("Caller deposit not found for principal {}", caller_pid));
-                }
+    let record = get_alkane(txid.clone()).inspect_err(|e| {
+        append_log(format!("[topup] record not found txid={} err={}", txid, e));
+    })?;
+    let config = get_token_config(&record.alkaneid);
+    let amounts = config.topup(record.amount).map_err(FomowellError::InvalidArgument)?;
+
+    let user_canister_id = Principal::from_text("a7ady-jiaaa-aaaah-arexa-cai")
+        .map_err(|e| format!("Invalid canister ID: {}", e))?;
+
+    let user_service = UserCanisterService(user_canister_id);
+
+    let mut all_deposits = Vec::new();
+    let page_size = 500u64;
+    let mut offset = 0u64;
+
+    loop {
+        let (deposits,) = user_service
+            .query_list_deposits_paginated(offset, offset + page_size)
+            .await
+            .map_err(|e| error::call_failed("query_list_deposits_paginated", e))?;
+        if deposits.is_empty() {
+            break;
+        }
+        all_deposits.extend(deposits);
+        offset += page_size;
+    }
+
+    let caller_pid = caller();
+    let found_deposit = all_deposits.iter().find(|d| d.pid == caller_pid);
+
+    match found_deposit {
+        Some(deposit) => {
+            if deposit.address != record.send_address {
+                return Err(FomowellError::AddressMismatch {
+                    expected: record.send_address.clone(),
+                    actual: deposit.address.clone(),
+                });
             }
+            append_log(format!("[topup] BTC address  txid={} caller_pid={} address={}",
+                txid, caller_pid, deposit.address));
+        }
+        None => {
+            append_log(format!("[topup] Caller deposit not found txid={} caller_pid={}", txid, caller_pid));
+            return Err(FomowellError::NotFound(format!("deposit of principal {}", caller_pid)));
+        }
+    }
+
+    let meme_token_id = get_token_id_by_alkaneid(record.alkaneid.clone())
+        .map_err(|e| FomowellError::NotFound(format!("meme token for {}: {}", record.alkaneid, e)))?;
+    let token_canister_id = Principal::from_text(FOMOWELLL_MAINNET_CANISTER_ID)
+        .map_err(|e| format!("Invalid canister ID: {}", e))?;
+    let service = Service(token_canister_id);
+
+    let to_account = Account {
+        owner: caller(),
+        subaccount: None,
+    };
+
+    let transfer_arg = InternalTransferArg {
+        to: to_account,
+        lock_id: None,
+        subaccount: None,
+        ledger_type: LedgerType::MemeToken(meme_token_id),
+        amount: candid::Nat::from(amounts.meme_amount),
+    };
+
+    match service.internal_transfer(transfer_arg).await {
+        Ok((crate::did::fomowell_token::Result1::Ok,)) => {
+            PROCESSED_TRANSACTIONS.with(|set| set.borrow_mut().insert(txid.clone()));
+            append_log(format!(
+                "[topup] success txid={} amount={} fee={} meme_amount={} alkaneid={}",
+                txid, record.amount, amounts.fee, amounts.meme_amount, record.alkaneid
+            ));
+            reserves::record_credit(&record.alkaneid, amounts.credited);
+            let fee_destination = collect_topup_fee(&service, &config, &record.alkaneid, meme_token_id, amounts.fee).await;
+            events::event(EventKind::TopupCredited)
+                .principal(caller())
+                .txid(txid.clone())
+                .alkaneid(record.alkaneid.clone())
+                .amount(amounts.credited)
+                .detail(format!("fee={} to {} meme_amount={}", amounts.fee, fee_destination, amounts.meme_amount))
+                .record();
+            check_solvency("topup");
+            Ok(TopupReceipt {
+                txid,
+                amount: record.amount,
+                fee: amounts.fee,
+                meme_amount: amounts.meme_amount,
+                alkaneid: record.alkaneid,
+                meme_token_id,
+            })
+        }
+        Ok((crate::did::fomowell_token::Result1::Err(e),)) => {
+            append_log(format!("[topup] internal_transfer failed txid={} error={}", txid, e));
+            Err(FomowellError::LedgerCallFailed { code: "Rejected".into(), message: e })
+        }
+        Err((code, message)) => {
+            append_log(format!("[topup] call internal_transfer error txid={} code={:?} err={}", txid, code, message));
+            Err(FomowellError::LedgerCallFailed { code: format!("{:?}", code), message })
+        }
+    }
+}
 
-            let meme_token_id = get_token_id_by_alkaneid(record.alkaneid.clone())
-                .map_err(|e| format!("Failed to get meme_token_id: {}", e))?;
-            let token_canister_id = Principal::from_text(FOMOWELLL_MAINNET_CANISTER_ID)
-                .map_err(|e| format!("Invalid canister ID: {}", e))?;
-            let service = Service(token_canister_id);
-            
-            let to_account = Account {
-                owner: caller(),
-                subaccount: None,
-            };
-            
-            let transfer_arg = InternalTransferArg {
-                to: to_account,
-                lock_id: None,
-                subaccount: None,
-                ledger_type: LedgerType::MemeToken(meme_token_id),
-                amount: candid::Nat::from(record.amount),
-            };
-            
-            match service.internal_transfer(transfer_arg).await {
-                Ok((result,)) => {
-                    match result {
-                        crate::did::fomowell_token::Result1::Ok => {
-                            PROCESSED_TRANSACTIONS.with(|set| set.borrow_mut().insert(txid.clone()));
-                            append_log(format!("[topup] success txid={} amount={} alkaneid={}", txid, record.amount, record.alkaneid));
-                            Ok(format!("Topup successful: {} tokens transferred for txid {}", record.amount, txid))
-                        }
-                        crate::did::fomowell_token::Result1::Err(e) => {
-                            append_log(format!("[topup] internal_transfer failed txid={} error={}", txid, e));
-                            Err(format!("Internal transfer failed: {}", e))
-                        }
+/// Credits a topup fee to the treasury as meme tokens. The fee stays in the
+/// fund as alkanes instead when the token keeps its fees there, no treasury
+/// is set, the fee converts to nothing or the transfer fails. Returns where
+/// it went.
+async fn collect_topup_fee(service: &Service, config: &TokenConfig, alkaneid: &str, meme_token_id: u64, fee: u128) -> String {
+    if fee == 0 {
+        return "none".into();
+    }
+    if config.fee_destination == Some(FeeDestination::Treasury) {
+        let meme_fee = config.to_meme(fee).unwrap_or(0);
+        match fees::treasury() {
+            Some(treasury) if meme_fee > 0 => {
+                let transfer_arg = InternalTransferArg {
+                    to: treasury.into(),
+                    lock_id: None,
+                    subaccount: None,
+                    ledger_type: LedgerType::MemeToken(meme_token_id),
+                    amount: candid::Nat::from(meme_fee),
+                };
+                match service.internal_transfer(transfer_arg).await {
+                    Ok((crate::did::fomowell_token::Result1::Ok,)) => {
+                        // 国库拿到的 meme token 和用户的一样可以兑回 alkanes
+                        reserves::record_credit(alkaneid, fee);
+                        return format!("treasury meme_amount={}", meme_fee);
+                    }
+                    Ok((crate::did::fomowell_token::Result1::Err(e),)) => {
+                        append_log(format!("[topup-fee] treasury transfer failed alkaneid={} fee={} error={}", alkaneid, fee, e));
+                    }
+                    Err((code, message)) => {
+                        append_log(format!(
+                            "[topup-fee] call internal_transfer error alkaneid={} fee={} code={:?} err={}",
+                            alkaneid, fee, code, message
+                        ));
                     }
                 }
-                Err(e) => {
-                    append_log(format!("[topup] call internal_transfer error txid={} err={:?}", txid, e));
-                    Err(format!("Failed to call internal_transfer: {:?}", e))
-                },
             }
+            Some(_) => append_log(format!("[topup-fee] fee {} of {} converts to 0 meme units", fee, alkaneid)),
+            None => append_log(format!("[topup-fee] no treasury set, fee {} of {} kept in fund", fee, alkaneid)),
         }
-        Err(e) => {
-            append_log(format!("[topup] record not found txid={} err={}", txid, e));
-            Err(e)
-        },
     }
+    fees::accrue(alkaneid, fee);
+    "fund".into()
 }
 
-
 pub fn deploy_alkanes_protostone(
     premint: u128,
     amount_per_mint: u128,
@@ -330,505 +613,1262 @@ fn test_deploy_alkanes_protostone() {
 }
 
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct WithdrawRequest {
+    ic_txid: String,
+    token_type: String,
+    token_id: String,
+    token_amount: u128,
+    withdraw_address: String,
+    /// Withdraw fee split off `token_amount` when the request was queued and
+    /// booked when it is broadcast. Set by the canister; whatever the caller
+    /// passes is replaced. `None` on requests queued before fees were booked
+    /// at broadcast, whose fee is already booked.
+    fee: Option<u128>,
+}
+
+/// `WithdrawRequest` as queued before amounts were widened to u128, only read
+/// at upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct WithdrawRequestV1 {
     ic_txid: String,
     token_type: String,
     token_id: String,
     token_amount: u64,
     withdraw_address: String,
 }
-#[update]
-async fn withdraw_alkanes(withdraw_request: WithdrawRequest) -> Result<String, String> {
+
+impl From<WithdrawRequestV1> for WithdrawRequest {
+    fn from(r: WithdrawRequestV1) -> Self {
+        WithdrawRequest {
+            ic_txid: r.ic_txid,
+            token_type: r.token_type,
+            token_id: r.token_id,
+            token_amount: r.token_amount.into(),
+            withdraw_address: r.withdraw_address,
+            fee: None,
+        }
+    }
+}
+#[update(guard = "guard_withdraw_caller")]
+async fn withdraw_alkanes(withdraw_request: WithdrawRequest) -> Result<WithdrawReceipt, FomowellError> {
     // if PROCESSED_TRANSACTIONS.with(|set| set.borrow().contains(&withdraw_request.ic_txid)) {
     //     return Ok("Transaction already processed".into());
     // }
 
-    let pid = caller();
-    let allowed_canister = Principal::from_text("fw4iq-diaaa-aaaah-arela-cai")
-        .map_err(|e| format!("Invalid allowed canister ID: {}", e))?;
-    if pid != allowed_canister {
-        return Err("Unauthorized: Only fomowell canister can call this function".to_string());
-    }
+    let result = to_alkane_units(withdraw_request).and_then(|request| enqueue_withdraw(caller(), request));
+    metrics::record("withdraw_request", result.is_ok(), 0);
+    result
+}
+
+/// 由 Admin 发起的提现，换算后的 alkane 数量达到 `large_withdraw_amount` 时需要多人审批
+#[update(guard = "guard_admin")]
+async fn manual_withdraw(withdraw_request: WithdrawRequest) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::ManualWithdraw(to_alkane_units(withdraw_request)?))
+}
+
+/// `token_amount` arrives in meme token units; the queue and everything after
+/// it work in alkane base units.
+fn to_alkane_units(mut request: WithdrawRequest) -> Result<WithdrawRequest, FomowellError> {
+    request.token_amount = get_token_config(&request.token_id)
+        .to_alkane(request.token_amount)
+        .map_err(FomowellError::InvalidArgument)?;
+    Ok(request)
+}
 
+fn is_fee_sweep(request: &WithdrawRequest) -> bool {
+    request.token_type == fees::SWEEP_TOKEN_TYPE
+}
+
+fn ensure_queue_room() -> Result<(), FomowellError> {
     let is_full = WITHDRAW_REQUESTS.with(|requests| {
         requests.borrow().len() >= 10
     });
     if is_full {
         append_log("[withdraw-queue] queue full");
-        return Err("Withdraw request queue is full".to_string());
+        return Err(FomowellError::QueueFull);
     }
-    
+    Ok(())
+}
+
+/// Appends `request` to the queue of `pid` and returns its queue position.
+fn push_withdraw(pid: Principal, request: WithdrawRequest) -> u64 {
     WITHDRAW_REQUESTS.with(|requests| {
         let mut requests_map = requests.borrow_mut();
-        requests_map
-            .entry(pid)
-            .or_insert_with(Vec::new)
-            .push(withdraw_request.clone());
-    });
+        requests_map.entry(pid).or_insert_with(Vec::new).push(request);
+        requests_map.values().map(|v| v.len() as u64).sum()
+    })
+}
+
+fn enqueue_withdraw(pid: Principal, mut withdraw_request: WithdrawRequest) -> Result<WithdrawReceipt, FomowellError> {
+    pause::ensure_running(PauseSwitch::Withdraw)?;
+    if is_fee_sweep(&withdraw_request) {
+        return Err(FomowellError::InvalidArgument(format!("token_type {} is reserved for fee sweeps", fees::SWEEP_TOKEN_TYPE)));
+    }
+    ensure_queue_room()?;
+    // 已排队的提现和手续费先占用持有量
+    let available = collect_reserves()
+        .into_iter()
+        .find(|r| r.alkaneid == withdraw_request.token_id)
+        .map(|r| r.held.saturating_sub(r.withdraw_requested).saturating_sub(r.fees))
+        .unwrap_or(0);
+    if withdraw_request.token_amount > available {
+        return Err(FomowellError::InsufficientAlkanes {
+            alkaneid: withdraw_request.token_id.clone(),
+            requested: withdraw_request.token_amount,
+            available,
+        });
+    }
+    let (sent, fee) = get_token_config(&withdraw_request.token_id)
+        .withdraw(withdraw_request.token_amount)
+        .map_err(FomowellError::InvalidArgument)?;
+    // 手续费和提现数量一样等广播后才记账，之后留在 fund 里等 sweep
+    withdraw_request.token_amount = sent;
+    withdraw_request.fee = Some(fee);
+
+    let queue_position = push_withdraw(pid, withdraw_request.clone());
     append_log(format!("[withdraw-queue] queued ic_txid={} for caller={}", withdraw_request.ic_txid, pid));
-    Ok("Withdraw request submitted".into())
+    events::event(EventKind::WithdrawQueued)
+        .principal(pid)
+        .txid(withdraw_request.ic_txid.clone())
+        .alkaneid(withdraw_request.token_id.clone())
+        .amount(withdraw_request.token_amount)
+        .detail(format!("{} fee={}", withdraw_request.withdraw_address, fee))
+        .record();
+    Ok(WithdrawReceipt {
+        ic_txid: withdraw_request.ic_txid,
+        alkaneid: withdraw_request.token_id,
+        amount: withdraw_request.token_amount,
+        fee,
+        withdraw_address: withdraw_request.withdraw_address,
+        queue_position,
+    })
 }
 
-async fn check_withdraw_request()  {
-    let PREVIOUS_TX_ID = match PENDING_TX_ID.with(|id| id.borrow().clone()) {
-        Some(txid) => txid,
-        None => {
-            return;
-        }
-    };    
-    append_log(format!("[withdraw-check] checking txid={}", PREVIOUS_TX_ID));
-    match check_tx_confirmed(PREVIOUS_TX_ID.clone()).await {
-        Ok(true) => {
-            append_log(format!("[withdraw-check] confirmed txid={}", PREVIOUS_TX_ID));
-            let withdraw_requests = WITHDRAW_REQUESTS.with(|requests| {
-                requests.borrow().clone()
-            });   
-            if let Err(e) = send_withdraw_request(withdraw_requests.clone()).await {
-                append_log(format!("send_withdraw_request error={}", e));
-            }
-        }
-        Ok(false) => {
-            append_log(format!("[withdraw-check] not confirmed txid={}", PREVIOUS_TX_ID));
-            return;
-        }
-        Err(e) => {
-            append_log(format!("[withdraw-check] check_tx_confirmed error txid={} err={}", PREVIOUS_TX_ID, e));
-            return;
-        }
+/// Accrued fees of `alkaneid` that no queued sweep covers yet.
+fn unswept_fees(alkaneid: &str) -> u128 {
+    let queued = WITHDRAW_REQUESTS.with(|r| {
+        r.borrow()
+            .values()
+            .flatten()
+            .filter(|request| is_fee_sweep(request) && request.token_id == alkaneid)
+            .fold(0u128, |sum, request| sum.saturating_add(request.token_amount))
+    });
+    fees::accrued(alkaneid).saturating_sub(queued)
+}
+
+/// Queues a withdraw of every fee accrued for `alkaneid` and not queued yet.
+/// It goes out with the next withdraw run like any other request; the fees
+/// stay accrued until that run broadcasts it.
+fn queue_fee_sweep(pid: Principal, alkaneid: &str, withdraw_address: String) -> Result<WithdrawReceipt, FomowellError> {
+    pause::ensure_running(PauseSwitch::Withdraw)?;
+    ensure_queue_room()?;
+    let amount = unswept_fees(alkaneid);
+    if amount == 0 {
+        return Err(FomowellError::NotFound(format!("accrued fees of {}", alkaneid)));
     }
+    let request = WithdrawRequest {
+        ic_txid: format!("fee-sweep-{}-{}", alkaneid, fees::next_sweep()),
+        token_type: fees::SWEEP_TOKEN_TYPE.to_string(),
+        token_id: alkaneid.to_string(),
+        token_amount: amount,
+        withdraw_address,
+        fee: None,
+    };
+    let queue_position = push_withdraw(pid, request.clone());
+    append_log(format!("[fee-sweep] queued ic_txid={} amount={} address={}", request.ic_txid, amount, request.withdraw_address));
+    events::event(EventKind::AdminAction)
+        .principal(pid)
+        .txid(request.ic_txid.clone())
+        .alkaneid(alkaneid)
+        .amount(amount)
+        .detail(format!("fee sweep to {}", request.withdraw_address))
+        .record();
+    Ok(WithdrawReceipt {
+        ic_txid: request.ic_txid,
+        alkaneid: request.token_id,
+        amount,
+        fee: 0,
+        withdraw_address: request.withdraw_address,
+        queue_position,
+    })
 }
 
+/// A transaction we broadcast that has not been seen confirmed yet.
+/// `spent_outpoints` covers both alkane and BTC inputs, so later transactions
+/// can avoid conflicting with it and measure how deep an unconfirmed chain gets.
+/// `watch_output` is an `(address, vout)` of the tx that the Bitcoin canister
+/// should report once the tx is mined.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingTx {
+    pub txid: String,
+    pub kind: String,
+    pub spent_outpoints: Vec<(String, u64)>,
+    pub broadcast_at: u64,
+    pub watch_output: Option<(String, u32)>,
+    /// Alkanes taken out of the ledger that land in outputs we do not record
+    /// until the ledger sync sees them. Only gathers broadcast before their
+    /// outputs went straight into the ledger carry this.
+    pub in_flight: Option<Vec<(String, u128)>>,
+    /// Withdraw requests (fee sweeps included) the tx pays, queued again
+    /// if it is dropped.
+    pub requests: Option<Vec<(Principal, WithdrawRequest)>>,
+    /// `(address, alkaneid, record)` ledger entries its inputs took out of
+    /// the ledger, put back if it is dropped. The entries it added sit at
+    /// its own txid and need no list.
+    pub spent_entries: Option<Vec<(String, String, AlkaneUtxoRecord)>>,
+}
 
-async fn send_withdraw_request(withdraw_alkanes: HashMap<Principal, Vec<WithdrawRequest>>) -> Result<String, String> {
-    let keys = ["alkanes_topup", "alkanes_fund", "btc"];
-    let [alkanes_topup_address, alkanes_fund_address, alkanes_btc_address] =
-        keys.map(|k| get_address(k.to_string()).unwrap());
-    
-    let required_alkanes: HashMap<String, u64> = withdraw_alkanes
-        .values()
-        .flatten()
-        .fold(HashMap::new(), |mut acc, req| {
-            *acc.entry(req.token_id.clone()).or_insert(0) += req.token_amount;
-            acc
-        });
-    let required_alkane_ids: HashSet<String> = required_alkanes.keys().cloned().collect();
-    let alkanes_fund_address = get_address("alkanes_fund".to_string()).unwrap();
-    
-    let all_utxos: Vec<(String, String, AlkaneUtxoRecord)> = 
-        get_utxos_by_address(alkanes_fund_address.clone())
-            .into_iter()
-            .filter(|(_, alkaneid, _)| required_alkane_ids.contains(alkaneid))
-            .collect();
+/// `PendingTx` as stored before amounts were widened to u128, only read at
+/// upgrade.
+#[derive(CandidType, Deserialize, Clone)]
+struct PendingTxV1 {
+    txid: String,
+    kind: String,
+    spent_outpoints: Vec<(String, u64)>,
+    broadcast_at: u64,
+    watch_output: Option<(String, u32)>,
+    in_flight: Option<Vec<(String, u64)>>,
+}
 
-    // 检查总余额是否足够
-    let total_alkane_amounts: HashMap<String, u64> = all_utxos
-        .iter()
-        .fold(HashMap::new(), |mut acc, (_, alkaneid, record)| {
-            *acc.entry(alkaneid.clone()).or_insert(0) += record.amount;
-            acc
-        });
-    
-    if let Some((alkaneid, required_amount)) = required_alkanes
-        .iter()
-        .find(|(alkaneid, required)| { 
-            total_alkane_amounts.get(*alkaneid).copied().unwrap_or(0) < **required
-        })
-    {
-        let available = total_alkane_amounts.get(alkaneid).copied().unwrap_or(0);
-        return Err(format!(
-            "Insufficient alkanes for {}: required {}, available {}",
-            alkaneid, required_amount, available
-        ));
+impl From<PendingTxV1> for PendingTx {
+    fn from(tx: PendingTxV1) -> Self {
+        PendingTx {
+            txid: tx.txid,
+            kind: tx.kind,
+            spent_outpoints: tx.spent_outpoints,
+            broadcast_at: tx.broadcast_at,
+            watch_output: tx.watch_output,
+            in_flight: tx.in_flight.map(|alkanes| alkanes.into_iter().map(|(id, amount)| (id, amount.into())).collect()),
+            requests: None,
+            spent_entries: None,
+        }
     }
+}
 
-    let mut selected_utxos: Vec<(String, String, AlkaneUtxoRecord)> = Vec::new();
-    let mut selected_alkane_amounts: HashMap<String, u64> = HashMap::new();
-    
-    for (alkaneid, required_amount) in &required_alkanes {
-        let mut alkane_utxos: Vec<(String, String, AlkaneUtxoRecord)> = all_utxos
-            .iter()
-            .filter(|(_, aid, _)| aid == alkaneid)
-            .map(|(a, aid, r)| (a.clone(), aid.clone(), r.clone()))
-            .collect();
-        alkane_utxos.sort_by(|(_, _, r1), (_, _, r2)| r2.amount.cmp(&r1.amount));
-        
-        let mut selected_amount = 0u64;
-        for utxo in alkane_utxos {
-            if selected_amount >= *required_amount {
-                break;
-            }
-            selected_utxos.push(utxo.clone());
-            selected_amount += utxo.2.amount;
-        }
-        
-        selected_alkane_amounts.insert(alkaneid.clone(), selected_amount);
-        
-        if selected_amount < *required_amount {
-            return Err(format!(
-                "Failed to select sufficient UTXOs for {}: required {}, selected {}",
-                alkaneid, required_amount, selected_amount
-            ));
+/// Number of unconfirmed transactions (ours) that `txid` sits on top of,
+/// counting itself. Outputs of confirmed transactions have depth 0.
+fn unconfirmed_depth(txid: &str, pending: &[PendingTx]) -> u32 {
+    match pending.iter().find(|tx| tx.txid == txid) {
+        Some(tx) => {
+            1 + tx
+                .spent_outpoints
+                .iter()
+                .map(|(parent, _)| unconfirmed_depth(parent, pending))
+                .max()
+                .unwrap_or(0)
         }
+        None => 0,
     }
+}
 
-    // 使用选中的UTXO金额计算找零
-    let fund_summary: Vec<(String, u64)> = selected_alkane_amounts
-        .iter()
-        .map(|(alkaneid, amount)| (alkaneid.clone(), *amount))
-        .collect();
-
-    let mut edict_inputs: Vec<EdictInput> = withdraw_alkanes
-        .values()
-        .flatten()  
-        .enumerate()
-        .filter_map(|(idx, request)| {
-            let (block_str, tx_str) = request.token_id.split_once(':')?;
-            let block = block_str.parse::<u64>().ok()?;
-            let tx = tx_str.parse::<u32>().ok()?;
-            
-            Some(EdictInput {
-                block,
-                tx,
-                amount: request.token_amount as u128,
-                output: (idx + 1) as u32,
-            })
-        })
-        .collect();
-
-    // 构建找零数据：使用选中的UTXO金额计算找零
-    let change_edicts: Vec<EdictInput> = selected_alkane_amounts
+fn is_spent_by_pending(txid: &str, vout: u64, pending: &[PendingTx]) -> bool {
+    pending
         .iter()
-        .filter_map(|(alkaneid, &selected_amount)| {
-            let required_amount = required_alkanes.get(alkaneid).copied().unwrap_or(0);
-            let change_amount = selected_amount.saturating_sub(required_amount);
-            
-            if change_amount > 0 {
-                let (block_str, tx_str) = alkaneid.split_once(':')?;
-                let block = block_str.parse::<u64>().ok()?;
-                let tx = tx_str.parse::<u32>().ok()?;
-                
-                Some(EdictInput {
-                    block,
-                    tx,
-                    amount: change_amount as u128,
-                    output: 0,
-                })
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    edict_inputs.extend(change_edicts);
-    
-    let protostone_script = generate_protostone(edict_inputs);
+        .any(|tx| tx.spent_outpoints.iter().any(|(t, v)| t == txid && *v == vout))
+}
 
-    let fund_public_key = ic::schnorr_api::schnorr_public_key(
-        SCHNORR_KEY_NAME.to_string(),
-        get_derivation_path(fomowell_alkanes_fund_address),
-    )
-    .await;
-    let fund_public_key = hex::encode(fund_public_key);
+/// 广播结果计入熔断计数并写入事件
+fn note_broadcast(kind: &str, txid: &str, result: &Result<(), String>) {
+    pause::record_broadcast(kind, result);
+    metrics::record(&format!("broadcast_{}", kind), result.is_ok(), 0);
+    match result {
+        Ok(()) => events::event(EventKind::TxBroadcast).txid(txid).detail(kind).record(),
+        Err(e) => events::event(EventKind::TxBroadcastFailed).txid(txid).detail(format!("{}: {}", kind, e)).record(),
+    }
+}
 
-    let mut inputs: Vec<TransactionInput> = Vec::new();
-    for (_, _, utxo_record) in &selected_utxos {
-        inputs.push(TransactionInput {
-            txid: utxo_record.txid.clone(),
-            vout: utxo_record.vout as u32, 
-            amount: utxo_record.satoshi, 
-            address: alkanes_fund_address.clone(),
-            public_key: Some(fund_public_key.clone()),
-            signature_type: Some(InputSignatureType::from_str("taproot_default").unwrap()),
-            witness: None,
+fn record_pending_tx(
+    txid: String,
+    kind: &str,
+    spent_outpoints: Vec<(String, u64)>,
+    watch_output: (String, u32),
+    requests: Vec<(Principal, WithdrawRequest)>,
+    spent_entries: Vec<(String, String, AlkaneUtxoRecord)>,
+) {
+    PENDING_TXS.with(|pending| {
+        pending.borrow_mut().push(PendingTx {
+            txid,
+            kind: kind.to_string(),
+            spent_outpoints,
+            broadcast_at: time(),
+            watch_output: Some(watch_output),
+            in_flight: Some(Vec::new()),
+            requests: Some(requests),
+            spent_entries: Some(spent_entries),
         });
-    }
-    
-    
-    let mut outputs: Vec<TransactionOutput> = Vec::new();
-    outputs.push(TransactionOutput {
-        address: alkanes_fund_address.clone(),
-        amount: 330,
-        op_return: None,
     });
-    let withdraw_outputs: Vec<TransactionOutput> = withdraw_alkanes
-        .values()
-        .flatten()
-        .map(|request| {
-            TransactionOutput {
-                address: request.withdraw_address.clone(),
-                amount: 330,
-                op_return: None,
-            }
-        })
-        .collect();
-    outputs.extend(withdraw_outputs);
+}
 
-    outputs.push(TransactionOutput {
-        address: alkanes_fund_address.clone(),
-        amount: 0,
-        op_return: Some(protostone_script.as_bytes().to_vec()),
+/// Undoes what the broadcast of a dropped `tx` booked: the ledger entries it
+/// added go, the ones it spent come back, the requests it paid are queued
+/// again in front of their principal's queue and the withdrawn and fee
+/// counters are reversed. Children have to be rolled back before their
+/// parents, since a child's spent entries are the parent's outputs.
+fn roll_back(tx: &PendingTx) {
+    let (Some(requests), Some(spent_entries)) = (&tx.requests, &tx.spent_entries) else {
+        // 旧版本记录的交易没有回滚信息，只能等下一次账本同步
+        append_log(format!("[withdraw-check] no rollback data for txid={}, left to the next ledger sync", tx.txid));
+        return;
+    };
+    for address in alkanes_sync::SYNCED_ADDRESSES.iter().filter_map(|t| get_address(t.to_string()).ok()) {
+        let created: Vec<(String, u64)> = get_outpoints_by_address(&address)
+            .into_iter()
+            .filter(|(outpoint, _)| outpoint.txid == tx.txid)
+            .map(|(outpoint, _)| (outpoint.txid, outpoint.vout))
+            .collect();
+        take_utxos(&address, &created);
+    }
+    for (address, alkaneid, record) in spent_entries {
+        if let Err(e) = put_utxo(address, alkaneid, record.clone()) {
+            append_log(format!("[withdraw-check] entry not restored txid={} err={}", tx.txid, e));
+        }
+    }
+    for (_, request) in requests {
+        if is_fee_sweep(request) {
+            fees::accrue(&request.token_id, request.token_amount);
+            continue;
+        }
+        let fee = request.fee.unwrap_or(0);
+        reserves::reverse_withdrawn(&request.token_id, request.token_amount.saturating_add(fee));
+        fees::deduct(&request.token_id, fee);
+    }
+    WITHDRAW_REQUESTS.with(|queues| {
+        let mut queues = queues.borrow_mut();
+        for (pid, request) in requests.iter().rev() {
+            let queue = queues.entry(*pid).or_default();
+            if !queue.iter().any(|r| r.ic_txid == request.ic_txid) {
+                queue.insert(0, request.clone());
+            }
+        }
     });
+}
 
-    let fee_rate = get_feerate().await.unwrap();
-
-    let total_input: u64 = inputs.iter().map(|input| input.amount).sum();
-    let total_output: u64 = outputs.iter().map(|output| output.amount).sum();
-    
-    let fee = calculate_fee_with_opreturn(inputs.len(), outputs.len() + 1 , protostone_script.as_bytes().len(), fee_rate.parse::<f64>().unwrap());
+/// 检查所有未确认交易，已确认的和被 mempool 丢弃的移出 PENDING_TXS
+///
+/// A tx counts as confirmed once the Bitcoin canister lists its watched output
+/// with at least `MIN_CONFIRMATIONS` confirmations. Watched outputs that we
+/// already spent again never show up as UTXOs, so a confirmed child also
+/// confirms every pending ancestor it spends from.
+///
+/// With the HTTP cross-check on, mempool.space also has to report a tx as
+/// confirmed. Whatever the cross-check, mempool.space is asked about every
+/// unconfirmed tx older than `DROPPED_AFTER_SECS`; one it does not know at
+/// all was dropped from the mempool. It and every pending tx spending its
+/// outputs are rolled back with `roll_back`, so their inputs can be spent
+/// again and their requests go out in a later run.
+async fn refresh_pending_txs(chain: &impl chain::Chain) {
+    let pending = PENDING_TXS.with(|p| p.borrow().clone());
+    if pending.is_empty() {
+        return;
+    }
+    let min_confirmations = MIN_CONFIRMATIONS.with(|c| *c.borrow());
+    let cross_check = HTTP_CROSS_CHECK.with(|c| *c.borrow());
+    let mut confirmed: HashSet<String> = HashSet::new();
+    let mut statuses: HashMap<String, TxStatus> = HashMap::new();
+
+    let mut watched: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+    for tx in &pending {
+        match &tx.watch_output {
+            Some((address, vout)) => watched
+                .entry(address.clone())
+                .or_default()
+                .push((tx.txid.clone(), *vout)),
+            // 旧版本记录的交易没有 watch_output，只能通过 http 查询
+            None => match chain.tx_status(&tx.txid).await {
+                Ok(status) => {
+                    if status == TxStatus::Confirmed {
+                        confirmed.insert(tx.txid.clone());
+                    }
+                    statuses.insert(tx.txid.clone(), status);
+                }
+                Err(e) => append_log(format!("[withdraw-check] http check error txid={} err={}", tx.txid, e)),
+            },
+        }
+    }
 
-    let btc_utxos = get_btc_utxos(alkanes_btc_address.clone()).await.unwrap();
+    for (address, outpoints) in watched {
+        match chain.confirmed_outpoints(&address, min_confirmations).await {
+            Ok(utxos) => {
+                for (txid, vout) in outpoints {
+                    if utxos.iter().any(|(t, v)| *t == txid && *v == vout) {
+                        confirmed.insert(txid);
+                    }
+                }
+            }
+            Err(e) => append_log(format!("[withdraw-check] get_utxos error address={} err={}", address, e)),
+        }
+    }
 
-    let btc_public_key = ic::schnorr_api::schnorr_public_key(
-        SCHNORR_KEY_NAME.to_string(),
-        get_derivation_path(fomowell_btc_address),
-    )
-    .await;
-    let btc_public_key_hex = hex::encode(btc_public_key);
-    let mut total_value = 0u64;
-    let mut btc_inputs: Vec<TransactionInput> = Vec::new();
-    for utxo in &btc_utxos {
-        btc_inputs.push(TransactionInput {
-            txid: utxo.txid.clone(),
-            vout: utxo.vout,
-            amount: utxo.value,
-            address: alkanes_btc_address.clone(),
-            public_key: Some(btc_public_key_hex.clone()),
-            signature_type: Some(InputSignatureType::from_str("taproot_default").unwrap()),
-            witness: None,
-        });
-        total_value += utxo.value;
-        if total_value  + total_input >= fee + total_output + 2000 {
+    loop {
+        let ancestors: Vec<String> = pending
+            .iter()
+            .filter(|tx| confirmed.contains(&tx.txid))
+            .flat_map(|tx| tx.spent_outpoints.iter().map(|(parent, _)| parent.clone()))
+            .filter(|parent| !confirmed.contains(parent) && pending.iter().any(|tx| &tx.txid == parent))
+            .collect();
+        if ancestors.is_empty() {
             break;
         }
+        confirmed.extend(ancestors);
     }
-    inputs.extend(btc_inputs.clone());
-    let new_fee = calculate_fee_with_opreturn(inputs.len(), outputs.len() + 1 , protostone_script.as_bytes().len(), fee_rate.parse::<f64>().unwrap());
-    let change = inputs.iter().map(|input| input.amount).sum::<u64>()
-    .saturating_sub(outputs.iter().map(|output| output.amount).sum::<u64>())
-    .saturating_sub(new_fee);
-    if  change > 330 {
-        outputs.push(TransactionOutput {
-            address: alkanes_btc_address.clone(),
-            amount: change,
-            op_return: None,
-        });
+
+    let mut dropped: HashSet<String> = HashSet::new();
+    for tx in &pending {
+        // 确认的交易只在打开交叉校验时再问 http；超时未确认的交易总要问一次是否已被丢弃
+        let ask = if confirmed.contains(&tx.txid) {
+            cross_check
+        } else {
+            time().saturating_sub(tx.broadcast_at) >= DROPPED_AFTER_SECS * 1_000_000_000
+        };
+        if !ask {
+            continue;
+        }
+        let status = match statuses.get(&tx.txid) {
+            Some(status) => Ok(*status),
+            None => chain.tx_status(&tx.txid).await,
+        };
+        match (confirmed.contains(&tx.txid), status) {
+            (true, Ok(TxStatus::Confirmed)) => {}
+            (true, Ok(status)) => {
+                append_log(format!(
+                    "[withdraw-check] cross-check mismatch txid={} bitcoin canister=confirmed http={:?}",
+                    tx.txid, status
+                ));
+                confirmed.remove(&tx.txid);
+            }
+            (false, Ok(TxStatus::NotFound)) => {
+                dropped.insert(tx.txid.clone());
+            }
+            (false, Ok(_)) => {}
+            (_, Err(e)) => append_log(format!("[withdraw-check] http check error txid={} err={}", tx.txid, e)),
+        }
     }
-    match create_transaction_multi(
-        "testnet",
-        inputs,
-        outputs,
-        btc_inputs,
-        fomowell_alkanes_fund_address,
-    )
-    .await
-    {
-        Ok(final_psbt) => {
-            let final_hex = final_psbt.psbt_hex.clone();
-            let transaction_bytes = hex::decode(&final_hex)
-            .map_err(|e| format!("Failed to decode transaction hex: {}", e))?;
-            send_transaction(IC_BITCOIN_NETWORK, transaction_bytes).await;
-            let txid = final_psbt.txid.clone();
-            PENDING_TX_ID.with(|id| {
-                *id.borrow_mut() = Some(txid.clone());
-            });
-            append_log(format!("[withdraw-send] broadcast txid={} ", txid));
-            Ok(txid)
+    // 父交易没通过交叉校验时子交易也不能算确认
+    loop {
+        let orphans: Vec<String> = pending
+            .iter()
+            .filter(|tx| confirmed.contains(&tx.txid))
+            .filter(|tx| {
+                tx.spent_outpoints
+                    .iter()
+                    .any(|(parent, _)| !confirmed.contains(parent) && pending.iter().any(|p| &p.txid == parent))
+            })
+            .map(|tx| tx.txid.clone())
+            .collect();
+        if orphans.is_empty() {
+            break;
         }
-        Err(err) => {
-            append_log(format!("[withdraw-send] create tx failed err={:?}", err));
-            return Err(format!("cant create final tx !"));
+        for txid in orphans {
+            confirmed.remove(&txid);
         }
     }
-}
-
-async fn gather_alkanes_utxo() -> Result<String, String> {
-        let keys = ["alkanes_topup", "alkanes_fund", "btc"];
-        let [alkanes_topup_address, alkanes_fund_address, alkanes_btc_address] =
-            keys.map(|k| get_address(k.to_string()).unwrap());
-    
-        let alkanes_topup_utxo = get_utxos_by_address(alkanes_topup_address.clone());
-        let mut alkane_amounts: HashMap<String, u64> = HashMap::new();
+    // 被丢弃交易的后代花费的输出已不存在，一起释放
+    loop {
+        let children: Vec<String> = pending
+            .iter()
+            .filter(|tx| !dropped.contains(&tx.txid) && !confirmed.contains(&tx.txid))
+            .filter(|tx| tx.spent_outpoints.iter().any(|(parent, _)| dropped.contains(parent)))
+            .map(|tx| tx.txid.clone())
+            .collect();
+        if children.is_empty() {
+            break;
+        }
+        dropped.extend(children);
+    }
 
-        for (_, alkaneid, utxo_record) in &alkanes_topup_utxo {
-            *alkane_amounts.entry(alkaneid.clone()).or_insert(0) += utxo_record.amount;
+    for tx in &pending {
+        if confirmed.contains(&tx.txid) {
+            append_log(format!("[withdraw-check] confirmed txid={} kind={}", tx.txid, tx.kind));
+            events::event(EventKind::TxConfirmed).txid(tx.txid.clone()).detail(tx.kind.clone()).record();
+        } else if dropped.contains(&tx.txid) {
+            append_log(format!("[withdraw-check] dropped txid={} kind={}, rolled back", tx.txid, tx.kind));
+            events::event(EventKind::TxDropped).txid(tx.txid.clone()).detail(tx.kind.clone()).record();
+        } else {
+            append_log(format!("[withdraw-check] not confirmed txid={} kind={}", tx.txid, tx.kind));
         }
+    }
+    // 子交易先回滚，它放回的条目是父交易的输出，随后由父交易删掉
+    let mut rolled_back: Vec<&PendingTx> = pending.iter().filter(|tx| dropped.contains(&tx.txid)).collect();
+    rolled_back.sort_by_key(|tx| std::cmp::Reverse(unconfirmed_depth(&tx.txid, &pending)));
+    for tx in rolled_back {
+        roll_back(tx);
+    }
+    PENDING_TXS.with(|p| p.borrow_mut().retain(|tx| !confirmed.contains(&tx.txid) && !dropped.contains(&tx.txid)));
+}
 
-        let alkanes_map: HashMap<String, u64> = alkane_amounts.clone();
+async fn check_withdraw_request() {
+    refresh_pending_txs(&chain::IcChain).await;
 
-        // 按照alkanes id构建alkanes data
-        // 后续优化设计，可以将utxo只生成一个
-        let edict_inputs: Vec<EdictInput> = alkanes_map
-        .iter()
-        .enumerate()
-        .filter_map(|(idx, (alkaneid, amount))| {
-            let (block_str, tx_str) = alkaneid.split_once(':')?;
-            let block = block_str.parse::<u64>().ok()?;
-            let tx = tx_str.parse::<u32>().ok()?;
-            
-            Some(EdictInput {
-                block,
-                tx,
-                amount: *amount as u128,
-                output: (idx + 1) as u32, // output 从 1 开始,0是保险设计
-            })
-        })
-        .collect();
-        
-        let protostone_script = generate_protostone(edict_inputs);
-    
-        let topup_public_key = ic::schnorr_api::schnorr_public_key(
-            SCHNORR_KEY_NAME.to_string(),
-            get_derivation_path(fomowell_alkanes_topup_address),
-        )
-        .await;
-        let topup_public_key = hex::encode(topup_public_key);
-
-        let mut inputs: Vec<TransactionInput> = Vec::new();
-        for (address, _alkaneid, utxo_record) in &alkanes_topup_utxo {
-            inputs.push(TransactionInput {
-                txid: utxo_record.txid.clone(),
-                vout: utxo_record.vout as u32, 
-                amount: utxo_record.satoshi, 
-                address: alkanes_topup_address.clone(),
-                public_key: Some(topup_public_key.clone()),
-                signature_type: Some(InputSignatureType::from_str("taproot_default").unwrap()),
-                witness: None,
-            });
-        }
-        
-        // 构建outputs，
-        let mut outputs: Vec<TransactionOutput> = Vec::new();
-        // 根据alkanes_map中alkanesid的数量，构建n+1个output，地址是alkanes_fund_address
-        for (alkaneid, amount) in &alkanes_map {
-            outputs.push(TransactionOutput {
-                address: alkanes_fund_address.clone(),
-                amount: 330,
-                op_return: None,
-            });
-        }
-        outputs.push(TransactionOutput {
-            address: alkanes_fund_address.clone(),
-            amount: 0,
-            op_return: Some(protostone_script.as_bytes().to_vec()),
-        });
+    if pause::is_paused(PauseSwitch::Withdraw) {
+        append_log("[withdraw-check] withdraw paused, queue left untouched");
+        return;
+    }
 
-        let fee_rate = get_feerate().await.unwrap();
-        let fee = calculate_fee_with_opreturn(inputs.len(), outputs.len() +1, protostone_script.as_bytes().len(), fee_rate.parse::<f64>().unwrap());
+    let withdraw_requests = WITHDRAW_REQUESTS.with(|requests| requests.borrow().clone());
+    if withdraw_requests.values().all(|v| v.is_empty()) {
+        return;
+    }
+    let _ = run_tx_flow("withdraw", send_withdraw_request(&chain::IcChain, withdraw_requests)).await;
+}
 
-        let btc_utxos = get_btc_utxos(alkanes_btc_address.clone()).await.unwrap();
+#[query]
+fn get_pending_txs() -> Vec<PendingTx> {
+    PENDING_TXS.with(|p| p.borrow().clone())
+}
 
-        let btc_public_key = ic::schnorr_api::schnorr_public_key(
-            SCHNORR_KEY_NAME.to_string(),
-            get_derivation_path(fomowell_btc_address),
-        )
-        .await;
-        let btc_public_key_hex = hex::encode(btc_public_key);
-        let mut total_value = 0u64;
-        let mut btc_inputs: Vec<TransactionInput> = Vec::new();
-        for utxo in &btc_utxos {
-            btc_inputs.push(TransactionInput {
-                txid: utxo.txid.clone(),
-                vout: utxo.vout,
-                amount: utxo.value,
-                address: fomowell_btc_address.to_string(),
-                public_key: Some(btc_public_key_hex.clone()),
-                signature_type: Some(InputSignatureType::from_str("taproot_default").unwrap()),
-                witness: None,
-            });
-            total_value += utxo.value;
-            if total_value >= fee {
-                break;
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SchedulerConfig {
+    pub max_unconfirmed_depth: u32,
+    pub min_confirmations: u32,
+    pub http_cross_check: bool,
+    /// Sats on every alkane output a withdraw or gather creates.
+    pub postage: u64,
+}
+
+#[query]
+fn get_scheduler_config() -> SchedulerConfig {
+    SchedulerConfig {
+        max_unconfirmed_depth: MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow()),
+        min_confirmations: MIN_CONFIRMATIONS.with(|c| *c.borrow()),
+        http_cross_check: HTTP_CROSS_CHECK.with(|c| *c.borrow()),
+        postage: postage(),
+    }
+}
+
+fn postage() -> u64 {
+    POSTAGE.with(|p| *p.borrow())
+}
+
+/// `http_cross_check` additionally asks mempool.space before a tx is treated
+/// as confirmed, and releases unconfirmed txs it no longer knows; the Bitcoin
+/// canister stays the source of truth.
+#[update(guard = "guard_operator")]
+async fn set_confirmation_config(min_confirmations: u32, http_cross_check: bool) -> Result<ProposalReceipt, FomowellError> {
+    if min_confirmations == 0 {
+        return Err(FomowellError::InvalidArgument("min_confirmations must be at least 1".into()));
+    }
+    proposals::submit(ProposalAction::SetConfirmationConfig { min_confirmations, http_cross_check })
+}
+
+#[update(guard = "guard_operator")]
+async fn set_max_unconfirmed_depth(depth: u32) -> Result<ProposalReceipt, FomowellError> {
+    if depth == 0 {
+        return Err(FomowellError::InvalidArgument("depth must be at least 1".into()));
+    }
+    proposals::submit(ProposalAction::SetMaxUnconfirmedDepth(depth))
+}
+
+/// Sats put on each withdraw output, the fund change output and the gather
+/// outputs. Input sats beyond that go to the BTC change output.
+#[update(guard = "guard_operator")]
+async fn set_postage(postage: u64) -> Result<ProposalReceipt, FomowellError> {
+    if postage < txplan::DUST_LIMIT {
+        return Err(FomowellError::InvalidArgument(format!("postage must be at least {}", txplan::DUST_LIMIT)));
+    }
+    proposals::submit(ProposalAction::SetPostage(postage))
+}
+
+
+/// 用 indexer 的结果校正 topup/fund 地址的 utxo 账本
+///
+/// Each address is synced on its own, so an indexer error on one of them
+/// leaves the other's result in place.
+async fn sync_utxo_ledger() -> Vec<LedgerDiff> {
+    let mut diffs = Vec::new();
+    for address_type in alkanes_sync::SYNCED_ADDRESSES {
+        let address = match get_address(address_type.to_string()) {
+            Ok(address) => address,
+            Err(e) => {
+                append_log(format!("[ledger-sync] address error type={} err={}", address_type, e));
+                continue;
+            }
+        };
+        let pending = PENDING_TXS.with(|p| p.borrow().clone());
+        match alkanes_sync::fetch_diff(&address, &pending).await {
+            Ok(diff) => {
+                // 拉取期间可能有新交易广播，应用前按最新的 pending 重新过滤
+                let pending = PENDING_TXS.with(|p| p.borrow().clone());
+                let diff = LedgerDiff {
+                    added: diff
+                        .added
+                        .into_iter()
+                        .filter(|e| !is_spent_by_pending(&e.utxo.txid, e.utxo.vout, &pending))
+                        .collect(),
+                    ..diff
+                };
+                if !diff.is_empty() {
+                    alkanes_sync::apply(&diff);
+                }
+                append_log(format!(
+                    "[ledger-sync] done type={} added={} removed={}",
+                    address_type,
+                    diff.added.len(),
+                    diff.removed.len()
+                ));
+                diffs.push(diff);
+            }
+            Err(e) => append_log(format!("[ledger-sync] indexer error type={} err={}", address_type, e)),
+        }
+    }
+    diffs
+}
+
+#[update(guard = "guard_operator")]
+async fn sync_utxo_ledger_now() -> Result<Vec<LedgerDiff>, FomowellError> {
+    Ok(sync_utxo_ledger().await)
+}
+
+/// Dry run: diffs the ledger against the last indexer snapshot of each address
+/// without touching either.
+#[query]
+fn get_utxo_ledger_diff() -> Vec<LedgerDiff> {
+    let pending = PENDING_TXS.with(|p| p.borrow().clone());
+    alkanes_sync::preview(&pending)
+}
+
+/// Sends the queued withdraw requests in one transaction. Ledger, queue and
+/// reserves change only after the broadcast succeeded, in one synchronous
+/// block, so any earlier failure leaves them as they were.
+async fn send_withdraw_request(
+    chain: &impl chain::Chain,
+    withdraw_alkanes: HashMap<Principal, Vec<WithdrawRequest>>,
+) -> Result<String, FomowellError> {
+    let alkanes_fund_address = get_address("alkanes_fund".to_string())?;
+    let (plan, withdraw_alkanes) = plan_withdraw(chain, &withdraw_alkanes).await?;
+    // 先还原成未签名交易，构建不出来或 alkanes 去向不对就不去要签名
+    let simulation = txplan::simulate(&plan, txplan::network(), &alkanes_fund_address).map_err(FomowellError::TxBuildFailed)?;
+    ensure_as_planned(&simulation)?;
+    let required_alkanes = required_alkanes(&withdraw_alkanes).map_err(FomowellError::InvalidArgument)?;
+    let txid = sign_and_broadcast(chain, &plan).await?;
+
+    // 从这里到返回没有 await，账本、队列和储备要么一起更新，要么都不变
+    let spent_outpoints: Vec<(String, u64)> = plan.inputs.iter().map(|input| (input.txid.clone(), input.vout)).collect();
+    let fund_outpoints: Vec<(String, u64)> = plan
+        .inputs
+        .iter()
+        .filter(|input| input.address == alkanes_fund_address)
+        .map(|input| (input.txid.clone(), input.vout))
+        .collect();
+    // 花掉的 fund utxo 上所有 alkanes 都会被 pointer 带到 output 0，
+    // 扣除已提现数量后记为新的 fund utxo，后续提现可在未确认时继续使用
+    let spent_entries = take_utxos(&alkanes_fund_address, &fund_outpoints);
+    let sent: Vec<(Principal, WithdrawRequest)> = withdraw_alkanes
+        .iter()
+        .flat_map(|(pid, requests)| requests.iter().map(|request| (*pid, request.clone())))
+        .collect();
+    record_pending_tx(
+        txid.clone(),
+        "withdraw",
+        spent_outpoints,
+        (alkanes_fund_address.clone(), 0),
+        sent,
+        spent_entries.iter().map(|(alkaneid, record)| (alkanes_fund_address.clone(), alkaneid.clone(), record.clone())).collect(),
+    );
+    // 同一批 outpoint 在 simulate 里已经按 checked_total 求过和，这里不会溢出
+    let mut change_amounts: HashMap<String, u128> = HashMap::new();
+    for (alkaneid, record) in &spent_entries {
+        let total = change_amounts.entry(alkaneid.clone()).or_insert(0);
+        *total = total.saturating_add(record.amount);
+    }
+    for (alkaneid, amount) in change_amounts {
+        let change_amount = amount.saturating_sub(required_alkanes.get(&alkaneid).copied().unwrap_or(0));
+        if change_amount > 0 {
+            let change = AlkaneUtxoRecord { amount: change_amount, txid: txid.clone(), vout: 0, satoshi: plan.outputs[0].satoshi };
+            // alkaneid 来自账本本身，不会解析失败
+            if let Err(e) = put_utxo(&alkanes_fund_address, &alkaneid, change) {
+                append_log(format!("[withdraw-send] change not recorded txid={} err={}", txid, e));
+            }
+        }
+    }
+
+    // fee sweep 发的是已计入手续费的 alkanes，不动入账计数；
+    // 提现的手续费算作已提现，留在 fund 里等 sweep
+    for request in withdraw_alkanes.values().flatten() {
+        if is_fee_sweep(request) {
+            fees::deduct(&request.token_id, request.token_amount);
+            continue;
+        }
+        let fee = request.fee.unwrap_or(0);
+        reserves::record_withdrawn(&request.token_id, request.token_amount.saturating_add(fee));
+        fees::accrue(&request.token_id, fee);
+    }
+
+    // 只移除本次已发送的请求，广播期间新入队的请求保留
+    WITHDRAW_REQUESTS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        for (pid, sent) in &withdraw_alkanes {
+            if let Some(queue) = requests.get_mut(pid) {
+                queue.retain(|r| !sent.iter().any(|s| s.ic_txid == r.ic_txid));
             }
         }
-        inputs.extend(btc_inputs.clone());
-        let new_fee = calculate_fee_with_opreturn(inputs.len(), outputs.len() + 1 , protostone_script.as_bytes().len(), fee_rate.parse::<f64>().unwrap());
-        let change = inputs.iter().map(|input| input.amount).sum::<u64>()
-            .saturating_sub(outputs.iter().map(|output| output.amount).sum::<u64>())
-            .saturating_sub(new_fee);
-        if  change > 330 {
-            outputs.push(TransactionOutput {
-                address: alkanes_btc_address.clone(),
-                amount: change,
-                op_return: None,
+        requests.retain(|_, queue| !queue.is_empty());
+    });
+    append_log(format!(
+        "[withdraw-send] broadcast txid={} fee={} change={} fee_rounding={}",
+        txid, plan.fee, plan.change, plan.fee_rounding
+    ));
+    Ok(txid)
+}
+
+fn required_alkanes(withdraw_alkanes: &HashMap<Principal, Vec<WithdrawRequest>>) -> Result<HashMap<String, u128>, String> {
+    withdraw_alkanes
+        .values()
+        .flatten()
+        .try_fold(HashMap::new(), |mut acc, req| {
+            let total = acc.entry(req.token_id.clone()).or_insert(0);
+            *total = txplan::checked_total(*total, req.token_amount, &req.token_id)?;
+            Ok(acc)
+        })
+}
+
+/// Picks the requests, fund UTXOs and BTC inputs of one withdraw
+/// transaction. Only asks for the fee rate and the BTC UTXOs; nothing is
+/// signed.
+///
+/// The protostone has to fit in one OP_RETURN, so a long queue goes out as
+/// the requests (in principal order) that fit and the rest waits for the
+/// next run. Returns the plan and the requests it pays.
+async fn plan_withdraw(
+    chain: &impl chain::Chain,
+    withdraw_alkanes: &HashMap<Principal, Vec<WithdrawRequest>>,
+) -> Result<(TxPlan, HashMap<Principal, Vec<WithdrawRequest>>), FomowellError> {
+    let alkanes_fund_address = get_address("alkanes_fund".to_string())?;
+    let alkanes_btc_address = get_address("btc".to_string())?;
+
+    // 已广播的交易在 ledger 中已经被移除，这里只需限制未确认链的深度
+    let pending = PENDING_TXS.with(|p| p.borrow().clone());
+    let max_depth = MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow());
+    let fund_ledger: Vec<PlannedInput> = ledger_inputs(&alkanes_fund_address)
+        .into_iter()
+        .filter(|input| unconfirmed_depth(&input.txid, &pending) < max_depth)
+        .collect();
+
+    let mut queued: Vec<(Principal, WithdrawRequest)> = withdraw_alkanes
+        .iter()
+        .flat_map(|(principal, requests)| requests.iter().map(|request| (*principal, request.clone())))
+        .collect();
+    queued.sort_by_key(|(principal, _)| *principal);
+    let (batch, (fund_inputs, mut outputs, protostone)) = withdraw_batch(&alkanes_fund_address, &fund_ledger, &queued)?;
+
+    let fee_rate = chain.fee_rate().await?;
+
+    // bitcoin canister 看不到 mempool，需排除被未确认交易花费的 btc utxo
+    let btc_utxos: Vec<UtxoInfo> = chain.btc_utxos(&alkanes_btc_address).await?
+        .into_iter()
+        .filter(|utxo| !is_spent_by_pending(&utxo.txid, utxo.vout as u64, &pending))
+        .collect();
+    let funding = fund_outputs(&alkanes_btc_address, &fund_inputs, &mut outputs, &protostone, fee_rate, usize::MAX, &btc_utxos)?;
+    let plan = TxPlan {
+        kind: "withdraw".to_string(),
+        inputs: fund_inputs.into_iter().chain(funding.inputs).collect(),
+        outputs,
+        protostone,
+        fee_rate,
+        fee: funding.fee,
+        change: funding.change,
+        fee_rounding: funding.fee_rounding,
+    };
+    Ok((plan, batch))
+}
+
+/// Fund inputs, outputs and protostone of one withdraw batch.
+type WithdrawOutputs = (Vec<PlannedInput>, Vec<PlannedOutput>, serde_bytes::ByteBuf);
+
+/// Requests of `queued` that build together and whose protostone fits in one
+/// OP_RETURN, with their fund inputs, outputs and protostone. Going in queue
+/// order, a request that does not build or fit alongside the ones already
+/// taken is skipped and stays queued for a later run, so one request the
+/// fund cannot cover yet does not hold up the others. Only when no request
+/// builds is the first one's error returned.
+fn withdraw_batch(
+    alkanes_fund_address: &str,
+    fund_ledger: &[PlannedInput],
+    queued: &[(Principal, WithdrawRequest)],
+) -> Result<(HashMap<Principal, Vec<WithdrawRequest>>, WithdrawOutputs), FomowellError> {
+    let mut chosen: Option<(HashMap<Principal, Vec<WithdrawRequest>>, WithdrawOutputs)> = None;
+    let mut first_error = None;
+    for (principal, request) in queued {
+        let mut batch = chosen.as_ref().map(|(batch, _)| batch.clone()).unwrap_or_default();
+        batch.entry(*principal).or_default().push(request.clone());
+        let error = match withdraw_outputs(alkanes_fund_address, fund_ledger, &batch) {
+            Ok(built) if built.2.len() <= txplan::MAX_OP_RETURN_BYTES => {
+                chosen = Some((batch, built));
+                continue;
+            }
+            Ok(built) => FomowellError::TxBuildFailed(format!(
+                "withdraw ic_txid={} needs a {}-byte OP_RETURN with the batch, more than {}",
+                request.ic_txid,
+                built.2.len(),
+                txplan::MAX_OP_RETURN_BYTES
+            )),
+            Err(e) => e,
+        };
+        append_log(format!("[withdraw-send] skipped ic_txid={} err={}", request.ic_txid, error));
+        first_error.get_or_insert(error);
+    }
+    chosen.ok_or_else(|| first_error.unwrap_or_else(|| FomowellError::NotFound("queued withdraw requests".into())))
+}
+
+/// Adds the BTC inputs paying for `outputs` and, when the sats left over are
+/// worth an output, the change output to the BTC address.
+fn fund_outputs(
+    alkanes_btc_address: &str,
+    alkane_inputs: &[PlannedInput],
+    outputs: &mut Vec<PlannedOutput>,
+    protostone: &[u8],
+    fee_rate: f64,
+    max_inputs: usize,
+    btc_utxos: &[UtxoInfo],
+) -> Result<txplan::BtcFunding, FomowellError> {
+    let regular_outputs = outputs.iter().filter(|output| !output.op_return).count();
+    let fee_for = |input_count: usize, with_change: bool| {
+        calculate_fee_with_opreturn(input_count, regular_outputs + usize::from(with_change), protostone.len(), fee_rate)
+    };
+    let btc_inputs = btc_utxos.iter().map(|utxo| PlannedInput {
+        address: alkanes_btc_address.to_string(),
+        txid: utxo.txid.clone(),
+        vout: utxo.vout as u64,
+        satoshi: utxo.value,
+        alkanes: Vec::new(),
+    });
+    let funding = txplan::fund_with_btc(
+        alkane_inputs.iter().map(|input| input.satoshi).sum(),
+        outputs.iter().map(|output| output.satoshi).sum(),
+        alkane_inputs.len(),
+        max_inputs,
+        btc_inputs,
+        fee_for,
+    )
+    .map_err(FomowellError::TxBuildFailed)?;
+    if funding.change > 0 {
+        outputs.push(PlannedOutput::new(alkanes_btc_address, funding.change));
+    }
+    Ok(funding)
+}
+
+/// Every ledger outpoint of `address` as an input.
+fn ledger_inputs(address: &str) -> Vec<PlannedInput> {
+    get_outpoints_by_address(address)
+        .into_iter()
+        .map(|(outpoint, utxo)| PlannedInput::from_ledger(address, outpoint, utxo))
+        .collect()
+}
+
+/// Fund inputs, outputs and protostone paying every request in `batch`.
+///
+/// A fund outpoint may hold several alkanes, and spending it moves all of
+/// them: whatever the requests do not take goes back to the fund on output
+/// 0, through one change edict per alkane.
+fn withdraw_outputs(
+    alkanes_fund_address: &str,
+    fund_ledger: &[PlannedInput],
+    batch: &HashMap<Principal, Vec<WithdrawRequest>>,
+) -> Result<WithdrawOutputs, FomowellError> {
+    for request in batch.values().flatten() {
+        txplan::check_address(&request.withdraw_address)
+            .map_err(|e| FomowellError::InvalidArgument(format!("withdraw_address of ic_txid={}: {}", request.ic_txid, e)))?;
+    }
+    let required_alkanes = required_alkanes(batch).map_err(FomowellError::InvalidArgument)?;
+    // 账本里每个 outpoint 上同一种 alkane 只有一条余额
+    let balance = |input: &PlannedInput, alkaneid: &str| -> u128 {
+        input.alkanes.iter().find(|(id, _)| id == alkaneid).map(|(_, amount)| *amount).unwrap_or(0)
+    };
+
+    // 检查总余额是否足够，超过 u128 的总量当然也够
+    let mut required: Vec<(&String, &u128)> = required_alkanes.iter().collect();
+    required.sort();
+    for (alkaneid, required_amount) in &required {
+        let available = fund_ledger.iter().fold(0u128, |sum, input| sum.saturating_add(balance(input, alkaneid)));
+        if available < **required_amount {
+            return Err(FomowellError::InsufficientAlkanes {
+                alkaneid: alkaneid.to_string(),
+                requested: **required_amount,
+                available,
             });
         }
-        let final_hex: String;
-        match create_transaction_multi(
-            "testnet",
-            inputs,
-            outputs,
-            btc_inputs,
-            fomowell_alkanes_fund_address,
-        )
-        .await
-        {
-            Ok(final_psbt) => {
-                final_hex = final_psbt.psbt_hex.clone();
-                let transaction_bytes = hex::decode(&final_hex)
-                .map_err(|e| format!("Failed to decode transaction hex: {}", e))?;
-                send_transaction(IC_BITCOIN_NETWORK, transaction_bytes).await;
-                let txid = final_psbt.txid.clone();
-                PENDING_TX_ID.with(|id| {
-                    *id.borrow_mut() = Some(txid.clone());
-                });
-                WITHDRAW_REQUESTS.with(|requests| {
-                    requests.borrow_mut().clear();
-                });
-                append_log(format!("[gather] broadcast txid={} ",txid));
-                Ok(txid)
+    }
+
+    // 选中的 outpoint 上所有 alkanes 都算进已选数量，后面的 alkane 可能因此少选或不选
+    let mut selected: Vec<&PlannedInput> = Vec::new();
+    let mut selected_alkane_amounts: BTreeMap<String, u128> = BTreeMap::new();
+    for (alkaneid, required_amount) in &required {
+        let mut candidates: Vec<&PlannedInput> = fund_ledger
+            .iter()
+            .filter(|input| balance(input, alkaneid) > 0 && !selected.contains(input))
+            .collect();
+        candidates.sort_by_key(|c| std::cmp::Reverse(balance(c, alkaneid)));
+        for input in candidates {
+            if selected_alkane_amounts.get(*alkaneid).copied().unwrap_or(0) >= **required_amount {
+                break;
             }
-            Err(err) => {
-                append_log(format!("[gather] create tx failed err={:?}", err));
-                return Err(format!("cant create final tx !"));
+            selected.push(input);
+            for (id, amount) in &input.alkanes {
+                let total = selected_alkane_amounts.entry(id.clone()).or_insert(0);
+                *total = txplan::checked_total(*total, *amount, id).map_err(FomowellError::TxBuildFailed)?;
             }
         }
+        let selected_amount = selected_alkane_amounts.get(*alkaneid).copied().unwrap_or(0);
+        if selected_amount < **required_amount {
+            return Err(FomowellError::Internal(format!(
+                "Failed to select sufficient UTXOs for {}: required {}, selected {}",
+                alkaneid, required_amount, selected_amount
+            )));
+        }
+    }
+    let fund_inputs: Vec<PlannedInput> = selected.into_iter().cloned().collect();
+
+    // output 0 收找零，每个请求一个输出；
+    // 按 principal 排序，模拟和实际发送得到同一笔交易
+    let mut output_plan = OutputPlan::default();
+    let postage = postage();
+    let change_output = output_plan.add_output(alkanes_fund_address, postage);
+    let mut queued: Vec<(&Principal, &Vec<WithdrawRequest>)> = batch.iter().collect();
+    queued.sort_by_key(|(principal, _)| **principal);
+    for request in queued.into_iter().flat_map(|(_, requests)| requests) {
+        output_plan
+            .pay(&request.withdraw_address, postage, &request.token_id, request.token_amount)
+            .map_err(FomowellError::TxBuildFailed)?;
+    }
+    for (alkaneid, selected_amount) in selected_alkane_amounts {
+        let change_amount = selected_amount.saturating_sub(required_alkanes.get(&alkaneid).copied().unwrap_or(0));
+        if change_amount > 0 {
+            output_plan.add_edict(change_output, &alkaneid, change_amount).map_err(FomowellError::TxBuildFailed)?;
+        }
+    }
+    let (outputs, protostone) = output_plan
+        .finish(change_output, alkanes_fund_address)
+        .map_err(FomowellError::TxBuildFailed)?;
+    Ok((fund_inputs, outputs, protostone))
+}
+
+/// Last check before signing: running the protostone over the inputs must
+/// give every output what the plan meant it to get.
+fn ensure_as_planned(simulation: &TxSimulation) -> Result<(), FomowellError> {
+    if simulation.problems.is_empty() {
+        return Ok(());
+    }
+    Err(FomowellError::TxBuildFailed(format!(
+        "{} transaction does not match its plan: {}",
+        simulation.plan.kind,
+        simulation.problems.join("; ")
+    )))
 }
 
+/// Key (derivation path) behind one of the canister's own addresses.
+fn signer_of(address: &str) -> Result<&'static str, FomowellError> {
+    [
+        ("alkanes_topup", fomowell_alkanes_topup_address),
+        ("alkanes_fund", fomowell_alkanes_fund_address),
+        ("btc", fomowell_btc_address),
+    ]
+    .into_iter()
+    .find(|(address_type, _)| get_address(address_type.to_string()).is_ok_and(|own| own == address))
+    .map(|(_, path)| path)
+    .ok_or_else(|| FomowellError::TxBuildFailed(format!("no signing key for input address {}", address)))
+}
 
-async fn gather_alkanes_utxo_timer() {
-    if let Err(e) = gather_alkanes_utxo().await {
-        append_log(format!("gather_alkanes_utxo error={}", e));
+/// Signs every input of `plan` with the key of the address it is spent from
+/// and broadcasts the transaction. Returns the txid once the Bitcoin canister
+/// accepted it; nothing is recorded as pending here.
+async fn sign_and_broadcast(chain: &impl chain::Chain, plan: &TxPlan) -> Result<String, FomowellError> {
+    let mut public_keys: HashMap<&'static str, String> = HashMap::new();
+    let mut inputs: Vec<TransactionInput> = Vec::new();
+    for input in &plan.inputs {
+        let signer = signer_of(&input.address)?;
+        let public_key = match public_keys.get(signer) {
+            Some(public_key) => public_key.clone(),
+            None => {
+                let public_key = hex::encode(chain.public_key(signer).await?);
+                public_keys.insert(signer, public_key.clone());
+                public_key
+            }
+        };
+        inputs.push(TransactionInput {
+            txid: input.txid.clone(),
+            vout: input.vout as u32,
+            amount: input.satoshi,
+            address: input.address.clone(),
+            public_key: Some(public_key),
+            signature_type: Some(InputSignatureType::Taproot(TapSighashType::Default)),
+            witness: None,
+            signer: Some(signer.to_string()),
+        });
     }
+    let outputs: Vec<TransactionOutput> = plan
+        .outputs
+        .iter()
+        .map(|output| TransactionOutput {
+            address: output.address.clone(),
+            amount: output.satoshi,
+            op_return: output.op_return.then(|| plan.protostone.to_vec()),
+        })
+        .collect();
+
+    let final_psbt = chain.sign(inputs, outputs).await?;
+    let transaction_bytes = hex::decode(&final_psbt.psbt_hex)
+        .map_err(|e| FomowellError::TxBuildFailed(format!("Failed to decode transaction hex: {}", e)))?;
+    let txid = final_psbt.txid;
+    let broadcast = chain.broadcast(transaction_bytes).await;
+    note_broadcast(&plan.kind, &txid, &broadcast);
+    broadcast.map_err(|message| FomowellError::BroadcastFailed { txid: txid.clone(), message })?;
+    Ok(txid)
 }
 
-async fn check_tx_confirmed(txid: String) -> Result<bool, String> {
-    let url = format!("https://mempool.space/api/tx/{}/status", txid);
-    let request = CanisterHttpRequestArgument {
-        url: url.clone(),
-        max_response_bytes: Some(10_000_000),
-        method: ic_cdk::api::management_canister::http_request::HttpMethod::GET,
-        headers: vec![],
-        body: None,
-        transform: None,
+/// Sweeps topup UTXOs to the fund address as far as `policy` allows. Like
+/// `send_withdraw_request` the ledger only changes after a successful
+/// broadcast; a `dry_run` returns the unsigned transaction instead.
+async fn gather_alkanes_utxo(
+    chain: &impl chain::Chain,
+    policy: &GatherPolicy,
+    dry_run: bool,
+) -> Result<GatherReport, FomowellError> {
+    let alkanes_topup_address = get_address("alkanes_topup".to_string())?;
+    let alkanes_fund_address = get_address("alkanes_fund".to_string())?;
+    let alkanes_btc_address = get_address("btc".to_string())?;
+
+    let pending = PENDING_TXS.with(|p| p.borrow().clone());
+    let topup = ledger_inputs(&alkanes_topup_address);
+    // 不值得扫时直接返回，不查费率也不查 utxo
+    if let Some(reason) = gather::skip_reason(&topup, policy) {
+        return Ok(GatherReport::skipped(reason));
+    }
+    let fee_rate = chain.fee_rate().await?;
+    if let Some(max_fee_rate) = policy.max_fee_rate
+        && fee_rate > max_fee_rate as f64
+    {
+        return Ok(GatherReport::skipped(format!("fee rate {} above max_fee_rate {}", fee_rate, max_fee_rate)));
+    }
+
+    let max_depth = MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow());
+    let fund: Vec<PlannedInput> = ledger_inputs(&alkanes_fund_address)
+        .into_iter()
+        .filter(|input| unconfirmed_depth(&input.txid, &pending) < max_depth)
+        .collect();
+    let alkane_inputs = gather::select_inputs(topup, fund, policy);
+
+    let (mut outputs, protostone) = gather::plan_outputs(&alkane_inputs, &alkanes_fund_address, postage())
+        .and_then(|output_plan| output_plan.finish(0, &alkanes_fund_address))
+        .map_err(FomowellError::TxBuildFailed)?;
+
+    // bitcoin canister 看不到 mempool，需排除被未确认交易花费的 btc utxo
+    let btc_utxos: Vec<UtxoInfo> = chain.btc_utxos(&alkanes_btc_address).await?
+        .into_iter()
+        .filter(|utxo| !is_spent_by_pending(&utxo.txid, utxo.vout as u64, &pending))
+        .collect();
+    let funding = fund_outputs(
+        &alkanes_btc_address,
+        &alkane_inputs,
+        &mut outputs,
+        &protostone,
+        fee_rate,
+        policy.max_inputs as usize,
+        &btc_utxos,
+    )?;
+
+    let plan = TxPlan {
+        kind: "gather".to_string(),
+        inputs: alkane_inputs.into_iter().chain(funding.inputs).collect(),
+        outputs,
+        protostone,
+        fee_rate,
+        fee: funding.fee,
+        change: funding.change,
+        fee_rounding: funding.fee_rounding,
     };
+    let simulation = txplan::simulate(&plan, txplan::network(), &alkanes_fund_address).map_err(FomowellError::TxBuildFailed)?;
+    // dry run 也要检查，计划有问题时报错而不是当作可以广播的交易返回
+    ensure_as_planned(&simulation)?;
+    if dry_run {
+        return Ok(GatherReport { simulation: Some(simulation), ..Default::default() });
+    }
+    let txid = sign_and_broadcast(chain, &plan).await?;
+
+    let outpoints_of = |address: &str| -> Vec<(String, u64)> {
+        plan.inputs
+            .iter()
+            .filter(|input| input.address == address)
+            .map(|input| (input.txid.clone(), input.vout))
+            .collect()
+    };
+    let spent_entries: Vec<(String, String, AlkaneUtxoRecord)> = [&alkanes_topup_address, &alkanes_fund_address]
+        .into_iter()
+        .flat_map(|address| {
+            take_utxos(address, &outpoints_of(address))
+                .into_iter()
+                .map(|(alkaneid, record)| (address.clone(), alkaneid, record))
+        })
+        .collect();
+    let spent_outpoints: Vec<(String, u64)> = plan.inputs.iter().map(|input| (input.txid.clone(), input.vout)).collect();
+    record_pending_tx(txid.clone(), "gather", spent_outpoints, (alkanes_fund_address.clone(), 0), Vec::new(), spent_entries);
+
+    // 和提现找零一样，归集输出立即记入 fund 账本，不用等下一次同步；
+    // 否则交易确认后、同步前这段时间储备会少算
+    for output in &simulation.output_alkanes {
+        let Some(planned) = plan.outputs.get(output.output as usize).filter(|o| o.address == alkanes_fund_address) else {
+            continue;
+        };
+        for (alkaneid, amount) in &output.alkanes {
+            let record = AlkaneUtxoRecord { amount: *amount, txid: txid.clone(), vout: output.output as u64, satoshi: planned.satoshi };
+            if let Err(e) = put_utxo(&alkanes_fund_address, alkaneid, record) {
+                append_log(format!("[gather] output not recorded txid={} vout={} err={}", txid, output.output, e));
+            }
+        }
+    }
+    append_log(format!(
+        "[gather] broadcast txid={} inputs={} fee={} change={} fee_rounding={}",
+        txid,
+        plan.inputs.len(),
+        plan.fee,
+        plan.change,
+        plan.fee_rounding
+    ));
+    Ok(GatherReport { simulation: Some(simulation), txid: Some(txid), skipped: None })
+}
+
+async fn gather_alkanes_utxo_timer() {
+    if pause::is_paused(PauseSwitch::Gather) {
+        append_log("[gather] gather paused, skipped");
+        return;
+    }
+    let policy = gather::policy();
+    if let Ok(GatherReport { skipped: Some(reason), .. }) =
+        run_tx_flow("gather", gather_alkanes_utxo(&chain::IcChain, &policy, false)).await
+    {
+        append_log(format!("[gather] skipped: {}", reason));
+    }
+}
+
+/// 按当前策略立即归集一次。`dry_run` 只返回计划的交易，不签名也不广播
+#[update(guard = "guard_admin")]
+async fn trigger_gather(dry_run: bool) -> Result<GatherReport, FomowellError> {
+    let policy = gather::policy();
+    if dry_run {
+        derive_addresses().await?;
+        return gather_alkanes_utxo(&chain::IcChain, &policy, true).await;
+    }
+    pause::ensure_running(PauseSwitch::Gather)?;
+    run_tx_flow("gather", gather_alkanes_utxo(&chain::IcChain, &policy, false)).await
+}
 
-    let cycles: u128 = 2_000_000_000;
+/// Same as `trigger_gather(true)`, for operators and auditors.
+#[update(guard = "guard_operator_or_auditor")]
+async fn simulate_gather() -> Result<GatherReport, FomowellError> {
+    derive_addresses().await?;
+    gather_alkanes_utxo(&chain::IcChain, &gather::policy(), true).await
+}
+
+/// Builds the transaction the next withdraw run would send for the current
+/// queue and returns it unsigned. An update call because it needs the fee
+/// rate and the BTC UTXOs; nothing is signed, broadcast or committed.
+#[update(guard = "guard_operator_or_auditor")]
+async fn simulate_withdraw() -> Result<TxSimulation, FomowellError> {
+    let withdraw_requests = WITHDRAW_REQUESTS.with(|requests| requests.borrow().clone());
+    if withdraw_requests.values().all(|v| v.is_empty()) {
+        return Err(FomowellError::NotFound("queued withdraw requests".into()));
+    }
+    derive_addresses().await?;
+    let (plan, _) = plan_withdraw(&chain::IcChain, &withdraw_requests).await?;
+    txplan::simulate(&plan, txplan::network(), &get_address("alkanes_fund".to_string())?).map_err(FomowellError::TxBuildFailed)
+}
 
-    match http_request(request, cycles).await {
-        Ok((response,)) => {
-            let body_str = String::from_utf8(response.body)
-                .map_err(|e| format!("Failed to parse response body: {}", e))?;
-            
-            let json: Value = serde_json::from_str(&body_str)
-                .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+#[query]
+fn get_gather_policy() -> GatherPolicy {
+    gather::policy()
+}
 
-            json["confirmed"]
-                .as_bool()
-                .ok_or_else(|| {
-                    format!(
-                        "Missing or invalid confirmed field in response: {}",
-                        body_str
-                    )
-                })
-                .map(|confirmed| {
-                    append_log(format!("[check-tx] txid={} confirmed={}", txid, confirmed));
-                    confirmed
-                })
+#[update(guard = "guard_admin")]
+async fn set_gather_policy(policy: GatherPolicy) -> Result<ProposalReceipt, FomowellError> {
+    gather::validate_policy(&policy).map_err(FomowellError::InvalidArgument)?;
+    proposals::submit(ProposalAction::SetGatherPolicy(policy))
+}
+
+/// Runs one withdraw / gather attempt under the transaction lock.
+///
+/// The flows commit nothing before their broadcast succeeded, so a failure
+/// only needs reporting: it is logged and recorded as a `TxFlowFailed` event.
+async fn run_tx_flow<T>(
+    flow: &'static str,
+    run: impl std::future::Future<Output = Result<T, FomowellError>>,
+) -> Result<T, FomowellError> {
+    let result = match chain::FlowGuard::acquire(flow) {
+        Ok(_guard) => match derive_addresses().await {
+            Ok(()) => run.await,
+            Err(e) => Err(e),
+        },
+        Err(e) => {
+            append_log(format!("[{}] skipped: {}", flow, e));
+            return Err(e);
+        }
+    };
+    if let Err(e) = &result {
+        append_log(format!("[{}] failed, nothing committed: {}", flow, e));
+        // 广播失败已经记为 TxBroadcastFailed
+        if !matches!(e, FomowellError::BroadcastFailed { .. }) {
+            events::event(EventKind::TxFlowFailed).detail(format!("{}: {}", flow, e)).record();
+        }
+    }
+    result
+}
+
+/// Optional mempool.space cross-check. Only the `confirmed` flag survives the
+/// outcall transform so every replica agrees on the response.
+async fn check_tx_status_http(txid: &str) -> Result<TxStatus, String> {
+    let base = match IC_BITCOIN_NETWORK {
+        BitcoinNetwork::Mainnet => "https://mempool.space/api",
+        _ => "https://mempool.space/testnet4/api",
+    };
+    let url = format!("{}/tx/{}/status", base, txid);
+    // 每轮检查都会重新查询，这里不需要重试
+    let request = ic::http::JsonRequest::get(url, 2_000)
+        .keep(&["/confirmed"])
+        .retry(ic::http::RetryPolicy::NONE);
+
+    match ic::http::send_json(&request).await {
+        Ok(json) => json["confirmed"]
+            .as_bool()
+            .map(|confirmed| if confirmed { TxStatus::Confirmed } else { TxStatus::Unconfirmed })
+            .ok_or_else(|| format!("Missing or invalid confirmed field in response: {}", json))
+            .inspect(|status| {
+                append_log(format!("[check-tx] txid={} status={:?}", txid, status));
+            }),
+        // mempool.space 对既不在链上也不在 mempool 里的交易返回 404
+        Err(ic::http::HttpError::Status { status: 404, .. }) => {
+            append_log(format!("[check-tx] txid={} status=NotFound", txid));
+            Ok(TxStatus::NotFound)
         }
-        Err((code, msg)) => {
-            append_log(format!("[check-tx] http error txid={} code={:?} msg={}", txid, code, msg));
-            Err(format!("HTTP request failed: {:?}, {}", code, msg))
+        Err(e) => {
+            append_log(format!("[check-tx] http error txid={} err={}", txid, e));
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Bitcoin 以反序显示 txid，bitcoin canister 返回的是内部字节序
+fn txid_to_hex(txid: &[u8]) -> String {
+    let mut bytes = txid.to_vec();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+/// Returns every UTXO of `address`, following `next_page`, plus the tip height.
+async fn fetch_utxos(address: &str, min_confirmations: Option<u32>) -> Result<(Vec<Utxo>, u32), FomowellError> {
+    let mut utxos = Vec::new();
+    let mut filter = min_confirmations.map(UtxoFilter::MinConfirmations);
+    loop {
+        budget::charge("bitcoin_get_utxos", GET_UTXOS_CYCLES)?;
+        let result = bitcoin_get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            network: IC_BITCOIN_NETWORK,
+            filter,
+        })
+        .await;
+        metrics::record("bitcoin_get_utxos", result.is_ok(), GET_UTXOS_CYCLES);
+        let (response,) = result.map_err(|e| error::call_failed("bitcoin_get_utxos", e))?;
+        utxos.extend(response.utxos);
+        match response.next_page {
+            Some(page) => filter = Some(UtxoFilter::Page(page)),
+            None => return Ok((utxos, response.tip_height)),
         }
     }
 }
@@ -840,40 +1880,26 @@ pub struct UtxoInfo {
     vout: u32,
 }
 
-#[update]
-async fn get_btc_utxos(address: String) -> Result<Vec<UtxoInfo>, String> {
-    let network = IC_BITCOIN_NETWORK;
-    let filter = None;
-
-    let query_btc_res: Result<(GetUtxosResponse,), (ic_cdk::api::call::RejectionCode, String)> =
-        bitcoin_get_utxos(GetUtxosRequest {
-            address: address.clone(),
-            network,
-            filter,
-        })
-        .await;
+#[update(guard = "guard_operator_or_auditor")]
+async fn get_btc_utxos(address: String) -> Result<Vec<UtxoInfo>, FomowellError> {
+    budget::check_rate(RateScope::AddressQuery, caller())?;
+    fetch_btc_utxos(&address).await
+}
 
-    match query_btc_res {
-        Ok((get_utxos_response,)) => {
-            let utxos = get_utxos_response.utxos;
-            let mut utxo_info_list: Vec<UtxoInfo> = Vec::new();
-
-            for utxo in utxos {
-                let value = utxo.value;
-                let txid = hex::encode(utxo.outpoint.txid);
-                let vout = utxo.outpoint.vout;
-                let utxo_info = UtxoInfo { value, txid, vout };
-                utxo_info_list.push(utxo_info);
-            }
-            
-            utxo_info_list.sort_by(|a, b| b.value.cmp(&a.value));
-            
-            Ok(utxo_info_list)
-        }
-        Err((code, msg)) => {
-            Err(format!("Failed to get UTXOs for address {}: {:?}, {}", address, code, msg))
-        }
-    }
+/// All UTXOs of `address`, largest first. Unlike `get_btc_utxos` this is not
+/// rate limited, the withdraw and gather flows use it.
+async fn fetch_btc_utxos(address: &str) -> Result<Vec<UtxoInfo>, FomowellError> {
+    let (utxos, _) = fetch_utxos(address, None).await?;
+    let mut utxo_info_list: Vec<UtxoInfo> = utxos
+        .into_iter()
+        .map(|utxo| UtxoInfo {
+            value: utxo.value,
+            txid: txid_to_hex(&utxo.outpoint.txid),
+            vout: utxo.outpoint.vout,
+        })
+        .collect();
+    utxo_info_list.sort_by_key(|utxo| std::cmp::Reverse(utxo.value));
+    Ok(utxo_info_list)
 }
 
 // Tools
@@ -890,14 +1916,31 @@ fn bitcoin_network_to_ic_bitcoin_network(
     }
 }
 
-async fn generate_address(input: String) -> String {
+async fn generate_address(input: String) -> Result<String, FomowellError> {
     let address = ic::p2tr_key_only::get_address(
         IC_BITCOIN_NETWORK,
         SCHNORR_KEY_NAME.to_string(),
         get_derivation_path(&input),
     )
-    .await;
-    address.to_string()
+    .await?;
+    Ok(address.to_string())
+}
+
+/// 派生还没缓存的地址。init 里不能发起调用，失败的地址留给下一次 timer 补上
+async fn derive_addresses() -> Result<(), FomowellError> {
+    let paths = [
+        ("alkanes_topup", fomowell_alkanes_topup_address),
+        ("alkanes_fund", fomowell_alkanes_fund_address),
+        ("btc", fomowell_btc_address),
+    ];
+    for (key, path) in paths {
+        if get_address(key.to_string()).is_ok() {
+            continue;
+        }
+        let address = generate_address(path.to_string()).await?;
+        ADDRESSES.with(|addrs| addrs.borrow_mut().insert(key.to_string(), address));
+    }
+    Ok(())
 }
 
 
@@ -908,49 +1951,74 @@ fn get_derivation_path(input: &str) -> Vec<Vec<u8>> {
 
 /// 地址类型：`"alkanes_topup"`, `"alkanes_fund"`, 或 `"btc"`
 #[query]
-fn get_address(address_type: String) -> Result<String, String> {
+fn get_address(address_type: String) -> Result<String, FomowellError> {
     ADDRESSES.with(|addrs| {
         addrs.borrow()
             .get(&address_type)
             .cloned()
-            .ok_or_else(|| format!("Address type '{}' not initialized", address_type))
+            .ok_or_else(|| FomowellError::NotFound(format!("address type '{}'", address_type)))
     })
 }
 
 
-async fn get_feerate() -> Result<String, String> {
-    let fee_canister =
-        crate::did::fee_rate_canister_did::Service(Principal::from_text(FEE_RATE_CANISTER_ID).unwrap());
-    let query_fee_rate__res = fee_canister.get_mempool_tx_fee_rate().await;
-    let mut current_fee_rate = 0.0;
-
-    match query_fee_rate__res {
-        Ok(result_tuple) => {
-            let fee_rate_view = result_tuple.0;
-            current_fee_rate = (fee_rate_view.high) as f64;
-        }
-        Err((code, msg)) => {
-            return Err(format!("Error get mempool fee_rate: {:?}, {}", code, msg));
-        }
+/// sat/vB. A zero rate is treated as unavailable rather than building a
+/// transaction that never confirms.
+async fn get_feerate() -> Result<f64, FomowellError> {
+    let fee_canister = Principal::from_text(FEE_RATE_CANISTER_ID)
+        .map_err(|e| FomowellError::Internal(format!("Invalid fee rate canister id: {}", e)))?;
+    let (fee_rate_view,) = crate::did::fee_rate_canister_did::Service(fee_canister)
+        .get_mempool_tx_fee_rate()
+        .await
+        .map_err(|(code, msg)| FomowellError::FeeOracleUnavailable(format!("{:?}, {}", code, msg)))?;
+    if fee_rate_view.high == 0 {
+        return Err(FomowellError::FeeOracleUnavailable("fee rate canister returned 0".into()));
     }
-    Ok(current_fee_rate.to_string())
+    Ok(fee_rate_view.high as f64)
 }
 
 // Query
 
-#[update]
-async fn set_owner_ic(new_owner: Principal) -> Result<String, String> {
-    set_owner(new_owner)
+/// 两步转移 owner：owner 提名，新 owner 调用 `accept_owner_ic` 确认
+#[update(guard = "guard_owner")]
+async fn propose_owner_ic(new_owner: Principal) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::ProposeOwner(new_owner))
 }
 
-#[update]
-async fn upload_alkanes(batch: Vec<AlkaneRecord>) -> Result<String, String> {
-    batch_upload(batch)
+#[update(guard = "guard_pending_owner")]
+async fn accept_owner_ic() -> Result<OwnerReceipt, FomowellError> {
+    access::accept_owner()
+}
+
+#[query]
+fn get_owner_ic() -> (Principal, Option<Principal>) {
+    (get_owner(), access::pending_owner())
+}
+
+#[update(guard = "guard_admin")]
+async fn grant_role_ic(principal: Principal, role: Role) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::GrantRole(principal, role))
+}
+
+#[update(guard = "guard_admin")]
+async fn revoke_role_ic(principal: Principal, role: Role) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::RevokeRole(principal, role))
+}
+
+#[query(guard = "guard_admin_or_auditor")]
+fn list_roles_ic() -> Vec<(Principal, Vec<Role>)> {
+    access::list_roles()
+}
+
+#[update(guard = "guard_uploader")]
+async fn upload_alkanes(batch: Vec<AlkaneRecord>) -> Result<u64, FomowellError> {
+    let uploaded = batch.len() as u64;
+    batch_upload(batch)?;
+    Ok(uploaded)
 }
 
 #[query]
-fn get_alkane(txid: String) -> Result<AlkaneRecord, String> {
-    alkanes_query(txid)
+fn get_alkane(txid: String) -> Result<AlkaneRecord, FomowellError> {
+    alkanes_query(txid.clone()).map_err(|_| FomowellError::NotFound(format!("alkane record {}", txid)))
 }
 
 #[query]
@@ -959,111 +2027,205 @@ fn list_alkanes() -> Vec<AlkaneRecord> {
 }
 
 
-#[update]
-async fn clear_alkanes() -> Result<String, String> {
-    clear()
+#[update(guard = "guard_admin")]
+async fn clear_alkanes() -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::ClearAll)
 }
 
 
 
-#[update]
-async fn add_white_token_ic(token: String) -> Result<String, String> {
+#[update(guard = "guard_operator")]
+async fn add_white_token_ic(token: String, divisibility: Option<u8>) -> Result<TokenReceipt, FomowellError> {
+    pause::ensure_running(PauseSwitch::Whitelist)?;
     if is_white_token(token.clone()) {
-        return Err("Token already in whitelist".into());
+        return Err(FomowellError::AlreadyExists(format!("whitelisted token {}", token)));
     }
 
-    let url = format!("https://open-api.unisat.io/v1/indexer/alkanes/{}/info", token);
-    let request = CanisterHttpRequestArgument {
-        url: url.clone(),
-        max_response_bytes: Some(10_000_000),
-        method: ic_cdk::api::management_canister::http_request::HttpMethod::GET,
-        headers: vec![],
-        body: None,
-        transform: None,
+    let indexer = indexer::configured()?;
+    let info = indexer.token_info(&token).await.inspect_err(|e| {
+        append_log(format!("[token-create] indexer error token={} err={}", token, e));
+    })?;
+    // 在创建 meme token 之前确定精度并检查配置，失败时不留下任何状态
+    let divisibility = conversion::resolve_divisibility(divisibility, info.divisibility).map_err(FomowellError::InvalidArgument)?;
+    let mut config = TokenConfig { divisibility, ..Default::default() };
+    conversion::validate(&config).map_err(FomowellError::InvalidArgument)?;
+    let symbol = info.symbol;
+    let name = info.name;
+
+    let create_arg = CreateMemeTokenArg {
+        creator: Some(caller()),
+        ticker: symbol.clone(),
+        logo_base64: None,
+        twitter: None,
+        logo: "".to_string(),
+        name: name.clone(),
+        description: format!("Alkanes token: {}", name),
+        website: None,
+        meme_token_type: MemeTokenType::Brc20(token.clone()),
+        swap_fee_rate: None,
+        dev_buy: Some(candid::Nat::from(0u64)),
+        telegram: None,
     };
 
-    let cycles: u128 = 2_000_000_000;
-
-    match http_request(request, cycles).await {
-        Ok((response,)) => {
-            let body_str = String::from_utf8(response.body)
-                .map_err(|e| format!("Failed to parse response body: {}", e))?;
-            
-            let json: Value = serde_json::from_str(&body_str)
-                .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+    let token_canister_id = Principal::from_text(FOMOWELLL_MAINNET_CANISTER_ID)
+        .map_err(|e| format!("Invalid canister ID: {}", e))?;
+    let service = Service(token_canister_id);
 
-            if json["code"].as_u64() != Some(0) {
-                return Err(format!("API error: {}", json["msg"].as_str().unwrap_or("Unknown error")));
-            }
-
-            let data = json["data"].as_object()
-                .ok_or_else(|| "Missing data field".to_string())?;
-            let token_data = data["tokenData"].as_object()
-                .ok_or_else(|| "Missing tokenData field".to_string())?;
-
-            let symbol = token_data["symbol"].as_str()
-                .ok_or_else(|| "Missing symbol field".to_string())?
-                .to_string();
-            let name = token_data["name"].as_str()
-                .ok_or_else(|| "Missing name field".to_string())?
-                .to_string();
-
-            let create_arg = CreateMemeTokenArg {
-                creator: Some(caller()),
-                ticker: symbol.clone(),
-                logo_base64: None,
-                twitter: None,
-                logo: "".to_string(),
-                name: name.clone(),
-                description: format!("Alkanes token: {}", name),
-                website: None,
-                meme_token_type: MemeTokenType::Brc20(token.clone()),
-                swap_fee_rate: None,
-                dev_buy: Some(candid::Nat::from(0u64)),
-                telegram: None,
-            };
-
-            let token_canister_id = Principal::from_text(FOMOWELLL_MAINNET_CANISTER_ID)
-                .map_err(|e| format!("Invalid canister ID: {}", e))?;
-            let service = Service(token_canister_id);
-            
-            match service.create_token(create_arg).await {
-                Ok((result,)) => {
-                    match result {
-                        crate::did::fomowell_token::Result3::Ok(meme_token) => {
-                            let meme_token_id = meme_token.id;
-                            if let Err(e) = set_token_id_mapping(token.clone(), meme_token_id) {
-                                return Err(format!("Failed to store token ID mapping: {}", e));
-                            }
-                            
-                            match add_white_token(token.clone()) {
-                                Ok(_) => {
-                                    append_log(format!("[token-create] success token={} meme_token_id={}", token, meme_token_id));
-                                    Ok(format!("Token {} created successfully with meme_token_id {}", token, meme_token_id))
-                                },
-                                Err(e) => {
-                                    append_log(format!("[token-create] add whitelist failed token={} err={}", token, e));
-                                    Err(format!("Token failed to add to whitelist: {}", e))
-                                },
-                            }
-                        }
-                        crate::did::fomowell_token::Result3::Err(e) => {
-                            append_log(format!("[token-create] service returned error token={} err={}", token, e));
-                            Err(format!("Failed to create token: {}", e))
-                        }
-                    }
-                }
-                Err(e) => {
-                    append_log(format!("[token-create] call create_token failed token={} err={:?}", token, e));
-                    Err(format!("Failed to call create_token: {:?}", e))
-                },
-            }
+    let meme_token = match service.create_token(create_arg).await {
+        Ok((crate::did::fomowell_token::Result3::Ok(meme_token),)) => meme_token,
+        Ok((crate::did::fomowell_token::Result3::Err(e),)) => {
+            append_log(format!("[token-create] service returned error token={} err={}", token, e));
+            return Err(FomowellError::LedgerCallFailed { code: "Rejected".into(), message: e });
         }
         Err((code, message)) => {
-            append_log(format!("[token-create] http error token={} code={:?} message={}", token, code, message));
-            Err(format!("HTTP request failed: code={:?}, message={}", code, message))
+            append_log(format!("[token-create] call create_token failed token={} code={:?} err={}", token, code, message));
+            return Err(FomowellError::LedgerCallFailed { code: format!("{:?}", code), message });
         }
+    };
+    let meme_token_id = meme_token.id;
+    // 小数位按 indexer 和 meme token 实际值换算，其余限制之后通过提案设置；
+    // meme token 的小数位只有创建后才知道，写入映射之前再检查一次。
+    // meme token 已经建好，检查失败也要记下映射，否则重试会再建一个
+    config.decimals = meme_token.decimals;
+    let rejected = conversion::validate(&config).err();
+    if let Some(e) = &rejected {
+        append_log(format!("[token-create] config rejected token={} meme_token_id={} err={}", token, meme_token_id, e));
+        config = conversion::held_back(&config);
     }
+    set_token_id_mapping(token.clone(), meme_token_id)
+        .map_err(|e| format!("Failed to store token ID mapping: {}", e))?;
+    set_token_config(&token, config);
+    add_white_token(token.clone()).inspect_err(|e| {
+        append_log(format!("[token-create] add whitelist failed token={} err={}", token, e));
+    })?;
+    append_log(format!("[token-create] success token={} meme_token_id={}", token, meme_token_id));
+    events::event(EventKind::TokenWhitelisted)
+        .principal(caller())
+        .alkaneid(token.clone())
+        .detail(format!("meme_token_id={} divisibility={} decimals={}", meme_token_id, divisibility, meme_token.decimals))
+        .record();
+    if let Some(e) = rejected {
+        return Err(FomowellError::InvalidArgument(format!(
+            "{}; {} is whitelisted as meme token {} but takes no topups until set_token_config_ic stores a valid config",
+            e, token, meme_token_id
+        )));
+    }
+    Ok(TokenReceipt { alkaneid: token, meme_token_id })
+}
+
+/// The unisat API key is masked in the returned config.
+#[query]
+fn get_indexer_config() -> IndexerConfig {
+    let mut config = indexer::config();
+    if config.unisat_api_key.is_some() {
+        config.unisat_api_key = Some("***".to_string());
+    }
+    config
+}
+
+#[update(guard = "guard_admin")]
+async fn set_indexer_config(config: IndexerConfig) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::SetIndexerConfig(config))
+}
+
+/// Operator/Auditor passthroughs to the configured indexer, for diagnosing drift
+/// between the ledger and the chain. Each call spends outcall cycles.
+#[update(guard = "guard_operator_or_auditor")]
+async fn indexer_balances(address: String) -> Result<Vec<AlkaneBalance>, FomowellError> {
+    budget::check_rate(RateScope::AddressQuery, caller())?;
+    let indexer = indexer::configured()?;
+    Ok(indexer.balances(&address).await?)
+}
+
+#[update(guard = "guard_operator_or_auditor")]
+async fn indexer_address_utxos(address: String) -> Result<Vec<AlkaneUtxo>, FomowellError> {
+    budget::check_rate(RateScope::AddressQuery, caller())?;
+    let indexer = indexer::configured()?;
+    Ok(indexer.address_utxos(&address).await?)
+}
+
+#[update(guard = "guard_operator_or_auditor")]
+async fn indexer_outpoint_balances(txid: String, vout: u32) -> Result<Vec<AlkaneBalance>, FomowellError> {
+    let indexer = indexer::configured()?;
+    Ok(indexer.outpoint_balances(&txid, vout).await?)
+}
+
+#[update(guard = "guard_operator_or_auditor")]
+async fn indexer_trace(txid: String, vout: u32) -> Result<AlkaneTrace, FomowellError> {
+    let indexer = indexer::configured()?;
+    Ok(indexer.trace(&txid, vout).await?)
+}
+
+/// Loads data served by the `Mock` provider, for local testing.
+#[update(guard = "guard_admin")]
+async fn seed_mock_indexer(
+    tokens: Vec<AlkaneTokenInfo>,
+    utxos: Vec<(String, Vec<AlkaneUtxo>)>,
+    traces: Vec<AlkaneTrace>,
+) -> Result<(), FomowellError> {
+    for token in tokens {
+        indexer::mock::mock_token(token);
+    }
+    for (address, address_utxos) in utxos {
+        indexer::mock::mock_address_utxos(&address, address_utxos);
+    }
+    for trace in traces {
+        indexer::mock::mock_trace(trace);
+    }
+    Ok(())
+}
+
+/// Newest first, at most 500 per call.
+#[query(guard = "guard_operator_or_auditor")]
+fn get_events(filter: EventFilter) -> Vec<Event> {
+    events::query(&filter)
+}
+
+#[query]
+fn get_event_retention() -> RetentionConfig {
+    events::retention()
+}
+
+#[update(guard = "guard_admin")]
+async fn set_event_retention(retention: RetentionConfig) -> Result<ProposalReceipt, FomowellError> {
+    events::validate_retention(&retention).map_err(FomowellError::InvalidArgument)?;
+    proposals::submit(ProposalAction::SetEventRetention(retention))
+}
+
+fn collect_metrics() -> Metrics {
+    Metrics {
+        timestamp_nanos: time(),
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        heap_memory_bytes: metrics::heap_memory_bytes(),
+        stable_memory_bytes: metrics::stable_memory_bytes(),
+        withdraw_queue_depth: WITHDRAW_REQUESTS.with(|r| r.borrow().values().map(|v| v.len() as u64).sum()),
+        pending_txs: PENDING_TXS.with(|p| p.borrow().len() as u64),
+        ledger_utxos: utxo_count(),
+        alkane_records: count(),
+        operations: metrics::operations(),
+    }
+}
+
+#[query]
+fn get_metrics() -> Metrics {
+    collect_metrics()
+}
+
+#[query]
+fn get_budget_status() -> BudgetStatus {
+    budget::status()
+}
+
+#[update(guard = "guard_admin")]
+async fn set_budget_config(config: BudgetConfig) -> Result<ProposalReceipt, FomowellError> {
+    budget::validate_config(&config).map_err(FomowellError::InvalidArgument)?;
+    proposals::submit(ProposalAction::SetBudgetConfig(config))
+}
+
+/// HTTP gateway 入口，供 Prometheus 抓取 `/metrics`
+#[query]
+fn http_request(request: HttpGatewayRequest) -> HttpGatewayResponse {
+    metrics::serve(&request, collect_metrics)
 }
 
 #[query]
@@ -1080,15 +2242,15 @@ fn get_logs(offset: u64, limit: u64) -> Vec<LogEntry> {
     })
 }
 
-#[update]
-async fn clear_logs() -> Result<String, String> {
+#[update(guard = "guard_admin")]
+async fn clear_logs() -> Result<(), FomowellError> {
     LOGS.with(|logs| logs.borrow_mut().clear());
-    Ok("logs cleared".into())
+    Ok(())
 }
 
 
-#[update]
-async fn batch_upload_utxos(utxos: Vec<(String, String, AlkaneUtxoRecord)>) -> Result<String, String> {
+#[update(guard = "guard_uploader")]
+async fn batch_upload_utxos(utxos: Vec<(String, String, AlkaneUtxoRecord)>) -> Result<u64, FomowellError> {
     let mut uploaded = 0;
     let mut errors = Vec::new();
     
@@ -1100,9 +2262,13 @@ async fn batch_upload_utxos(utxos: Vec<(String, String, AlkaneUtxoRecord)>) -> R
     }
     
     if errors.is_empty() {
-        Ok(format!("Batch upload successful: {} UTXOs saved", uploaded))
+        Ok(uploaded)
     } else {
-        Err(format!("Batch upload completed with errors. Success: {}, Errors: {}", uploaded, errors.join("; ")))
+        Err(FomowellError::InvalidArgument(format!(
+            "Batch upload completed with errors. Success: {}, Errors: {}",
+            uploaded,
+            errors.join("; ")
+        )))
     }
 }
 #[query]
@@ -1114,36 +2280,377 @@ fn get_utxos_by_address_and_alkaneid(address: String, alkaneid: String) -> Vec<A
 fn get_all_utxos_ic() -> Vec<(String, String, AlkaneUtxoRecord)> {
     get_all_utxos()
 }
-#[update]
-async fn batch_remove_utxos(utxos: Vec<(String, String, String, u64)>) -> Result<String, String> {
-    let mut removed = 0;
-    let mut errors = Vec::new();
-    
-    for (address, alkaneid, txid, vout) in utxos {
-        match remove_utxo(address.clone(), alkaneid.clone(), txid.clone(), vout) {
-            Ok(_) => removed += 1,
-            Err(e) => errors.push(format!("Failed to remove UTXO for address: {}, alkaneid: {}, txid: {}, vout: {}, error: {}", 
-                address, alkaneid, txid, vout, e)),
+
+/// Ledger entries by address, alkane and/or outpoint, at most 500 per page.
+#[query]
+fn list_utxos(query: UtxoQuery) -> Result<UtxoPage, FomowellError> {
+    query_utxos(&query).map_err(FomowellError::InvalidArgument)
+}
+#[update(guard = "guard_uploader")]
+async fn batch_remove_utxos(utxos: Vec<(String, String, String, u64)>) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::RemoveUtxos(utxos))
+}
+
+
+#[update(guard = "guard_operator")]
+async fn remove_white_token_ic(token: String) -> Result<(), FomowellError> {
+    pause::ensure_running(PauseSwitch::Whitelist)?;
+    if !is_white_token(token.clone()) {
+        return Err(FomowellError::NotFound(format!("whitelisted token {}", token)));
+    }
+    remove_white_token(token.clone())?;
+    events::event(EventKind::TokenRemoved).principal(caller()).alkaneid(token).record();
+    Ok(())
+}
+
+#[query]
+fn get_white_tokens_ic() -> Vec<String> {
+    get_white_tokens()
+}
+
+/// Tokens without a stored config convert 1:1 with no limits or fee.
+#[query]
+fn get_token_config_ic(alkaneid: String) -> TokenConfig {
+    get_token_config(&alkaneid)
+}
+
+#[query]
+fn list_token_configs() -> Vec<(String, TokenConfig)> {
+    get_token_configs()
+}
+
+#[update(guard = "guard_admin")]
+async fn set_token_config_ic(alkaneid: String, config: TokenConfig) -> Result<ProposalReceipt, FomowellError> {
+    if !is_white_token(alkaneid.clone()) {
+        return Err(FomowellError::NotFound(format!("whitelisted token {}", alkaneid)));
+    }
+    conversion::validate(&config).map_err(FomowellError::InvalidArgument)?;
+    proposals::submit(ProposalAction::SetTokenConfig(alkaneid, config))
+}
+
+
+/// 按 alkane 汇总存入、入账、待提现、已提现和持有数量
+fn collect_reserves() -> Vec<AlkaneReserve> {
+    // 对账只做报告，溢出时停在 u128::MAX，不让查询失败
+    fn add(totals: &mut HashMap<String, u128>, alkaneid: &str, amount: u128) {
+        let total = totals.entry(alkaneid.to_string()).or_insert(0);
+        *total = total.saturating_add(amount);
+    }
+
+    let mut deposited: HashMap<String, u128> = HashMap::new();
+    for record in get_all() {
+        add(&mut deposited, &record.alkaneid, record.amount);
+    }
+    let mut requested: HashMap<String, u128> = HashMap::new();
+    let mut fee_amounts: HashMap<String, u128> = HashMap::new();
+    for (alkaneid, amount) in fees::state().accrued {
+        add(&mut fee_amounts, &alkaneid, amount);
+    }
+    // 排队中的 sweep 已经算在 accrued 里；提现的手续费广播后才入账，排队时算在待提现里
+    WITHDRAW_REQUESTS.with(|r| {
+        for request in r.borrow().values().flatten().filter(|r| !is_fee_sweep(r)) {
+            add(&mut requested, &request.token_id, request.token_amount.saturating_add(request.fee.unwrap_or(0)));
+        }
+    });
+    let mut held: HashMap<String, u128> = HashMap::new();
+    for address in alkanes_sync::SYNCED_ADDRESSES.iter().filter_map(|t| get_address(t.to_string()).ok()) {
+        for (_, alkaneid, utxo) in get_utxos_by_address(address) {
+            add(&mut held, &alkaneid, utxo.amount);
         }
     }
-    
-    if errors.is_empty() {
-        Ok(format!("Batch remove successful: {} UTXOs removed", removed))
-    } else {
-        Err(format!("Batch remove completed with errors. Success: {}, Errors: {}", removed, errors.join("; ")))
+    PENDING_TXS.with(|p| {
+        for tx in p.borrow().iter() {
+            for (alkaneid, amount) in tx.in_flight.iter().flatten() {
+                add(&mut held, alkaneid, *amount);
+            }
+        }
+    });
+    reserves::reserves(&deposited, &requested, &held, &fee_amounts)
+}
+
+/// Logs every broken reserve invariant and pauses topup and withdraw when
+/// there is one.
+fn check_solvency(trigger: &str) {
+    let mut violations = Vec::new();
+    for reserve in collect_reserves() {
+        for violation in reserve.violations() {
+            violations.push((reserve.alkaneid.clone(), violation));
+        }
+    }
+    if violations.is_empty() {
+        return;
+    }
+    for (alkaneid, violation) in &violations {
+        append_log(format!("[solvency] mismatch trigger={} {}: {}", trigger, alkaneid, violation));
+        events::event(EventKind::SolvencyMismatch)
+            .alkaneid(alkaneid.clone())
+            .detail(format!("trigger={} {}", trigger, violation))
+            .record();
+    }
+    let violations: Vec<String> = violations.iter().map(|(alkaneid, v)| format!("{}: {}", alkaneid, v)).collect();
+    pause::pause(
+        &[PauseSwitch::Topup, PauseSwitch::Withdraw],
+        &format!("solvency check failed: {}", violations.join("; ")),
+        true,
+    );
+}
+
+/// Per-alkane accounting; `held` covers the topup and fund ledger plus
+/// alkanes in unconfirmed gather transactions.
+#[query]
+fn get_reserves() -> Vec<AlkaneReserve> {
+    collect_reserves()
+}
+
+/// Treasury account and the fees held as alkanes in the fund.
+#[query]
+fn get_fees() -> FeeState {
+    fees::state()
+}
+
+/// `None` stops crediting topup fees to a treasury; they stay in the fund.
+#[update(guard = "guard_admin")]
+async fn set_treasury(account: Option<TreasuryAccount>) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::SetTreasury(account))
+}
+
+/// Sends every fee accrued for `alkaneid` to `withdraw_address` through the
+/// withdraw queue.
+#[update(guard = "guard_admin")]
+async fn sweep_fees(alkaneid: String, withdraw_address: String) -> Result<ProposalReceipt, FomowellError> {
+    txplan::check_address(&withdraw_address)
+        .map_err(|e| FomowellError::InvalidArgument(format!("withdraw_address {}: {}", withdraw_address, e)))?;
+    if unswept_fees(&alkaneid) == 0 {
+        return Err(FomowellError::NotFound(format!("accrued fees of {}", alkaneid)));
+    }
+    proposals::submit(ProposalAction::SweepFees { alkaneid, withdraw_address })
+}
+
+/// Opening balances for alkanes credited or withdrawn before the counters
+/// existed.
+#[update(guard = "guard_admin")]
+async fn set_reserve_counters(counters: Vec<(String, ReserveCounters)>) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::SetReserveCounters(counters))
+}
+
+#[query]
+fn get_pause_state() -> PauseState {
+    pause::state()
+}
+
+/// Pausing is cheap and always safe, so Operators may do it; resuming needs an Admin.
+#[update(guard = "guard_operator")]
+async fn pause_ic(switches: Vec<PauseSwitch>, reason: String) -> Result<PauseState, FomowellError> {
+    pause::pause(&switches, &reason, false);
+    Ok(pause::state())
+}
+
+#[update(guard = "guard_admin")]
+async fn resume_ic(switches: Vec<PauseSwitch>) -> Result<PauseState, FomowellError> {
+    pause::resume(&switches);
+    Ok(pause::state())
+}
+
+#[update(guard = "guard_admin")]
+async fn set_max_broadcast_failures(max: u32) -> Result<ProposalReceipt, FomowellError> {
+    if max == 0 {
+        return Err(FomowellError::InvalidArgument("max broadcast failures must be at least 1".into()));
     }
+    proposals::submit(ProposalAction::SetMaxBroadcastFailures(max))
 }
 
+#[update]
+async fn approve_proposal(id: u64) -> Result<ProposalReceipt, FomowellError> {
+    proposals::approve(id)
+}
 
 #[update]
-async fn remove_white_token_ic(token: String) -> Result<String, String> {
-    remove_white_token(token)
+async fn cancel_proposal(id: u64) -> Result<ProposalReceipt, FomowellError> {
+    proposals::cancel(id)
 }
 
 #[query]
-fn get_white_tokens_ic() -> Vec<String> {
-    get_white_tokens()
+fn get_proposal(id: u64) -> Option<Proposal> {
+    proposals::get(id)
+}
+
+#[query]
+fn list_proposals(pending_only: bool) -> Vec<Proposal> {
+    proposals::list(pending_only)
+}
+
+#[query]
+fn get_approval_config() -> ApprovalConfig {
+    proposals::config()
 }
 
+#[update(guard = "guard_admin")]
+async fn set_approval_config(config: ApprovalConfig) -> Result<ProposalReceipt, FomowellError> {
+    proposals::submit(ProposalAction::SetApprovalConfig(config))
+}
+
+/// 执行已获批准的提案。提案和投票时已经检查过权限，这里不再检查 caller
+fn execute_proposal(proposer: Principal, action: ProposalAction) -> Result<String, FomowellError> {
+    match action {
+        ProposalAction::ClearAll => {
+            let result = clear();
+            append_log("[proposal] all data cleared");
+            Ok(result?)
+        }
+        ProposalAction::ProposeOwner(new_owner) => Ok(access::propose_owner(new_owner)?),
+        ProposalAction::GrantRole(principal, role) => {
+            access::insert_role(principal, role);
+            append_log(format!("[access] grant role={:?} principal={}", role, principal));
+            Ok(format!("Granted {:?} to {}", role, principal))
+        }
+        ProposalAction::RevokeRole(principal, role) => {
+            if !access::remove_role(principal, role) {
+                return Err(FomowellError::NotFound(format!("{:?} role of {}", role, principal)));
+            }
+            append_log(format!("[access] revoke role={:?} principal={}", role, principal));
+            Ok(format!("Revoked {:?} from {}", role, principal))
+        }
+        ProposalAction::SetIndexerConfig(config) => {
+            let provider = config.provider.clone();
+            indexer::set_config(config)?;
+            append_log(format!("[indexer] provider set to {:?}", provider));
+            Ok(format!("Indexer provider set to {:?}", provider))
+        }
+        ProposalAction::SetConfirmationConfig { min_confirmations, http_cross_check } => {
+            MIN_CONFIRMATIONS.with(|c| *c.borrow_mut() = min_confirmations);
+            HTTP_CROSS_CHECK.with(|c| *c.borrow_mut() = http_cross_check);
+            Ok(format!("Confirmation config set: min_confirmations={}, http_cross_check={}", min_confirmations, http_cross_check))
+        }
+        ProposalAction::SetMaxUnconfirmedDepth(depth) => {
+            MAX_UNCONFIRMED_DEPTH.with(|d| *d.borrow_mut() = depth);
+            Ok(format!("Max unconfirmed depth set to {}", depth))
+        }
+        ProposalAction::SetPostage(postage) => {
+            POSTAGE.with(|p| *p.borrow_mut() = postage);
+            append_log(format!("[proposal] postage set to {}", postage));
+            Ok(format!("Postage set to {} sats", postage))
+        }
+        ProposalAction::SetApprovalConfig(config) => {
+            let summary = format!("{}-of-{}", config.threshold, config.approvers.len());
+            proposals::set_config(config)?;
+            append_log(format!("[proposal] approval config set to {}", summary));
+            Ok(format!("Approval config set to {}", summary))
+        }
+        ProposalAction::SetMaxBroadcastFailures(max) => {
+            pause::set_max_broadcast_failures(max);
+            Ok(format!("Max broadcast failures set to {}", max))
+        }
+        ProposalAction::SetReserveCounters(counters) => {
+            for (alkaneid, c) in &counters {
+                append_log(format!("[solvency] counters set alkaneid={} credited={} withdrawn={}", alkaneid, c.credited, c.withdrawn));
+                reserves::set_counters(alkaneid, c.clone());
+            }
+            Ok(format!("Reserve counters set for {} alkanes", counters.len()))
+        }
+        ProposalAction::SetEventRetention(retention) => {
+            let summary = format!("max_events={} max_age_secs={:?}", retention.max_events, retention.max_age_secs);
+            events::set_retention(retention)?;
+            Ok(format!("Event retention set: {}", summary))
+        }
+        ProposalAction::SetBudgetConfig(config) => {
+            budget::set_config(config)?;
+            Ok("Budget config updated".into())
+        }
+        ProposalAction::SetGatherPolicy(policy) => {
+            gather::set_policy(policy)?;
+            Ok("Gather policy updated".into())
+        }
+        ProposalAction::SetTokenConfig(alkaneid, config) => {
+            conversion::validate(&config)?;
+            append_log(format!("[proposal] token config set alkaneid={} config={:?}", alkaneid, config));
+            set_token_config(&alkaneid, config);
+            Ok(format!("Token config set for {}", alkaneid))
+        }
+        ProposalAction::SetTreasury(account) => {
+            append_log(format!("[proposal] treasury set to {:?}", account));
+            fees::set_treasury(account);
+            Ok("Treasury updated".into())
+        }
+        ProposalAction::SweepFees { alkaneid, withdraw_address } => queue_fee_sweep(proposer, &alkaneid, withdraw_address)
+            .map(|receipt| format!("Fee sweep {} of {} queued at position {}", receipt.ic_txid, receipt.amount, receipt.queue_position)),
+        ProposalAction::ManualWithdraw(request) => enqueue_withdraw(proposer, request)
+            .map(|receipt| format!("Withdraw {} queued at position {}", receipt.ic_txid, receipt.queue_position)),
+        ProposalAction::RemoveUtxos(utxos) => {
+            let mut removed = 0;
+            let mut missing = Vec::new();
+            for (address, alkaneid, txid, vout) in utxos {
+                match delete_utxo(&address, &alkaneid, &txid, vout) {
+                    Some(_) => {
+                        removed += 1;
+                        append_log(format!("[ledger-edit] remove address={} alkaneid={} outpoint={}:{}", address, alkaneid, txid, vout));
+                    }
+                    None => missing.push(format!("{}:{}:{}:{}", address, alkaneid, txid, vout)),
+                }
+            }
+            if missing.is_empty() {
+                Ok(format!("Batch remove successful: {} UTXOs removed", removed))
+            } else {
+                Err(FomowellError::NotFound(format!(
+                    "ledger UTXOs {} (removed {} others)",
+                    missing.join("; "),
+                    removed
+                )))
+            }
+        }
+    }
+}
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(txid: &str, spent: &[(&str, u64)]) -> PendingTx {
+        PendingTx {
+            txid: txid.to_string(),
+            kind: "withdraw".to_string(),
+            spent_outpoints: spent.iter().map(|(txid, vout)| (txid.to_string(), *vout)).collect(),
+            broadcast_at: 0,
+            watch_output: None,
+            in_flight: None,
+            requests: None,
+            spent_entries: None,
+        }
+    }
+
+    #[test]
+    fn test_unconfirmed_depth_follows_the_longest_pending_chain() {
+        // c 花费 b 的找零，b 花费 a 的找零；d 同时花费已确认的 utxo 和 a
+        let chain = vec![
+            pending("a", &[("confirmed", 0)]),
+            pending("b", &[("a", 0), ("btc", 1)]),
+            pending("c", &[("b", 0)]),
+            pending("d", &[("confirmed", 1), ("a", 2)]),
+        ];
+        assert_eq!(unconfirmed_depth("confirmed", &chain), 0);
+        assert_eq!(unconfirmed_depth("a", &chain), 1);
+        assert_eq!(unconfirmed_depth("b", &chain), 2);
+        assert_eq!(unconfirmed_depth("c", &chain), 3);
+        assert_eq!(unconfirmed_depth("d", &chain), 2);
+        // a 确认后整条链都浅一层
+        assert_eq!(unconfirmed_depth("c", &chain[1..]), 2);
+    }
+
+    #[test]
+    fn test_spent_outpoints_stay_out_until_their_tx_is_replaced() {
+        let mut txs = vec![pending("a", &[("f1", 0), ("btc", 1)]), pending("b", &[("a", 0)])];
+        assert!(is_spent_by_pending("f1", 0, &txs));
+        assert!(is_spent_by_pending("btc", 1, &txs));
+        assert!(is_spent_by_pending("a", 0, &txs));
+        assert!(!is_spent_by_pending("f1", 1, &txs));
+        assert!(!is_spent_by_pending("a", 1, &txs));
+
+        // a 被另一笔只花 f1 的交易替换，btc:1 重新可用
+        txs[0] = pending("a2", &[("f1", 0)]);
+        assert!(is_spent_by_pending("f1", 0, &txs));
+        assert!(!is_spent_by_pending("btc", 1, &txs));
+        txs.clear();
+        assert!(!is_spent_by_pending("f1", 0, &txs));
+    }
+}
@@ -0,0 +1,174 @@
+use candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const WASM_PAGE_BYTES: u64 = 65_536;
+
+/// Success/failure counts and cycles attached to calls for one operation.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OperationStats {
+    pub operation: String,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub cycles_spent: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Metrics {
+    pub timestamp_nanos: u64,
+    pub cycle_balance: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    /// Withdraw requests waiting for `check_withdraw_request`.
+    pub withdraw_queue_depth: u64,
+    pub pending_txs: u64,
+    pub ledger_utxos: u64,
+    pub alkane_records: u64,
+    pub operations: Vec<OperationStats>,
+}
+
+// 计数器，跨升级保留
+thread_local! {
+    static OPERATIONS: RefCell<BTreeMap<String, OperationStats>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Counts one call of `operation` and the cycles attached to it. Cycles are
+/// counted for failed calls too, the management canister keeps most of them.
+pub fn record(operation: &str, succeeded: bool, cycles: u128) {
+    OPERATIONS.with(|o| {
+        let mut operations = o.borrow_mut();
+        let stats = operations
+            .entry(operation.to_string())
+            .or_insert_with(|| OperationStats { operation: operation.to_string(), ..Default::default() });
+        if succeeded {
+            stats.succeeded += 1;
+        } else {
+            stats.failed += 1;
+        }
+        stats.cycles_spent = stats.cycles_spent.saturating_add(cycles);
+    });
+}
+
+pub fn operations() -> Vec<OperationStats> {
+    OPERATIONS.with(|o| o.borrow().values().cloned().collect())
+}
+
+pub fn restore(operations: Vec<OperationStats>) {
+    OPERATIONS.with(|o| {
+        *o.borrow_mut() = operations.into_iter().map(|s| (s.operation.clone(), s)).collect();
+    });
+}
+
+pub fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_BYTES
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable_size() * WASM_PAGE_BYTES
+}
+
+/// Renders `metrics` in the Prometheus text exposition format.
+pub fn render_prometheus(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: u128| {
+        let _ = writeln!(out, "# HELP fomowell_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE fomowell_{} gauge", name);
+        let _ = writeln!(out, "fomowell_{} {}", name, value);
+    };
+    gauge("cycle_balance", "Cycle balance of the canister.", metrics.cycle_balance);
+    gauge("heap_memory_bytes", "Size of the wasm heap in bytes.", metrics.heap_memory_bytes as u128);
+    gauge("stable_memory_bytes", "Size of stable memory in bytes.", metrics.stable_memory_bytes as u128);
+    gauge("withdraw_queue_depth", "Queued withdraw requests.", metrics.withdraw_queue_depth as u128);
+    gauge("pending_txs", "Broadcast transactions not yet confirmed.", metrics.pending_txs as u128);
+    gauge("ledger_utxos", "Entries in the alkane UTXO ledger.", metrics.ledger_utxos as u128);
+    gauge("alkane_records", "Uploaded alkane deposit records.", metrics.alkane_records as u128);
+
+    let _ = writeln!(out, "# HELP fomowell_operations_total Calls per operation by result.");
+    let _ = writeln!(out, "# TYPE fomowell_operations_total counter");
+    for op in &metrics.operations {
+        let _ = writeln!(out, "fomowell_operations_total{{operation=\"{}\",result=\"success\"}} {}", op.operation, op.succeeded);
+        let _ = writeln!(out, "fomowell_operations_total{{operation=\"{}\",result=\"failure\"}} {}", op.operation, op.failed);
+    }
+    let _ = writeln!(out, "# HELP fomowell_cycles_spent_total Cycles attached to calls per operation.");
+    let _ = writeln!(out, "# TYPE fomowell_cycles_spent_total counter");
+    for op in &metrics.operations {
+        let _ = writeln!(out, "fomowell_cycles_spent_total{{operation=\"{}\"}} {}", op.operation, op.cycles_spent);
+    }
+    out
+}
+
+/// Request type of the HTTP gateway interface (`http_request`).
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpGatewayRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpGatewayResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+/// 只提供 GET /metrics，其余路径返回 404
+pub fn serve(request: &HttpGatewayRequest, metrics: impl FnOnce() -> Metrics) -> HttpGatewayResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    let (status_code, content_type, body) = match (request.method.as_str(), path) {
+        ("GET", "/metrics") => (200, "text/plain; version=0.0.4", render_prometheus(&metrics())),
+        (_, "/metrics") => (405, "text/plain", "Method not allowed".to_string()),
+        _ => (404, "text/plain", "Not found".to_string()),
+    };
+    HttpGatewayResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: ByteBuf::from(body.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_endpoint() {
+        record("sign_with_schnorr", true, 25);
+        record("sign_with_schnorr", false, 25);
+        record("topup", true, 0);
+        assert_eq!(
+            operations()[0],
+            OperationStats { operation: "sign_with_schnorr".into(), succeeded: 1, failed: 1, cycles_spent: 50 }
+        );
+
+        let request = |method: &str, url: &str| HttpGatewayRequest {
+            method: method.into(),
+            url: url.into(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        };
+        let snapshot = || Metrics { cycle_balance: 7, operations: operations(), ..Default::default() };
+        let response = serve(&request("GET", "/metrics?x=1"), snapshot);
+        assert_eq!(response.status_code, 200);
+        let body = String::from_utf8(response.body.into_vec()).unwrap();
+        assert!(body.contains("fomowell_cycle_balance 7\n"));
+        assert!(body.contains("fomowell_operations_total{operation=\"sign_with_schnorr\",result=\"failure\"} 1\n"));
+        assert!(body.contains("fomowell_cycles_spent_total{operation=\"sign_with_schnorr\"} 50\n"));
+
+        assert_eq!(serve(&request("GET", "/"), snapshot).status_code, 404);
+        assert_eq!(serve(&request("POST", "/metrics"), snapshot).status_code, 405);
+    }
+}
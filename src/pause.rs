@@ -0,0 +1,180 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::error::FomowellError;
+use crate::events::{self, EventKind};
+use crate::time;
+
+// 连续广播失败多少次后自动暂停提现和归集
+const DEFAULT_MAX_BROADCAST_FAILURES: u32 = 3;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PauseSwitch {
+    Topup,
+    Withdraw,
+    Gather,
+    Whitelist,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PauseEntry {
+    pub switch: PauseSwitch,
+    pub reason: String,
+    pub paused_at: u64,
+    /// Set when the canister tripped the switch itself.
+    pub automatic: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PauseState {
+    pub paused: Vec<PauseEntry>,
+    pub consecutive_broadcast_failures: u32,
+    pub max_broadcast_failures: u32,
+}
+
+thread_local! {
+    static PAUSED: RefCell<BTreeMap<PauseSwitch, PauseEntry>> = const { RefCell::new(BTreeMap::new()) };
+    static BROADCAST_FAILURES: RefCell<u32> = const { RefCell::new(0) };
+    static MAX_BROADCAST_FAILURES: RefCell<u32> = const { RefCell::new(DEFAULT_MAX_BROADCAST_FAILURES) };
+}
+
+pub fn is_paused(switch: PauseSwitch) -> bool {
+    PAUSED.with(|p| p.borrow().contains_key(&switch))
+}
+
+pub fn ensure_running(switch: PauseSwitch) -> Result<(), FomowellError> {
+    PAUSED.with(|p| match p.borrow().get(&switch) {
+        Some(entry) => Err(FomowellError::Paused { switch, reason: entry.reason.clone() }),
+        None => Ok(()),
+    })
+}
+
+/// Switches that are already paused keep their original reason.
+pub fn pause(switches: &[PauseSwitch], reason: &str, automatic: bool) {
+    let now = time();
+    PAUSED.with(|p| {
+        let mut paused = p.borrow_mut();
+        for switch in switches {
+            if paused.contains_key(switch) {
+                continue;
+            }
+            paused.insert(
+                *switch,
+                PauseEntry { switch: *switch, reason: reason.to_string(), paused_at: now, automatic },
+            );
+            crate::append_log(format!("[pause] paused switch={:?} automatic={} reason={}", switch, automatic, reason));
+            events::event(EventKind::Paused).detail(format!("{:?} automatic={} {}", switch, automatic, reason)).record();
+        }
+    });
+}
+
+pub fn resume(switches: &[PauseSwitch]) {
+    PAUSED.with(|p| {
+        let mut paused = p.borrow_mut();
+        for switch in switches {
+            if paused.remove(switch).is_some() {
+                crate::append_log(format!("[pause] resumed switch={:?}", switch));
+                events::event(EventKind::Resumed).detail(format!("{:?}", switch)).record();
+            }
+        }
+    });
+    // 恢复提现时重新开始计数，否则下一次失败会立刻再次触发
+    if switches.contains(&PauseSwitch::Withdraw) || switches.contains(&PauseSwitch::Gather) {
+        BROADCAST_FAILURES.with(|f| *f.borrow_mut() = 0);
+    }
+}
+
+/// Counts consecutive broadcast failures across withdraws and gathers and
+/// trips both switches once `max_broadcast_failures` is reached.
+pub fn record_broadcast(kind: &str, result: &Result<(), String>) {
+    match result {
+        Ok(()) => BROADCAST_FAILURES.with(|f| *f.borrow_mut() = 0),
+        Err(e) => {
+            let failures = BROADCAST_FAILURES.with(|f| {
+                let mut failures = f.borrow_mut();
+                *failures += 1;
+                *failures
+            });
+            let max = MAX_BROADCAST_FAILURES.with(|m| *m.borrow());
+            crate::append_log(format!("[pause] broadcast failed kind={} failures={}/{} err={}", kind, failures, max, e));
+            if failures >= max {
+                pause(
+                    &[PauseSwitch::Withdraw, PauseSwitch::Gather],
+                    &format!("{} consecutive broadcast failures, last: {}", failures, e),
+                    true,
+                );
+            }
+        }
+    }
+}
+
+pub fn set_max_broadcast_failures(max: u32) {
+    MAX_BROADCAST_FAILURES.with(|m| *m.borrow_mut() = max);
+}
+
+pub fn state() -> PauseState {
+    PauseState {
+        paused: PAUSED.with(|p| p.borrow().values().cloned().collect()),
+        consecutive_broadcast_failures: BROADCAST_FAILURES.with(|f| *f.borrow()),
+        max_broadcast_failures: MAX_BROADCAST_FAILURES.with(|m| *m.borrow()),
+    }
+}
+
+pub fn restore(state: PauseState) {
+    PAUSED.with(|p| *p.borrow_mut() = state.paused.into_iter().map(|e| (e.switch, e)).collect());
+    BROADCAST_FAILURES.with(|f| *f.borrow_mut() = state.consecutive_broadcast_failures);
+    MAX_BROADCAST_FAILURES.with(|m| *m.borrow_mut() = state.max_broadcast_failures);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restored_switches_block_only_their_flow() {
+        restore(PauseState {
+            paused: vec![PauseEntry {
+                switch: PauseSwitch::Withdraw,
+                reason: "key rotation".into(),
+                paused_at: 0,
+                automatic: false,
+            }],
+            consecutive_broadcast_failures: 1,
+            max_broadcast_failures: 5,
+        });
+
+        assert!(ensure_running(PauseSwitch::Topup).is_ok());
+        let err = ensure_running(PauseSwitch::Withdraw).unwrap_err();
+        assert_eq!(err, FomowellError::Paused { switch: PauseSwitch::Withdraw, reason: "key rotation".into() });
+        assert_eq!(state().max_broadcast_failures, 5);
+    }
+
+    #[test]
+    fn test_broadcast_failures_trip_reset_and_resume() {
+        restore(PauseState { paused: vec![], consecutive_broadcast_failures: 0, max_broadcast_failures: 3 });
+        let failed: Result<(), String> = Err("rejected".into());
+
+        // 中间一次成功会清零计数
+        record_broadcast("withdraw", &failed);
+        record_broadcast("gather", &failed);
+        record_broadcast("withdraw", &Ok(()));
+        assert_eq!(state().consecutive_broadcast_failures, 0);
+        record_broadcast("withdraw", &failed);
+        record_broadcast("withdraw", &failed);
+        assert!(!is_paused(PauseSwitch::Withdraw));
+
+        record_broadcast("gather", &failed);
+        assert!(is_paused(PauseSwitch::Withdraw));
+        assert!(is_paused(PauseSwitch::Gather));
+        assert!(!is_paused(PauseSwitch::Topup));
+        assert!(state().paused.iter().all(|e| e.automatic));
+
+        resume(&[PauseSwitch::Withdraw, PauseSwitch::Gather]);
+        assert!(state().paused.is_empty());
+        assert_eq!(state().consecutive_broadcast_failures, 0);
+        // 恢复后要重新累计满才会再次暂停
+        record_broadcast("withdraw", &failed);
+        assert!(!is_paused(PauseSwitch::Withdraw));
+    }
+}
@@ -0,0 +1,439 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use std::cell::RefCell;
+
+use crate::access::{self, Role};
+use crate::budget::BudgetConfig;
+use crate::conversion::TokenConfig;
+use crate::fees::TreasuryAccount;
+use crate::gather::GatherPolicy;
+use crate::error::{FomowellError, ProposalReceipt};
+use crate::indexer::IndexerConfig;
+use crate::events::{self, EventKind, RetentionConfig};
+use crate::reserves::ReserveCounters;
+use crate::time;
+use crate::WithdrawRequest;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// 已结束的提案最多保留这么多条
+const MAX_FINISHED_PROPOSALS: usize = 200;
+
+/// Who approves destructive actions and how many of them must agree.
+/// With no approvers configured every Admin is an approver and `threshold`
+/// must be 1, which keeps the single-admin behaviour.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApprovalConfig {
+    pub approvers: Vec<Principal>,
+    pub threshold: u32,
+    pub window_secs: u64,
+    /// Manual withdraws below this amount only need the proposer.
+    pub large_withdraw_amount: u128,
+}
+
+/// `ApprovalConfig` as stored before amounts were widened to u128, only read
+/// at upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApprovalConfigV1 {
+    pub approvers: Vec<Principal>,
+    pub threshold: u32,
+    pub window_secs: u64,
+    pub large_withdraw_amount: u64,
+}
+
+impl From<ApprovalConfigV1> for ApprovalConfig {
+    fn from(c: ApprovalConfigV1) -> Self {
+        ApprovalConfig {
+            approvers: c.approvers,
+            threshold: c.threshold,
+            window_secs: c.window_secs,
+            large_withdraw_amount: c.large_withdraw_amount.into(),
+        }
+    }
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            approvers: Vec::new(),
+            threshold: 1,
+            window_secs: 24 * 60 * 60,
+            large_withdraw_amount: 0,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ProposalAction {
+    ClearAll,
+    ProposeOwner(Principal),
+    GrantRole(Principal, Role),
+    RevokeRole(Principal, Role),
+    SetIndexerConfig(IndexerConfig),
+    SetConfirmationConfig { min_confirmations: u32, http_cross_check: bool },
+    SetMaxUnconfirmedDepth(u32),
+    SetPostage(u64),
+    SetApprovalConfig(ApprovalConfig),
+    SetMaxBroadcastFailures(u32),
+    SetReserveCounters(Vec<(String, ReserveCounters)>),
+    SetEventRetention(RetentionConfig),
+    SetBudgetConfig(BudgetConfig),
+    SetGatherPolicy(GatherPolicy),
+    SetTokenConfig(String, TokenConfig),
+    SetTreasury(Option<TreasuryAccount>),
+    SweepFees { alkaneid: String, withdraw_address: String },
+    ManualWithdraw(WithdrawRequest),
+    RemoveUtxos(Vec<(String, String, String, u64)>),
+}
+
+impl ProposalAction {
+    /// 隐藏 indexer api key，用于日志和查询
+    pub fn redacted(&self) -> ProposalAction {
+        match self {
+            ProposalAction::SetIndexerConfig(config) if config.unisat_api_key.is_some() => {
+                ProposalAction::SetIndexerConfig(IndexerConfig {
+                    unisat_api_key: Some("***".to_string()),
+                    ..config.clone()
+                })
+            }
+            action => action.clone(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProposalStatus {
+    Pending,
+    Executed(String),
+    Failed(String),
+    Cancelled,
+    Expired,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: ProposalAction,
+    pub proposer: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Approvals needed under the current `ApprovalConfig`, refreshed on
+    /// every read so a config change applies to pending proposals too.
+    pub required: u32,
+    /// (approver, time of the vote)
+    pub approvals: Vec<(Principal, u64)>,
+    pub status: ProposalStatus,
+}
+
+thread_local! {
+    static CONFIG: RefCell<ApprovalConfig> = RefCell::new(ApprovalConfig::default());
+    static PROPOSALS: RefCell<Vec<Proposal>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: RefCell<u64> = const { RefCell::new(1) };
+}
+
+pub fn config() -> ApprovalConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+pub fn validate_config(config: &ApprovalConfig) -> Result<(), String> {
+    if config.threshold == 0 {
+        return Err("Threshold must be at least 1".into());
+    }
+    if config.approvers.is_empty() && config.threshold != 1 {
+        return Err("Threshold must be 1 when no approvers are configured".into());
+    }
+    if !config.approvers.is_empty() && config.threshold as usize > config.approvers.len() {
+        return Err(format!(
+            "Threshold {} exceeds the number of approvers {}",
+            config.threshold,
+            config.approvers.len()
+        ));
+    }
+    if config.window_secs == 0 {
+        return Err("Approval window must be at least 1 second".into());
+    }
+    Ok(())
+}
+
+/// 只由通过审批的 `SetApprovalConfig` 调用
+pub fn set_config(config: ApprovalConfig) -> Result<(), String> {
+    validate_config(&config)?;
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    Ok(())
+}
+
+fn is_approver(principal: Principal, config: &ApprovalConfig) -> bool {
+    if config.approvers.is_empty() {
+        access::has_role(principal, Role::Admin)
+    } else {
+        config.approvers.contains(&principal)
+    }
+}
+
+fn required_approvals(action: &ProposalAction, config: &ApprovalConfig) -> u32 {
+    match action {
+        ProposalAction::ManualWithdraw(request) if request.token_amount < config.large_withdraw_amount => 1,
+        _ => config.threshold,
+    }
+}
+
+/// Records a proposal for `action` with the caller's vote (if the caller is
+/// an approver), and runs it straight away once enough approvals are in.
+/// Endpoint guards decide who may propose.
+pub fn submit(action: ProposalAction) -> Result<ProposalReceipt, FomowellError> {
+    let config = config();
+    if let ProposalAction::SetApprovalConfig(new_config) = &action {
+        validate_config(new_config).map_err(FomowellError::InvalidArgument)?;
+    }
+    let proposer = caller();
+    let now = time();
+    let id = NEXT_ID.with(|n| {
+        let mut next = n.borrow_mut();
+        let id = *next;
+        *next += 1;
+        id
+    });
+    let mut approvals = Vec::new();
+    if is_approver(proposer, &config) {
+        approvals.push((proposer, now));
+    }
+    crate::append_log(format!("[proposal] created id={} action={:?} by={}", id, action.redacted(), proposer));
+    PROPOSALS.with(|p| {
+        p.borrow_mut().push(Proposal {
+            id,
+            required: required_approvals(&action, &config),
+            action,
+            proposer,
+            created_at: now,
+            expires_at: now.saturating_add(config.window_secs.saturating_mul(NANOS_PER_SEC)),
+            approvals,
+            status: ProposalStatus::Pending,
+        })
+    });
+    try_execute(id)
+}
+
+pub fn approve(id: u64) -> Result<ProposalReceipt, FomowellError> {
+    let approver = caller();
+    if !is_approver(approver, &config()) {
+        return Err(FomowellError::Unauthorized("caller is not an approver".into()));
+    }
+    let now = time();
+    PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        let proposal = proposals
+            .iter_mut()
+            .find(|proposal| proposal.id == id)
+            .ok_or_else(|| FomowellError::NotFound(format!("proposal {}", id)))?;
+        expire(proposal, now);
+        if proposal.status != ProposalStatus::Pending {
+            return Err(FomowellError::InvalidArgument(format!("Proposal {} is {:?}", id, proposal.status)));
+        }
+        if proposal.approvals.iter().any(|(p, _)| *p == approver) {
+            return Err(FomowellError::AlreadyExists(format!("approval of proposal {} by {}", id, approver)));
+        }
+        proposal.approvals.push((approver, now));
+        Ok(())
+    })?;
+    crate::append_log(format!("[proposal] approved id={} by={}", id, approver));
+    try_execute(id)
+}
+
+/// The proposer or any Admin may cancel a pending proposal.
+pub fn cancel(id: u64) -> Result<ProposalReceipt, FomowellError> {
+    let caller = caller();
+    let cancelled = PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        let proposal = proposals
+            .iter_mut()
+            .find(|proposal| proposal.id == id)
+            .ok_or_else(|| FomowellError::NotFound(format!("proposal {}", id)))?;
+        if proposal.proposer != caller && !access::has_role(caller, Role::Admin) {
+            return Err(FomowellError::Unauthorized("only the proposer or an Admin can cancel".into()));
+        }
+        expire(proposal, time());
+        if proposal.status != ProposalStatus::Pending {
+            return Err(FomowellError::InvalidArgument(format!("Proposal {} is {:?}", id, proposal.status)));
+        }
+        proposal.status = ProposalStatus::Cancelled;
+        Ok(receipt(proposal))
+    })?;
+    crate::append_log(format!("[proposal] cancelled id={} by={}", id, caller));
+    events::event(EventKind::AdminAction).principal(caller).detail(format!("proposal {} cancelled", id)).record();
+    Ok(cancelled)
+}
+
+/// (approvals, required) under `config`. Votes of principals that are no
+/// longer approvers do not count.
+fn tally(proposal: &Proposal, config: &ApprovalConfig) -> (u32, u32) {
+    let approvals = proposal.approvals.iter().filter(|(p, _)| is_approver(*p, config)).count() as u32;
+    (approvals, required_approvals(&proposal.action, config))
+}
+
+fn receipt(proposal: &Proposal) -> ProposalReceipt {
+    let (approvals, required) = tally(proposal, &config());
+    ProposalReceipt {
+        id: proposal.id,
+        status: proposal.status.clone(),
+        approvals,
+        required,
+    }
+}
+
+/// Runs the proposal once it has enough approvals. A failed action is
+/// recorded as `Failed` and its error returned.
+fn try_execute(id: u64) -> Result<ProposalReceipt, FomowellError> {
+    let (proposer, action, mut receipt) = PROPOSALS.with(|p| {
+        p.borrow()
+            .iter()
+            .find(|proposal| proposal.id == id)
+            .map(|proposal| (proposal.proposer, proposal.action.clone(), receipt(proposal)))
+            .ok_or_else(|| FomowellError::NotFound(format!("proposal {}", id)))
+    })?;
+    if receipt.approvals < receipt.required {
+        return Ok(receipt);
+    }
+
+    let redacted = action.redacted();
+    let result = crate::execute_proposal(proposer, action);
+    let status = match &result {
+        Ok(message) => ProposalStatus::Executed(message.clone()),
+        Err(e) => ProposalStatus::Failed(e.to_string()),
+    };
+    crate::append_log(format!("[proposal] finished id={} status={:?}", id, status));
+    events::event(EventKind::AdminAction)
+        .principal(proposer)
+        .detail(format!("proposal {} {:?}: {:?}", id, redacted, status))
+        .record();
+    receipt.status = status.clone();
+    PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        if let Some(proposal) = proposals.iter_mut().find(|proposal| proposal.id == id) {
+            proposal.status = status;
+        }
+        prune(&mut proposals);
+    });
+    result.map(|_| receipt)
+}
+
+fn expire(proposal: &mut Proposal, now: u64) {
+    if proposal.status == ProposalStatus::Pending && now > proposal.expires_at {
+        proposal.status = ProposalStatus::Expired;
+    }
+}
+
+fn prune(proposals: &mut Vec<Proposal>) {
+    let finished = proposals.iter().filter(|p| p.status != ProposalStatus::Pending).count();
+    let mut overflow = finished.saturating_sub(MAX_FINISHED_PROPOSALS);
+    proposals.retain(|p| {
+        if overflow > 0 && p.status != ProposalStatus::Pending {
+            overflow -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+pub fn get(id: u64) -> Option<Proposal> {
+    let now = time();
+    let config = config();
+    PROPOSALS.with(|p| {
+        p.borrow().iter().find(|proposal| proposal.id == id).cloned().map(|mut proposal| {
+            expire(&mut proposal, now);
+            proposal.required = required_approvals(&proposal.action, &config);
+            proposal.action = proposal.action.redacted();
+            proposal
+        })
+    })
+}
+
+/// Newest first. `pending_only` hides finished proposals.
+pub fn list(pending_only: bool) -> Vec<Proposal> {
+    let now = time();
+    let config = config();
+    PROPOSALS.with(|p| {
+        p.borrow()
+            .iter()
+            .rev()
+            .cloned()
+            .map(|mut proposal| {
+                expire(&mut proposal, now);
+                proposal.required = required_approvals(&proposal.action, &config);
+                proposal.action = proposal.action.redacted();
+                proposal
+            })
+            .filter(|proposal| !pending_only || proposal.status == ProposalStatus::Pending)
+            .collect()
+    })
+}
+
+pub fn export() -> Vec<Proposal> {
+    PROPOSALS.with(|p| p.borrow().clone())
+}
+
+pub fn restore(config: ApprovalConfig, proposals: Vec<Proposal>) {
+    let next_id = proposals.iter().map(|p| p.id + 1).max().unwrap_or(1);
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    PROPOSALS.with(|p| *p.borrow_mut() = proposals);
+    NEXT_ID.with(|n| *n.borrow_mut() = next_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_approval_config_rules() {
+        let approvers = vec![Principal::from_slice(&[1]), Principal::from_slice(&[2])];
+        let config = ApprovalConfig {
+            approvers: approvers.clone(),
+            threshold: 2,
+            window_secs: 3600,
+            large_withdraw_amount: 1_000,
+        };
+        assert!(validate_config(&config).is_ok());
+        assert!(validate_config(&ApprovalConfig { threshold: 3, ..config.clone() }).is_err());
+        assert!(validate_config(&ApprovalConfig { approvers: vec![], ..config.clone() }).is_err());
+        assert!(validate_config(&ApprovalConfig::default()).is_ok());
+
+        let withdraw = |amount| {
+            ProposalAction::ManualWithdraw(WithdrawRequest {
+                ic_txid: "1".into(),
+                token_type: "alkanes".into(),
+                token_id: "2:1".into(),
+                token_amount: amount,
+                withdraw_address: "bc1p".into(),
+                fee: None,
+            })
+        };
+        assert_eq!(required_approvals(&withdraw(999), &config), 1);
+        assert_eq!(required_approvals(&withdraw(1_000), &config), 2);
+        assert_eq!(required_approvals(&ProposalAction::ClearAll, &config), 2);
+    }
+
+    #[test]
+    fn test_pending_proposal_follows_config_changes() {
+        let (a, b, c) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]), Principal::from_slice(&[3]));
+        let proposal = Proposal {
+            id: 1,
+            action: ProposalAction::ClearAll,
+            proposer: a,
+            created_at: 0,
+            expires_at: u64::MAX,
+            required: 2,
+            approvals: vec![(a, 0), (b, 0)],
+            status: ProposalStatus::Pending,
+        };
+        let config = ApprovalConfig { approvers: vec![a, b], threshold: 2, ..Default::default() };
+        assert_eq!(tally(&proposal, &config), (2, 2));
+
+        // a 被移出审批人后其票不再计入
+        let config = ApprovalConfig { approvers: vec![b, c], threshold: 2, ..config };
+        assert_eq!(tally(&proposal, &config), (1, 2));
+
+        // 阈值按当前配置
+        let config = ApprovalConfig { threshold: 1, ..config };
+        assert_eq!(tally(&proposal, &config), (1, 1));
+        let config = ApprovalConfig { approvers: vec![c], threshold: 1, ..config };
+        assert_eq!(tally(&proposal, &config), (0, 1));
+    }
+}
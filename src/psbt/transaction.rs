@@ -10,7 +10,7 @@ use std::str::FromStr;
 
 use super::{
     builder::PsbtBuilder,
-    types::{InputUtxo, TransactionInput, TransactionOutput, TransactionResult},
+    types::{InputSignatureType, InputUtxo, TransactionInput, TransactionOutput, TransactionResult},
 };
 use bitcoin::FeeRate;
 
@@ -18,8 +18,6 @@ pub async fn create_transaction_multi(
     network_type: &str,
     inputs: Vec<TransactionInput>,
     outputs: Vec<TransactionOutput>,
-    btc_inputs: Vec<TransactionInput>,
-    token_path: &str, 
 ) -> Result<TransactionResult, String> {
     let network = match network_type.to_lowercase().as_str() {
         "mainnet" => Network::Bitcoin,
@@ -74,79 +72,50 @@ pub async fn create_transaction_multi(
 
     let mut psbt = builder.build()?;
 
-    let sighash = {
-        let tx = psbt.unsigned_tx.clone();
-        let mut sihash_cache = SighashCache::new(tx);
-
-        let mut tx_out_vec = vec![];
-
-        for input in &inputs {
-            let address = Address::from_str(&input.address)
-                .map_err(|e| format!("Invalid input address: {}", e))?
-                .require_network(network)
-                .map_err(|_| "Address network mismatch".to_string())?;
-            tx_out_vec.push(TxOut {
-                value: Amount::from_sat(input.amount),
-                script_pubkey: address.script_pubkey(),
-            });
-        }
-        sihash_cache
-            .taproot_key_spend_signature_hash(
-                0,
-                &bitcoin::sighash::Prevouts::All(&tx_out_vec.as_slice()),
-                bitcoin::TapSighashType::All,
-            )
-            .unwrap()
-    };
-
-    let signature_blob = ic::schnorr_api::sign_with_schnorr(
-        "test_key_1".to_string(),
-        crate::get_derivation_path(token_path), 
-        Some(vec![]),
-        <TapSighash as AsRef<[u8; 32]>>::as_ref(&sighash).to_vec(),
-    )
-    .await;
-    let token_signature_blob = ic::schnorr_api::sign_with_schnorr(
-        "test_key_1".to_string(),
-        crate::get_derivation_path("fomowell-btc-address"),
-        Some(vec![]),
-        <TapSighash as AsRef<[u8; 32]>>::as_ref(&sighash).to_vec(),
-    )
-    .await;
-
-    let signature = bitcoin::taproot::Signature {
-        signature: bitcoin::secp256k1::schnorr::Signature::from_slice(&signature_blob).unwrap(),
-        sighash_type: bitcoin::TapSighashType::All,
-    };
-    psbt.inputs[0].final_script_witness = Some(bitcoin::Witness::from_slice(&[signature.to_vec()]));
-
-    let token_signature = bitcoin::taproot::Signature {
-        signature: bitcoin::secp256k1::schnorr::Signature::from_slice(&token_signature_blob)
-            .unwrap(),
-        sighash_type: bitcoin::TapSighashType::All,
-    };
-
-    for i in 1..(inputs.len() - btc_inputs.len()) {
-        if let Some(witness_data) = &inputs[i].witness {
-            if let Some(input) = psbt.inputs.get_mut(i) {
-                input.final_script_witness =
-                    Some(bitcoin::Witness::from_slice(&[witness_data.clone()]));
-            }
-        }
+    let mut prevouts = vec![];
+    for input in &inputs {
+        let address = Address::from_str(&input.address)
+            .map_err(|e| format!("Invalid input address: {}", e))?
+            .require_network(network)
+            .map_err(|_| "Address network mismatch".to_string())?;
+        prevouts.push(TxOut {
+            value: Amount::from_sat(input.amount),
+            script_pubkey: address.script_pubkey(),
+        });
     }
 
-    let start_index = inputs.len() - btc_inputs.len();
-    for i in 0..btc_inputs.len() {
-        psbt.inputs[start_index + i].final_script_witness = Some(bitcoin::Witness::from_slice(&[
-            bitcoin::taproot::Signature {
-                signature: bitcoin::secp256k1::schnorr::Signature::from_slice(
-                    &token_signature_blob,
-                )
+    // 每个输入用自己的 sighash 和所属地址的 key 签名
+    let mut sighash_cache = SighashCache::new(psbt.unsigned_tx.clone());
+    for (i, input) in inputs.iter().enumerate() {
+        if let Some(witness_data) = &input.witness {
+            psbt.inputs[i].final_script_witness = Some(bitcoin::Witness::from_slice(std::slice::from_ref(witness_data)));
+            continue;
+        }
+        let signer = input
+            .signer
+            .as_deref()
+            .ok_or_else(|| format!("Input {}:{} has neither a signer nor a witness", input.txid, input.vout))?;
+        let sighash_type = match input.signature_type {
+            Some(InputSignatureType::Taproot(sighash_type)) => sighash_type,
+            Some(InputSignatureType::Ecdsa(_)) => return Err("Only taproot inputs can be signed".to_string()),
+            None => bitcoin::TapSighashType::Default,
+        };
+        let sighash = sighash_cache
+            .taproot_key_spend_signature_hash(i, &bitcoin::sighash::Prevouts::All(&prevouts), sighash_type)
+            .map_err(|e| format!("Failed to compute sighash: {}", e))?;
+        let signature_blob = ic::schnorr_api::sign_with_schnorr(
+            crate::SCHNORR_KEY_NAME.to_string(),
+            crate::get_derivation_path(signer),
+            Some(vec![]),
+            <TapSighash as AsRef<[u8; 32]>>::as_ref(&sighash).to_vec(),
+        )
+        .await?;
+        let signature = bitcoin::taproot::Signature {
+            signature: bitcoin::secp256k1::schnorr::Signature::from_slice(&signature_blob)
                 .map_err(|e| format!("Failed to create schnorr signature: {}", e))?,
-                sighash_type: bitcoin::TapSighashType::AllPlusAnyoneCanPay,
-            }
-            .to_vec(),
-        ]));
+            sighash_type,
+        };
+        psbt.inputs[i].final_script_witness = Some(bitcoin::Witness::from_slice(&[signature.to_vec()]));
     }
 
     let tx: bitcoin::Transaction = psbt.clone().extract_tx().map_err(|e| e.to_string())?;
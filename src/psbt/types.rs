@@ -18,6 +18,9 @@ pub struct TransactionInput {
     pub public_key: Option<String>,
     pub signature_type: Option<InputSignatureType>,
     pub witness: Option<Vec<u8>>,
+    /// Derivation path of the canister key that signs this input. Inputs
+    /// without one must carry their own `witness`.
+    pub signer: Option<String>,
     // pub sighash_type: Option<PsbtSighashType>,
     // pub sighash_type: Option<TapSighashType>, // 改为 TapSighashType
 }
@@ -0,0 +1,178 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+/// Running totals that cannot be recomputed from other state.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ReserveCounters {
+    /// Credited to users through `internal_transfer` on topup.
+    pub credited: u128,
+    /// Sent to users by broadcast withdraw transactions.
+    pub withdrawn: u128,
+}
+
+/// `ReserveCounters` as stored before amounts were widened to u128, only read
+/// at upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ReserveCountersV1 {
+    pub credited: u64,
+    pub withdrawn: u64,
+}
+
+impl From<ReserveCountersV1> for ReserveCounters {
+    fn from(c: ReserveCountersV1) -> Self {
+        ReserveCounters { credited: c.credited.into(), withdrawn: c.withdrawn.into() }
+    }
+}
+
+/// 单个 alkane 的储备情况
+///
+/// `deposited` comes from the uploaded deposit records, `withdraw_requested`
+/// from the withdraw queue and `held` from the topup/fund ledger plus alkanes
+/// moved by gather transactions that are still unconfirmed. `fees` are fees
+/// kept in the fund, accrued or queued for a sweep; they are held but owed to
+/// nobody.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AlkaneReserve {
+    pub alkaneid: String,
+    pub deposited: u128,
+    pub credited: u128,
+    pub withdraw_requested: u128,
+    pub withdrawn: u128,
+    pub held: u128,
+    pub fees: u128,
+}
+
+impl AlkaneReserve {
+    /// Credited amount we still owe, queued withdraws included.
+    pub fn outstanding(&self) -> u128 {
+        self.credited.saturating_sub(self.withdrawn)
+    }
+
+    /// Broken invariants, empty when the alkane is fully backed.
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        if self.credited > self.deposited {
+            violations.push(format!("credited {} exceeds deposited {}", self.credited, self.deposited));
+        }
+        if self.withdrawn.saturating_add(self.withdraw_requested) > self.credited {
+            violations.push(format!(
+                "withdrawn {} + requested {} exceeds credited {}",
+                self.withdrawn, self.withdraw_requested, self.credited
+            ));
+        }
+        if self.held < self.outstanding().saturating_add(self.fees) {
+            violations.push(format!(
+                "held {} below outstanding {} + fees {}",
+                self.held,
+                self.outstanding(),
+                self.fees
+            ));
+        }
+        violations
+    }
+}
+
+thread_local! {
+    static COUNTERS: RefCell<BTreeMap<String, ReserveCounters>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn record_credit(alkaneid: &str, amount: u128) {
+    COUNTERS.with(|c| {
+        let mut counters = c.borrow_mut();
+        let entry = counters.entry(alkaneid.to_string()).or_default();
+        entry.credited = entry.credited.saturating_add(amount);
+    });
+}
+
+pub fn record_withdrawn(alkaneid: &str, amount: u128) {
+    COUNTERS.with(|c| {
+        let mut counters = c.borrow_mut();
+        let entry = counters.entry(alkaneid.to_string()).or_default();
+        entry.withdrawn = entry.withdrawn.saturating_add(amount);
+    });
+}
+
+/// Takes back a `record_withdrawn` whose transaction was dropped.
+pub fn reverse_withdrawn(alkaneid: &str, amount: u128) {
+    COUNTERS.with(|c| {
+        let mut counters = c.borrow_mut();
+        let entry = counters.entry(alkaneid.to_string()).or_default();
+        entry.withdrawn = entry.withdrawn.saturating_sub(amount);
+    });
+}
+
+/// Joins the stored counters with the amounts computed by the caller. Every
+/// alkane that shows up in any of the inputs gets a row.
+pub fn reserves(
+    deposited: &HashMap<String, u128>,
+    withdraw_requested: &HashMap<String, u128>,
+    held: &HashMap<String, u128>,
+    fees: &HashMap<String, u128>,
+) -> Vec<AlkaneReserve> {
+    fn row<'a>(rows: &'a mut BTreeMap<String, AlkaneReserve>, alkaneid: &str) -> &'a mut AlkaneReserve {
+        rows.entry(alkaneid.to_string())
+            .or_insert_with(|| AlkaneReserve { alkaneid: alkaneid.to_string(), ..Default::default() })
+    }
+
+    let mut rows: BTreeMap<String, AlkaneReserve> = BTreeMap::new();
+    for (alkaneid, counters) in export() {
+        let r = row(&mut rows, &alkaneid);
+        r.credited = counters.credited;
+        r.withdrawn = counters.withdrawn;
+    }
+    for (alkaneid, amount) in deposited {
+        row(&mut rows, alkaneid).deposited = *amount;
+    }
+    for (alkaneid, amount) in withdraw_requested {
+        row(&mut rows, alkaneid).withdraw_requested = *amount;
+    }
+    for (alkaneid, amount) in held {
+        row(&mut rows, alkaneid).held = *amount;
+    }
+    for (alkaneid, amount) in fees {
+        row(&mut rows, alkaneid).fees = *amount;
+    }
+    rows.into_values().collect()
+}
+
+/// 设置期初余额，用于在计数器上线前已有的入账和提现
+pub fn set_counters(alkaneid: &str, counters: ReserveCounters) {
+    COUNTERS.with(|c| {
+        c.borrow_mut().insert(alkaneid.to_string(), counters);
+    });
+}
+
+pub fn export() -> Vec<(String, ReserveCounters)> {
+    COUNTERS.with(|c| c.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+}
+
+pub fn restore(counters: Vec<(String, ReserveCounters)>) {
+    COUNTERS.with(|c| *c.borrow_mut() = counters.into_iter().collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_invariants() {
+        restore(vec![("2:1".into(), ReserveCounters { credited: 100, withdrawn: 30 })]);
+        let deposited = HashMap::from([("2:1".to_string(), 120)]);
+        let requested = HashMap::from([("2:1".to_string(), 20)]);
+        let held = HashMap::from([("2:1".to_string(), 90), ("2:2".to_string(), 5)]);
+
+        let rows = reserves(&deposited, &requested, &held, &HashMap::new());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].outstanding(), 70);
+        assert!(rows[0].violations().is_empty());
+        assert!(rows[1].violations().is_empty());
+
+        let short = AlkaneReserve { held: 60, ..rows[0].clone() };
+        assert_eq!(short.violations(), vec!["held 60 below outstanding 70 + fees 0".to_string()]);
+        let fees_unbacked = AlkaneReserve { fees: 25, ..rows[0].clone() };
+        assert_eq!(fees_unbacked.violations().len(), 1);
+        let over_requested = AlkaneReserve { withdraw_requested: 71, ..rows[0].clone() };
+        assert_eq!(over_requested.violations().len(), 1);
+    }
+}
@@ -0,0 +1,495 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bitcoin::{Address, Amount, Network, Script, TapSighashType, Txid};
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::alkanes::alkanes_data::alkanes_protostone::{
+    apply_protostone, build_alkanes_transfer_script, parse_transfer_script, Edict, Protostone, RuneId, TransferOutcome,
+};
+use crate::alkanes::alkanes_storage::{OutPoint, UtxoBalances};
+use crate::psbt::builder::PsbtBuilder;
+use crate::psbt::types::{InputSignatureType, InputUtxo};
+
+/// OP_RETURN script size `PsbtBuilder::add_output` accepts.
+pub const MAX_OP_RETURN_BYTES: usize = 80;
+
+/// Smallest output these transactions create, and the smallest postage an
+/// alkane-carrying output may get. Less than this goes to the fee instead.
+pub const DUST_LIMIT: u64 = 330;
+
+/// `total + amount` of one alkane, an error instead of wrapping past u128.
+pub fn checked_total(total: u128, amount: u128, alkaneid: &str) -> Result<u128, String> {
+    total.checked_add(amount).ok_or_else(|| format!("{} total overflows u128: {} + {}", alkaneid, total, amount))
+}
+
+/// One outpoint of the topup or fund ledger with every alkane it holds.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlannedInput {
+    pub address: String,
+    pub txid: String,
+    pub vout: u64,
+    pub satoshi: u64,
+    pub alkanes: Vec<(String, u128)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlannedOutput {
+    pub address: String,
+    pub satoshi: u64,
+    /// Alkanes the edicts of the protostone send to this output.
+    pub alkanes: Vec<(String, u128)>,
+    /// Carries `TxPlan::protostone` instead of paying `address`.
+    pub op_return: bool,
+}
+
+impl PlannedInput {
+    /// A ledger outpoint as an input. Spending it moves every balance it holds.
+    pub fn from_ledger(address: &str, outpoint: OutPoint, utxo: UtxoBalances) -> Self {
+        Self {
+            address: address.to_string(),
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+            satoshi: utxo.satoshi,
+            alkanes: utxo
+                .balances
+                .into_iter()
+                .map(|(alkaneid, amount)| (alkaneid.to_string(), amount))
+                .collect(),
+        }
+    }
+}
+
+impl PlannedOutput {
+    pub fn new(address: impl Into<String>, satoshi: u64) -> Self {
+        Self { address: address.into(), satoshi, alkanes: Vec::new(), op_return: false }
+    }
+}
+
+/// Outputs and the edicts funding them, allocated together: an edict is
+/// only ever created for an output already in the plan, with that output's
+/// index, so no separate loop has to push outputs in a matching order.
+#[derive(Debug, Default)]
+pub struct OutputPlan {
+    outputs: Vec<PlannedOutput>,
+    edicts: Vec<Edict>,
+}
+
+impl OutputPlan {
+    /// Adds an output and returns its index.
+    pub fn add_output(&mut self, address: &str, satoshi: u64) -> u32 {
+        self.outputs.push(PlannedOutput::new(address, satoshi));
+        (self.outputs.len() - 1) as u32
+    }
+
+    /// Sends `amount` of `alkaneid` to the existing output `output`.
+    pub fn add_edict(&mut self, output: u32, alkaneid: &str, amount: u128) -> Result<(), String> {
+        let id = parse_alkane_id(alkaneid)?;
+        let count = self.outputs.len();
+        let planned = self
+            .outputs
+            .get_mut(output as usize)
+            .ok_or_else(|| format!("edict for {} points at output {} of {}", alkaneid, output, count))?;
+        planned.alkanes.push((alkaneid.to_string(), amount));
+        self.edicts.push(Edict { id, amount, output });
+        Ok(())
+    }
+
+    /// A new output receiving `amount` of `alkaneid` through its own edict.
+    pub fn pay(&mut self, address: &str, satoshi: u64, alkaneid: &str, amount: u128) -> Result<u32, String> {
+        let output = self.add_output(address, satoshi);
+        self.add_edict(output, alkaneid, amount)?;
+        Ok(output)
+    }
+
+    /// Appends the OP_RETURN output carrying the protostone. Whatever no edict
+    /// moves goes to `pointer`, which is also the refund pointer.
+    pub fn finish(mut self, pointer: u32, op_return_address: &str) -> Result<(Vec<PlannedOutput>, ByteBuf), String> {
+        if pointer as usize >= self.outputs.len() {
+            return Err(format!("pointer {} past the last of {} outputs", pointer, self.outputs.len()));
+        }
+        let protostone = Protostone {
+            subprotocol_id: 1,
+            edicts: self.edicts,
+            pointer: Some(pointer),
+            refund_pointer: Some(pointer),
+            burn: None,
+            message: None,
+            from: None,
+        };
+        let script = build_alkanes_transfer_script(&protostone);
+        self.outputs.push(PlannedOutput { op_return: true, ..PlannedOutput::new(op_return_address, 0) });
+        Ok((self.outputs, ByteBuf::from(script.into_bytes())))
+    }
+}
+
+/// The transaction a withdraw or gather run is going to sign, as picked from
+/// the ledger and the BTC UTXOs.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TxPlan {
+    /// `withdraw` or `gather`.
+    pub kind: String,
+    /// Alkane inputs first, then the BTC inputs paying the fee.
+    pub inputs: Vec<PlannedInput>,
+    pub outputs: Vec<PlannedOutput>,
+    /// Script of the OP_RETURN output.
+    pub protostone: ByteBuf,
+    pub fee_rate: f64,
+    pub fee: u64,
+    /// Sats back to the BTC address; 0 when the rest was too small for an output.
+    pub change: u64,
+    /// Sats the inputs hold beyond outputs and `fee` that go to the miner
+    /// anyway, because they were too few for a change output.
+    pub fee_rounding: u64,
+}
+
+/// BTC side of a transaction: the BTC inputs picked to pay for it and where
+/// the sats of all inputs end up.
+#[derive(Debug, PartialEq)]
+pub struct BtcFunding {
+    pub inputs: Vec<PlannedInput>,
+    pub fee: u64,
+    pub change: u64,
+    pub fee_rounding: u64,
+}
+
+/// Adds BTC UTXOs to inputs already holding `input_sats` until outputs, fee
+/// and a change output are covered, or `max_inputs` is reached. The sats of
+/// the alkane inputs beyond their outputs' postage count towards the change,
+/// so they go back to the BTC address instead of to the miner.
+///
+/// `fee_for(input_count, with_change)` is the fee of the transaction with
+/// that many inputs, with or without the change output.
+pub fn fund_with_btc(
+    input_sats: u64,
+    output_sats: u64,
+    input_count: usize,
+    max_inputs: usize,
+    btc_utxos: impl IntoIterator<Item = PlannedInput>,
+    fee_for: impl Fn(usize, bool) -> u64,
+) -> Result<BtcFunding, String> {
+    let mut available = input_sats;
+    let mut inputs = Vec::new();
+    let mut btc_utxos = btc_utxos.into_iter();
+    loop {
+        let count = input_count + inputs.len();
+        if available >= output_sats + fee_for(count, true) + DUST_LIMIT || count >= max_inputs {
+            break;
+        }
+        let Some(utxo) = btc_utxos.next() else {
+            break;
+        };
+        available += utxo.satoshi;
+        inputs.push(utxo);
+    }
+    let count = input_count + inputs.len();
+    let fee = fee_for(count, true);
+    if available >= output_sats + fee + DUST_LIMIT {
+        let change = available - output_sats - fee;
+        return Ok(BtcFunding { inputs, fee, change, fee_rounding: 0 });
+    }
+    let fee = fee_for(count, false);
+    if available < output_sats + fee {
+        return Err(format!(
+            "{} inputs hold {} sats, outputs and fee need {}",
+            count,
+            available,
+            output_sats + fee
+        ));
+    }
+    Ok(BtcFunding { inputs, fee, change: 0, fee_rounding: available - output_sats - fee })
+}
+
+/// 一条 edict 以及它指向的输出
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct EdictMapping {
+    pub alkaneid: String,
+    pub amount: u128,
+    pub output: u32,
+    /// `None` when the edict points at the OP_RETURN or past the last output.
+    pub address: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DecodedProtostone {
+    pub subprotocol_id: u128,
+    pub pointer: Option<u32>,
+    pub refund_pointer: Option<u32>,
+    pub burn: Option<u128>,
+    pub edicts: Vec<EdictMapping>,
+}
+
+/// Alkanes one output ends up with once the protostone ran.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutputAlkanes {
+    pub output: u32,
+    pub alkanes: Vec<(String, u128)>,
+}
+
+/// A plan built into its unsigned PSBT.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TxSimulation {
+    pub plan: TxPlan,
+    /// Taproot witnesses do not change the txid, so this is the txid the
+    /// signed transaction will have.
+    pub txid: String,
+    pub psbt_base64: String,
+    pub vsize: u64,
+    /// Decoded back from the OP_RETURN script rather than copied from the plan.
+    pub protostones: Vec<DecodedProtostone>,
+    /// The decoded protostone applied to the alkanes of the inputs. Outputs
+    /// receiving nothing are left out.
+    pub output_alkanes: Vec<OutputAlkanes>,
+    pub burned: Vec<(String, u128)>,
+    /// Where that result differs from the plan. Withdraw and gather runs
+    /// refuse to sign while this is not empty.
+    pub problems: Vec<String>,
+}
+
+pub fn network() -> Network {
+    match crate::IC_BITCOIN_NETWORK {
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
+/// Fails unless `address` parses as an address of `network()`.
+pub fn check_address(address: &str) -> Result<(), String> {
+    Address::from_str(address)
+        .map_err(|e| e.to_string())?
+        .require_network(network())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Builds the unsigned PSBT of `plan` with `PsbtBuilder` and runs its
+/// protostone over the input alkanes. No key is asked for anything, so this
+/// runs in tests as well.
+///
+/// `change_address` is the one address allowed to receive alkanes beyond
+/// what an output plans for: change edicts and whatever the pointer carries.
+pub fn simulate(plan: &TxPlan, network: Network, change_address: &str) -> Result<TxSimulation, String> {
+    let mut builder = PsbtBuilder::new(network);
+    for input in &plan.inputs {
+        let tx_id = Txid::from_str(&input.txid).map_err(|e| format!("Invalid input txid {}: {}", input.txid, e))?;
+        let utxo = InputUtxo { tx_id, vout: input.vout as u32, value: Amount::from_sat(input.satoshi) };
+        builder.add_input(utxo, &input.address, None, Some(InputSignatureType::Taproot(TapSighashType::Default)))?;
+    }
+    for output in &plan.outputs {
+        let op_return = output.op_return.then(|| plan.protostone.to_vec());
+        builder.add_output(&output.address, output.satoshi, op_return)?;
+    }
+    let psbt = builder.build()?;
+    let vsize = builder.estimate_vbytes()?;
+
+    let protostones = parse_transfer_script(Script::from_bytes(&plan.protostone))
+        .ok_or_else(|| "OP_RETURN script is not a protostone".to_string())?;
+    let [proto] = protostones.as_slice() else {
+        return Err(format!("expected one protostone, found {}", protostones.len()));
+    };
+    let mut held: BTreeMap<RuneId, u128> = BTreeMap::new();
+    for (alkaneid, amount) in plan.inputs.iter().flat_map(|input| &input.alkanes) {
+        let total = held.entry(parse_alkane_id(alkaneid)?).or_insert(0);
+        *total = checked_total(*total, *amount, alkaneid)?;
+    }
+    let op_returns: Vec<bool> = plan.outputs.iter().map(|output| output.op_return).collect();
+    let outcome = apply_protostone(&held, proto, &op_returns);
+
+    Ok(TxSimulation {
+        plan: plan.clone(),
+        txid: psbt.unsigned_tx.compute_txid().to_string(),
+        psbt_base64: STANDARD.encode(psbt.serialize()),
+        vsize,
+        protostones: vec![decode(proto, &plan.outputs)],
+        output_alkanes: outcome
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, alkanes)| !alkanes.is_empty())
+            .map(|(output, alkanes)| OutputAlkanes { output: output as u32, alkanes: named(alkanes) })
+            .collect(),
+        burned: named(&outcome.burned),
+        problems: problems(plan, &outcome, change_address),
+    })
+}
+
+fn parse_alkane_id(alkaneid: &str) -> Result<RuneId, String> {
+    alkaneid
+        .split_once(':')
+        .and_then(|(block, tx)| Some(RuneId { block: block.parse().ok()?, tx: tx.parse().ok()? }))
+        .ok_or_else(|| format!("Invalid alkane id {}", alkaneid))
+}
+
+fn named(balances: &BTreeMap<RuneId, u128>) -> Vec<(String, u128)> {
+    balances.iter().map(|(id, amount)| (format!("{}:{}", id.block, id.tx), *amount)).collect()
+}
+
+/// 对照计划检查每个输出实际收到的 alkanes
+fn problems(plan: &TxPlan, outcome: &TransferOutcome, change_address: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(reason) = &outcome.invalid {
+        problems.push(format!("protostone is invalid: {}", reason));
+    }
+    for (alkaneid, amount) in named(&outcome.burned) {
+        problems.push(format!("{} of {} would be burned", amount, alkaneid));
+    }
+    if let Some(output) = outcome.leftover_output
+        && plan.outputs[output as usize].address != change_address
+    {
+        problems.push(format!(
+            "{:?} not covered by an edict would go to output {} ({}) instead of {}",
+            named(&outcome.leftover),
+            output,
+            plan.outputs[output as usize].address,
+            change_address
+        ));
+    }
+    for (index, (output, received)) in plan.outputs.iter().zip(&outcome.outputs).enumerate() {
+        let received = named(received);
+        let mut planned: BTreeMap<RuneId, u128> = BTreeMap::new();
+        for (alkaneid, amount) in &output.alkanes {
+            match parse_alkane_id(alkaneid) {
+                Ok(id) => {
+                    let total = planned.entry(id).or_insert(0);
+                    match checked_total(*total, *amount, alkaneid) {
+                        Ok(sum) => *total = sum,
+                        Err(e) => problems.push(format!("output {}: {}", index, e)),
+                    }
+                }
+                Err(e) => problems.push(e),
+            }
+        }
+        let planned = named(&planned);
+        let as_planned = if output.address == change_address {
+            planned.iter().all(|(alkaneid, amount)| received.iter().any(|(id, got)| id == alkaneid && got >= amount))
+        } else {
+            received == planned
+        };
+        if !as_planned {
+            problems.push(format!("output {} ({}) would receive {:?}, planned {:?}", index, output.address, received, planned));
+        }
+    }
+    problems
+}
+
+fn decode(proto: &Protostone, outputs: &[PlannedOutput]) -> DecodedProtostone {
+    DecodedProtostone {
+        subprotocol_id: proto.subprotocol_id,
+        pointer: proto.pointer,
+        refund_pointer: proto.refund_pointer,
+        burn: proto.burn,
+        edicts: proto
+            .edicts
+            .iter()
+            .map(|edict| EdictMapping {
+                alkaneid: format!("{}:{}", edict.id.block, edict.id.tx),
+                amount: edict.amount,
+                output: edict.output,
+                address: outputs
+                    .get(edict.output as usize)
+                    .filter(|output| !output.op_return)
+                    .map(|output| output.address.clone()),
+            })
+            .collect(),
+    }
+}
+
+/// A valid taproot address on `Network::Testnet` for tests.
+#[cfg(test)]
+pub(crate) fn test_address(seed: u8) -> String {
+    use bitcoin::secp256k1::{Keypair, Secp256k1};
+    let secp = Secp256k1::new();
+    let keypair = Keypair::from_seckey_slice(&secp, &[seed; 32]).expect("valid secret key");
+    bitcoin::Address::p2tr(&secp, keypair.x_only_public_key().0, None, Network::Testnet).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EdictInput;
+    use bitcoin::psbt::Psbt;
+
+    #[test]
+    fn test_simulate_builds_unsigned_psbt() {
+        let (fund, user, btc) = (test_address(1), test_address(2), test_address(3));
+        let protostone = crate::generate_protostone(vec![
+            EdictInput { block: 2, tx: 1, amount: 400, output: 1 },
+            EdictInput { block: 2, tx: 1, amount: 600, output: 0 },
+        ]);
+        let output = |address: &str, satoshi, op_return| PlannedOutput { op_return, ..PlannedOutput::new(address, satoshi) };
+        let withdrawn = PlannedOutput { alkanes: vec![("2:1".into(), 400)], ..output(&user, 330, false) };
+        let plan = TxPlan {
+            kind: "withdraw".into(),
+            inputs: vec![
+                PlannedInput { address: fund.clone(), txid: "aa".repeat(32), vout: 0, satoshi: 330, alkanes: vec![("2:1".into(), 1_000)] },
+                PlannedInput { address: btc.clone(), txid: "bb".repeat(32), vout: 1, satoshi: 10_000, alkanes: vec![] },
+            ],
+            outputs: vec![output(&fund, 330, false), withdrawn, output(&fund, 0, true), output(&btc, 8_000, false)],
+            protostone: ByteBuf::from(protostone.into_bytes()),
+            fee_rate: 2.0,
+            fee: 1_670,
+            change: 8_000,
+            fee_rounding: 0,
+        };
+
+        let simulation = simulate(&plan, Network::Testnet, &fund).unwrap();
+        assert_eq!(simulation.problems, Vec::<String>::new());
+        let received: Vec<(u32, Vec<(String, u128)>)> = simulation.output_alkanes.iter().map(|o| (o.output, o.alkanes.clone())).collect();
+        assert_eq!(received, vec![(0, vec![("2:1".into(), 600)]), (1, vec![("2:1".into(), 400)])]);
+        let [proto] = simulation.protostones.as_slice() else { panic!("expected one protostone") };
+        assert_eq!((proto.pointer, proto.refund_pointer, proto.burn), (Some(0), Some(0), None));
+        let mapped: Vec<(u128, Option<&str>)> = proto.edicts.iter().map(|e| (e.amount, e.address.as_deref())).collect();
+        assert_eq!(mapped, vec![(400, Some(user.as_str())), (600, Some(fund.as_str()))]);
+
+        let psbt = Psbt::deserialize(&STANDARD.decode(&simulation.psbt_base64).unwrap()).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_eq!(psbt.unsigned_tx.output.len(), 4);
+        assert!(psbt.unsigned_tx.output[2].script_pubkey.is_op_return());
+        assert!(psbt.inputs.iter().all(|input| input.final_script_witness.is_none()));
+        assert_eq!(simulation.txid, psbt.unsigned_tx.compute_txid().to_string());
+        assert!(simulation.vsize > 0);
+
+        // edict 错位到 btc 找零输出：找零输出多收、用户输出没收到
+        let misindexed = crate::generate_protostone(vec![EdictInput { block: 2, tx: 1, amount: 400, output: 3 }]);
+        let plan = TxPlan { protostone: ByteBuf::from(misindexed.into_bytes()), ..plan };
+        let problems = simulate(&plan, Network::Testnet, &fund).unwrap().problems;
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("output 1"));
+        let pointer_to_user = TxPlan {
+            outputs: vec![output(&user, 330, false), output(&fund, 0, true)],
+            protostone: ByteBuf::from(crate::generate_protostone(vec![]).into_bytes()),
+            ..plan.clone()
+        };
+        let problems = simulate(&pointer_to_user, Network::Testnet, &fund).unwrap().problems;
+        assert!(problems[0].contains("not covered by an edict would go to output 0"), "{:?}", problems);
+
+        // 地址和网络不符时构建失败，不会走到签名
+        assert!(simulate(&plan, Network::Bitcoin, &fund).is_err());
+        assert!(parse_transfer_script(Script::from_bytes(&[])).is_none());
+    }
+
+    #[test]
+    fn test_fund_with_btc_recycles_dust() {
+        let utxo = |vout, satoshi| PlannedInput { address: "btc".into(), txid: "bb".repeat(32), vout, satoshi, alkanes: vec![] };
+        let fee_for = |inputs: usize, with_change: bool| 100 * inputs as u64 + if with_change { 50 } else { 0 };
+
+        // 两个 546 sats 的 alkane 输入只出一个 330 的输出，多出的够付手续费还能找零
+        let funding = fund_with_btc(1_092, 330, 2, usize::MAX, vec![utxo(0, 1_000)], fee_for).unwrap();
+        assert!(funding.inputs.is_empty());
+        assert_eq!((funding.fee, funding.change, funding.fee_rounding), (250, 512, 0));
+
+        // 够不上一个找零输出的部分算作手续费取整
+        let funding = fund_with_btc(700, 330, 2, 3, vec![utxo(0, 100), utxo(1, 1_000)], fee_for).unwrap();
+        assert_eq!(funding.inputs.len(), 1);
+        assert_eq!((funding.fee, funding.change, funding.fee_rounding), (300, 0, 170));
+        assert_eq!(funding.fee + funding.change + funding.fee_rounding + 330, 700 + 100);
+
+        // max_inputs 之内凑不够
+        assert!(fund_with_btc(330, 330, 1, 2, vec![utxo(0, 50), utxo(1, 10_000)], fee_for).is_err());
+        assert!(fund_with_btc(330, 330, 1, usize::MAX, vec![utxo(0, 50), utxo(1, 10_000)], fee_for).is_ok());
+    }
+}